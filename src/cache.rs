@@ -0,0 +1,16 @@
+//! Cache side-channel primitives shared by the oracles, labs and
+//! footprint tests.
+
+pub mod flush_reload;
+pub mod prime_probe;
+pub mod footprint;
+pub mod state;
+
+/// Timing tests share the caches and the clock with every other test in
+/// the process. Each one holds this for its duration so they run one at a
+/// time.
+#[cfg(test)]
+pub(crate) fn timing_lock() -> std::sync::MutexGuard<'static, ()> {
+    static LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
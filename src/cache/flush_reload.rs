@@ -0,0 +1,303 @@
+//! Flush+Reload: flush a line, let the victim run, time a reload. A fast
+//! reload means someone touched the line in between.
+
+use core::arch::x86_64::{_mm_clflush, _mm_mfence};
+use rand::seq::SliceRandom;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr;
+
+use crate::timer::{self, Timer};
+
+/// Used when calibration cannot tell hits from misses. In timer cycles with
+/// the overhead taken off, LLC hits come in well under it and DRAM loads
+/// at a few hundred.
+pub const DEFAULT_THRESHOLD: u64 = 120;
+const CALIBRATION_SAMPLES: usize = 1000;
+
+/// # Safety
+///
+/// `addr` must be mapped.
+#[inline(always)]
+pub unsafe fn flush(addr: *const u8) {
+    _mm_clflush(addr);
+}
+
+/// Load latency of `addr` on `timer::default_timer`, with its overhead
+/// subtracted.
+///
+/// # Safety
+///
+/// `addr` must be valid for reads.
+#[inline(always)]
+pub unsafe fn reload(addr: *const u8) -> u64 {
+    reload_with(timer::default_timer(), addr)
+}
+
+/// `reload` on `timer`. The timer fences its own readings, so neither the
+/// load nor surrounding code leaks into the measurement; the `mfence`
+/// drains earlier flushes first.
+///
+/// # Safety
+///
+/// As for `reload`.
+#[inline(always)]
+pub unsafe fn reload_with(timer: &dyn Timer, addr: *const u8) -> u64 {
+    _mm_mfence();
+    let start = timer.start();
+    ptr::read_volatile(addr);
+    let stop = timer.stop();
+    timer.elapsed(start, stop)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Calibration {
+    pub hit_median: u64,
+    pub miss_median: u64,
+    pub threshold: u64,
+}
+
+impl Calibration {
+    /// Picks a threshold between the 90th percentile of `hits` and the 10th
+    /// of `misses`, or between the medians if those overlap. If misses are
+    /// not slower at all, or either side has no samples, `DEFAULT_THRESHOLD`
+    /// is used; the median of an empty side reads as 0.
+    pub fn from_samples(mut hits: Vec<u64>, mut misses: Vec<u64>) -> Self {
+        hits.sort_unstable();
+        misses.sort_unstable();
+        let pct = |v: &[u64], p: usize| v.get(v.len() * p / 100).copied().unwrap_or(0);
+
+        let (hit_median, miss_median) = (pct(&hits, 50), pct(&misses, 50));
+        let threshold = if hits.is_empty() || misses.is_empty() {
+            DEFAULT_THRESHOLD
+        } else if pct(&hits, 90) < pct(&misses, 10) {
+            (pct(&hits, 90) + pct(&misses, 10)) / 2
+        } else if hit_median < miss_median {
+            (hit_median + miss_median) / 2
+        } else {
+            DEFAULT_THRESHOLD
+        };
+        Self { hit_median, miss_median, threshold }
+    }
+
+    pub fn is_hit(&self, cycles: u64) -> bool {
+        cycles <= self.threshold
+    }
+}
+
+/// Read-only shared mapping of a file. Pages are shared with every other
+/// process mapping the same file, which is what lets a monitor see them.
+pub struct SharedMapping {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl SharedMapping {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len() as usize;
+        if len == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "cannot map an empty file"));
+        }
+        let ptr = unsafe {
+            libc::mmap(ptr::null_mut(), len, libc::PROT_READ, libc::MAP_SHARED, file.as_raw_fd(), 0)
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { ptr: ptr as *mut u8, len })
+    }
+
+    pub fn as_ptr(&self) -> *const u8 {
+        self.ptr
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Drop for SharedMapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len) };
+    }
+}
+
+/// A set of monitored cache lines with a calibrated hit threshold.
+pub struct MonitorSet {
+    lines: Vec<*const u8>,
+    calibration: Calibration,
+    mapping: Option<SharedMapping>,
+}
+
+impl MonitorSet {
+    /// # Safety
+    ///
+    /// Every address must stay valid for reads for the life of the set.
+    pub unsafe fn new(lines: Vec<*const u8>) -> Self {
+        assert!(!lines.is_empty(), "nothing to monitor");
+        let mut set = Self {
+            lines,
+            calibration: Calibration { hit_median: 0, miss_median: 0, threshold: DEFAULT_THRESHOLD },
+            mapping: None,
+        };
+        set.calibrate();
+        set
+    }
+
+    /// `count` lines `stride` bytes apart starting at `base`.
+    ///
+    /// # Safety
+    ///
+    /// As for `new`.
+    pub unsafe fn strided(base: *const u8, stride: usize, count: usize) -> Self {
+        Self::new((0..count).map(|i| base.add(i * stride)).collect())
+    }
+
+    /// Monitors `offsets` within a shared mapping of `path`, e.g. the code
+    /// pages of a library another local process is running.
+    pub fn from_file(path: &Path, offsets: &[usize]) -> io::Result<Self> {
+        let mapping = SharedMapping::open(path)?;
+        if let Some(&bad) = offsets.iter().find(|&&o| o >= mapping.len()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("offset {:#x} past end of file", bad)));
+        }
+        let lines = offsets.iter().map(|&o| unsafe { mapping.as_ptr().add(o) }).collect();
+        let mut set = unsafe { Self::new(lines) };
+        set.mapping = Some(mapping);
+        Ok(set)
+    }
+
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    pub fn line(&self, i: usize) -> *const u8 {
+        self.lines[i]
+    }
+
+    pub fn mapping(&self) -> Option<&SharedMapping> {
+        self.mapping.as_ref()
+    }
+
+    pub fn calibration(&self) -> Calibration {
+        self.calibration
+    }
+
+    pub fn threshold(&self) -> u64 {
+        self.calibration.threshold
+    }
+
+    pub fn set_threshold(&mut self, threshold: u64) {
+        self.calibration.threshold = threshold;
+    }
+
+    /// Re-measures cached and flushed reload latency across the lines.
+    pub fn calibrate(&mut self) -> Calibration {
+        let mut hits = Vec::with_capacity(CALIBRATION_SAMPLES);
+        let mut misses = Vec::with_capacity(CALIBRATION_SAMPLES);
+        for i in 0..CALIBRATION_SAMPLES {
+            let line = self.lines[i % self.lines.len()];
+            unsafe {
+                ptr::read_volatile(line);
+                hits.push(reload(line));
+                flush(line);
+                misses.push(reload(line));
+            }
+        }
+        self.calibration = Calibration::from_samples(hits, misses);
+        self.calibration
+    }
+
+    pub fn flush_all(&self) {
+        for &line in &self.lines {
+            unsafe { flush(line) };
+        }
+        unsafe { _mm_mfence() };
+    }
+
+    pub fn reload(&self, i: usize) -> u64 {
+        unsafe { reload(self.lines[i]) }
+    }
+
+    /// Times every line once, in a fresh random order so the prefetcher
+    /// cannot learn the sequence. Result is indexed like the set.
+    pub fn probe(&self) -> Vec<u64> {
+        let mut order: Vec<usize> = (0..self.lines.len()).collect();
+        order.shuffle(&mut rand::thread_rng());
+        let mut times = vec![0; self.lines.len()];
+        for i in order {
+            times[i] = self.reload(i);
+        }
+        times
+    }
+
+    pub fn probe_hits(&self) -> Vec<bool> {
+        self.probe().into_iter().map(|t| self.calibration.is_hit(t)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detects_touched_line() {
+        let _timing = crate::cache::timing_lock();
+        let buf = vec![1u8; 16 * 4096];
+        let set = unsafe { MonitorSet::strided(buf.as_ptr(), 4096, 16) };
+        // Stray hits come in bursts when a neighbour is busy, so they are
+        // bounded over all rounds rather than per round.
+        let (mut hits, mut stray) = (0, 0);
+        for _ in 0..20 {
+            set.flush_all();
+            unsafe { ptr::read_volatile(buf.as_ptr().add(5 * 4096)) };
+            let probe = set.probe_hits();
+            hits += probe[5] as usize;
+            stray += probe.iter().filter(|&&h| h).count() - probe[5] as usize;
+        }
+        assert!(hits >= 15, "{}", hits);
+        // Under one in five of the 300 untouched probes.
+        assert!(stray < 60, "{}", stray);
+    }
+
+    #[test]
+    fn test_calibration_from_samples() {
+        let cal = Calibration::from_samples(vec![40; 100], vec![300; 100]);
+        assert_eq!(cal, Calibration { hit_median: 40, miss_median: 300, threshold: 170 });
+        let flat = Calibration::from_samples(vec![100; 10], vec![100; 10]);
+        assert_eq!(flat.threshold, DEFAULT_THRESHOLD);
+        let empty = Calibration::from_samples(Vec::new(), vec![300; 10]);
+        assert_eq!(empty, Calibration { hit_median: 0, miss_median: 300, threshold: DEFAULT_THRESHOLD });
+        assert_eq!(Calibration::from_samples(vec![40; 10], Vec::new()).threshold, DEFAULT_THRESHOLD);
+    }
+
+    #[test]
+    fn test_monitors_shared_file_pages() {
+        let _timing = crate::cache::timing_lock();
+        let path = std::env::temp_dir().join(format!("flush-reload-{}", std::process::id()));
+        std::fs::write(&path, vec![7u8; 4 * 4096]).unwrap();
+        let set = MonitorSet::from_file(&path, &[0, 4096, 2 * 4096, 3 * 4096]).unwrap();
+        // A second, independent mapping stands in for another process.
+        let other = SharedMapping::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut hits = 0;
+        for _ in 0..20 {
+            set.flush_all();
+            unsafe { ptr::read_volatile(other.as_ptr().add(2 * 4096)) };
+            hits += set.probe_hits()[2] as u32;
+        }
+        assert!(hits >= 15, "{}", hits);
+        assert!(MonitorSet::from_file(Path::new("/nonexistent"), &[0]).is_err());
+    }
+}
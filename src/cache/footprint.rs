@@ -0,0 +1,244 @@
+//! Cache-footprint equivalence for comparators.
+//!
+//! A comparator with uniform cacheline traversal touches the same lines of
+//! both inputs whatever they contain. To check that, both buffers are
+//! flushed, the comparator runs, and every line is reloaded with timing,
+//! giving a per-line hit bitmap. Repeating that for many secret pairs gives
+//! a hit count per pair and line, and a chi-square homogeneity test per
+//! line says whether all pairs could share one hit probability.
+
+use rand::Rng;
+
+use super::flush_reload::MonitorSet;
+use crate::fault::PageBuffer;
+use crate::Comparator;
+
+const LINE_SIZE: usize = 64;
+/// Per-line z score above which hit rates are taken to depend on the
+/// inputs. High, because every line of every comparator gets a test.
+pub const UNIFORM_Z: f64 = 5.0;
+const DEFAULT_PAIRS: usize = 16;
+const DEFAULT_REPEATS: usize = 30;
+
+/// Input pairs that an early-exit comparator would treat very differently:
+/// equal, differing in the first or the last byte, and unrelated.
+pub fn secret_pairs<R: Rng>(len: usize, count: usize, rng: &mut R) -> Vec<(Vec<u8>, Vec<u8>)> {
+    assert!(len > 0, "inputs must not be empty");
+    (0..count)
+        .map(|i| {
+            let lhs: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+            let mut rhs = lhs.clone();
+            match i % 4 {
+                0 => {}
+                1 => rhs[0] ^= rng.gen_range(1..=255u8),
+                2 => rhs[len - 1] ^= rng.gen_range(1..=255u8),
+                _ => rng.fill(&mut rhs[..]),
+            }
+            (lhs, rhs)
+        })
+        .collect()
+}
+
+/// Page-aligned copies of both inputs with every line of each monitored.
+pub struct FootprintTester {
+    lhs: PageBuffer,
+    rhs: PageBuffer,
+    len: usize,
+    monitor: MonitorSet,
+}
+
+impl FootprintTester {
+    pub fn new(len: usize) -> Option<Self> {
+        assert!(len > 0, "inputs must not be empty");
+        let lhs = PageBuffer::new(len)?;
+        let rhs = PageBuffer::new(len)?;
+        let lines = (0..len)
+            .step_by(LINE_SIZE)
+            .map(|o| unsafe { lhs.as_ptr().add(o) })
+            .chain((0..len).step_by(LINE_SIZE).map(|o| unsafe { rhs.as_ptr().add(o) }))
+            .collect();
+        let mut monitor = unsafe { MonitorSet::new(lines) };
+        monitor.calibrate();
+        Some(Self { lhs, rhs, len, monitor })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Monitored lines: those of `lhs`, then those of `rhs`.
+    pub fn lines(&self) -> usize {
+        self.monitor.len()
+    }
+
+    /// One flush, compare, reload cycle. Returns the comparator's result
+    /// and the hit bitmap, indexed like `lines`.
+    ///
+    /// # Safety
+    ///
+    /// `cmp` must read at most `len` bytes from each side.
+    pub unsafe fn capture(&mut self, cmp: Comparator, lhs: &[u8], rhs: &[u8]) -> (i32, Vec<bool>) {
+        assert!(lhs.len() == self.len && rhs.len() == self.len, "inputs must be {} bytes", self.len);
+        self.lhs.as_mut_slice()[..self.len].copy_from_slice(lhs);
+        self.rhs.as_mut_slice()[..self.len].copy_from_slice(rhs);
+        self.monitor.flush_all();
+        let result = cmp(self.lhs.as_ptr(), self.rhs.as_ptr(), self.len);
+        (result, self.monitor.probe_hits())
+    }
+
+    /// Captures every pair `repeats` times, interleaving the pairs so that
+    /// drift in machine noise is shared between them. The hit threshold is
+    /// recalibrated first, since cached reloads slow down with the clock.
+    ///
+    /// # Safety
+    ///
+    /// As for `capture`.
+    pub unsafe fn run(&mut self, cmp: Comparator, pairs: &[(Vec<u8>, Vec<u8>)], repeats: usize) -> FootprintReport {
+        assert!(pairs.len() > 1 && repeats > 0, "need at least two pairs and one repeat");
+        self.monitor.calibrate();
+        let mut hits = vec![vec![0u32; self.lines()]; pairs.len()];
+        for _ in 0..repeats {
+            for (counts, (lhs, rhs)) in hits.iter_mut().zip(pairs) {
+                let (_, bitmap) = self.capture(cmp, lhs, rhs);
+                for (count, hit) in counts.iter_mut().zip(bitmap) {
+                    *count += hit as u32;
+                }
+            }
+        }
+        FootprintReport::new(hits, repeats)
+    }
+}
+
+/// Hit counts per pair and line, with a homogeneity score per line.
+#[derive(Debug, Clone)]
+pub struct FootprintReport {
+    /// `hits[pair][line]` out of `repeats`.
+    pub hits: Vec<Vec<u32>>,
+    pub repeats: usize,
+    /// Wilson-Hilferty z score of each line's chi-square statistic. Lines
+    /// hit always or never score 0.
+    pub z: Vec<f64>,
+}
+
+impl FootprintReport {
+    pub fn new(hits: Vec<Vec<u32>>, repeats: usize) -> Self {
+        let lines = hits.first().map_or(0, Vec::len);
+        let z = (0..lines)
+            .map(|line| homogeneity_z(hits.iter().map(|h| h[line]), repeats))
+            .collect();
+        Self { hits, repeats, z }
+    }
+
+    pub fn lines(&self) -> usize {
+        self.z.len()
+    }
+
+    pub fn hit_rate(&self, pair: usize, line: usize) -> f64 {
+        self.hits[pair][line] as f64 / self.repeats as f64
+    }
+
+    /// The line whose hit rate depends most on the inputs, and its score.
+    pub fn worst_line(&self) -> Option<(usize, f64)> {
+        self.z.iter().copied().enumerate().max_by(|a, b| a.1.total_cmp(&b.1))
+    }
+
+    pub fn is_uniform(&self) -> bool {
+        self.worst_line().is_none_or(|(_, z)| z < UNIFORM_Z)
+    }
+}
+
+/// Chi-square test that `counts`, each out of `n`, share one success
+/// probability, mapped to a standard normal score by Wilson-Hilferty.
+fn homogeneity_z(counts: impl Iterator<Item = u32> + Clone, n: usize) -> f64 {
+    let k = counts.clone().count();
+    let total: u32 = counts.clone().sum();
+    let p = total as f64 / (k * n) as f64;
+    if k < 2 || p <= 0.0 || p >= 1.0 {
+        return 0.0;
+    }
+    let expected = n as f64 * p;
+    let variance = expected * (1.0 - p);
+    let chi2: f64 = counts.map(|c| (c as f64 - expected).powi(2) / variance).sum();
+    let df = (k - 1) as f64;
+    let spread = 2.0 / (9.0 * df);
+    ((chi2 / df).cbrt() - (1.0 - spread)) / spread.sqrt()
+}
+
+/// Panics unless `cmp` touches the same cache lines of `len`-byte inputs
+/// for a spread of secret pairs. Meant for tests of comparator entry
+/// points, Rust, FFI or JIT alike.
+///
+/// # Safety
+///
+/// `cmp` must read at most `len` bytes from each side.
+pub unsafe fn assert_uniform_footprint(cmp: Comparator, len: usize) {
+    let pairs = secret_pairs(len, DEFAULT_PAIRS, &mut rand::thread_rng());
+    let mut tester = FootprintTester::new(len).expect("failed to map footprint buffers");
+    let report = tester.run(cmp, &pairs, DEFAULT_REPEATS);
+    if let Some((line, z)) = report.worst_line().filter(|_| !report.is_uniform()) {
+        let rates: Vec<String> = (0..pairs.len()).map(|p| format!("{:.2}", report.hit_rate(p, line))).collect();
+        panic!(
+            "cache footprint depends on the inputs: line {} of {} has z = {:.1}, hit rates per pair [{}]",
+            line,
+            report.lines(),
+            z,
+            rates.join(", ")
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What `ct_memcmp` must not be: stops at the first difference.
+    unsafe extern "C" fn early_exit_memcmp(lhs: *const u8, rhs: *const u8, len: usize) -> i32 {
+        for i in 0..len {
+            let (l, r) = (core::ptr::read_volatile(lhs.add(i)), core::ptr::read_volatile(rhs.add(i)));
+            if l != r {
+                return l as i32 - r as i32;
+            }
+        }
+        0
+    }
+
+    #[test]
+    fn test_entry_points_have_uniform_footprint() {
+        let _timing = crate::cache::timing_lock();
+        let len = 512;
+        let jit = crate::jit::compile_checked(len);
+        unsafe {
+            assert_uniform_footprint(crate::ct_memcmp, len);
+            assert_uniform_footprint(crate::ct_memcmp_nospec, len);
+            assert_uniform_footprint(crate::ffi::ffi_ct_memcmp, len);
+            assert_uniform_footprint(jit.as_comparator(), len);
+        }
+    }
+
+    #[test]
+    fn test_early_exit_is_detected() {
+        let _timing = crate::cache::timing_lock();
+        let len = 512;
+        let pairs = secret_pairs(len, 8, &mut rand::thread_rng());
+        let mut tester = FootprintTester::new(len).unwrap();
+        let report = unsafe { tester.run(early_exit_memcmp, &pairs, 20) };
+        assert!(!report.is_uniform(), "{:?}", report.z);
+        // Pair 1 differs in byte 0, pair 0 not at all: the last lhs line
+        // separates them. Untouched lines still hit now and then through
+        // the prefetchers.
+        let last = len / LINE_SIZE - 1;
+        assert!(report.hit_rate(0, last) > report.hit_rate(1, last) + 0.3, "{:?}", report.hits);
+        assert!(std::panic::catch_unwind(|| unsafe { assert_uniform_footprint(early_exit_memcmp, len) }).is_err());
+    }
+
+    #[test]
+    fn test_homogeneity_score() {
+        assert_eq!(homogeneity_z([30, 30, 30].into_iter(), 30), 0.0);
+        assert!(homogeneity_z([14, 16, 15, 15].into_iter(), 30).abs() < 2.0);
+        assert!(homogeneity_z([30, 0, 30, 0].into_iter(), 30) > UNIFORM_Z);
+    }
+}
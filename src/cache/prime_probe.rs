@@ -0,0 +1,510 @@
+//! Prime+Probe: fill a cache set with our own lines, let the victim run,
+//! then time walking those lines again. A slow walk means the victim
+//! evicted one of them. Needs neither shared memory nor `clflush`.
+//!
+//! L1d sets are built from address bits, since the set index lies inside
+//! the page offset. Sets of physically indexed caches are found by timing
+//! alone with the group-testing reduction of Vila et al.
+
+use std::arch::asm;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::ptr;
+
+use super::flush_reload::{self, Calibration};
+use crate::fault::PageBuffer;
+use crate::timer;
+
+const PAGE_SIZE: usize = 4096;
+const CACHE_DIR: &str = "/sys/devices/system/cpu/cpu0/cache";
+/// Offsets of the forward and backward links stored in each line.
+const NEXT: usize = 0;
+const PREV: usize = 8;
+/// Votes per eviction test; the majority decides.
+const EVICTION_TRIALS: usize = 5;
+const CALIBRATION_SAMPLES: usize = 200;
+const WALK_PASSES: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheGeometry {
+    pub level: u32,
+    pub line_size: usize,
+    pub sets: usize,
+    pub ways: usize,
+}
+
+impl CacheGeometry {
+    /// 32 KiB, 8-way: what L1d has looked like on x86 for a long time.
+    pub const L1D_FALLBACK: CacheGeometry = CacheGeometry { level: 1, line_size: 64, sets: 64, ways: 8 };
+
+    /// Reads the first cache of `level` from sysfs whose type is `Data` or
+    /// `Unified`.
+    pub fn read(level: u32) -> io::Result<Self> {
+        let field = |dir: &Path, name: &str| -> io::Result<String> {
+            Ok(fs::read_to_string(dir.join(name))?.trim().to_string())
+        };
+        let number = |dir: &Path, name: &str| -> io::Result<usize> {
+            field(dir, name)?
+                .parse()
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("bad {} in {}", name, dir.display())))
+        };
+
+        let mut dirs: Vec<_> = fs::read_dir(CACHE_DIR)?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.file_name().is_some_and(|n| n.to_string_lossy().starts_with("index")))
+            .collect();
+        dirs.sort();
+        for dir in dirs {
+            if number(&dir, "level")? != level as usize || field(&dir, "type")? == "Instruction" {
+                continue;
+            }
+            return Ok(Self {
+                level,
+                line_size: number(&dir, "coherency_line_size")?,
+                sets: number(&dir, "number_of_sets")?,
+                ways: number(&dir, "ways_of_associativity")?,
+            });
+        }
+        Err(io::Error::new(io::ErrorKind::NotFound, format!("no L{} data cache", level)))
+    }
+
+    pub fn l1d() -> Self {
+        Self::read(1).unwrap_or(Self::L1D_FALLBACK)
+    }
+
+    /// The highest cache level sysfs reports.
+    pub fn llc() -> Option<Self> {
+        (2..=4).rev().find_map(|level| Self::read(level).ok())
+    }
+
+    pub fn size(&self) -> usize {
+        self.line_size * self.sets * self.ways
+    }
+
+    /// Bytes between two addresses that map to the same set.
+    pub fn way_size(&self) -> usize {
+        self.line_size * self.sets
+    }
+
+    pub fn set_index(&self, addr: usize) -> usize {
+        addr / self.line_size % self.sets
+    }
+
+    /// How many sets a line at a given page offset can land in, given that
+    /// the physical page is unknown. 1 for a virtually indexed L1d.
+    pub fn page_colors(&self) -> usize {
+        self.way_size().div_ceil(PAGE_SIZE)
+    }
+}
+
+/// Cache lines linked into a list through their first 16 bytes, so a walk
+/// is a chain of dependent loads whose latencies add up.
+pub struct EvictionSet {
+    lines: Vec<*const u8>,
+}
+
+impl EvictionSet {
+    /// Writes the links into `lines`.
+    ///
+    /// # Safety
+    ///
+    /// Every line must be valid for reads and writes of 16 bytes for the
+    /// life of the set, and not be used for anything else.
+    pub unsafe fn new(lines: Vec<*mut u8>) -> Self {
+        assert!(!lines.is_empty(), "empty eviction set");
+        let n = lines.len();
+        for i in 0..n {
+            ptr::write(lines[i].add(NEXT) as *mut *const u8, lines[(i + 1) % n]);
+            ptr::write(lines[i].add(PREV) as *mut *const u8, lines[(i + n - 1) % n]);
+        }
+        Self { lines: lines.into_iter().map(|l| l as *const u8).collect() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    pub fn lines(&self) -> &[*const u8] {
+        &self.lines
+    }
+
+    /// Loads every line, front to back.
+    ///
+    /// The walks are written in assembly so they stay a tight chain of
+    /// loads in debug builds too, where a Rust loop adds stack traffic of
+    /// its own.
+    #[inline(always)]
+    pub fn prime(&self) {
+        unsafe {
+            asm!(
+                "2:",
+                "mov {line}, [{line} + {link}]",
+                "dec {steps}",
+                "jnz 2b",
+                line = inout(reg) self.lines[0] => _,
+                steps = inout(reg) self.lines.len() => _,
+                link = const NEXT,
+                options(nostack, readonly),
+            );
+        }
+    }
+
+    /// Walks the set back to front and returns the cycles taken on
+    /// `timer::default_timer`, overhead subtracted. Going
+    /// backwards keeps the walk from evicting lines it has yet to visit
+    /// under LRU-like replacement, and leaves the set primed again.
+    #[inline(always)]
+    pub fn probe(&self) -> u64 {
+        let timer = timer::default_timer();
+        let start = timer.start();
+        unsafe {
+            asm!(
+                "2:",
+                "mov {line}, [{line} + {link}]",
+                "dec {steps}",
+                "jnz 2b",
+                line = inout(reg) self.lines[self.lines.len() - 1] => _,
+                steps = inout(reg) self.lines.len() => _,
+                link = const PREV,
+                options(nostack, readonly),
+            );
+        }
+        let stop = timer.stop();
+        timer.elapsed(start, stop)
+    }
+}
+
+/// Per-set activity measured by `L1dPrimeProbe::activity`.
+#[derive(Debug, Clone)]
+pub struct SetActivity {
+    /// Extra probe cycles per set with the victim run, compared with idle
+    /// rounds. Means are taken over the fastest 90% of samples of each
+    /// kind, since interrupts only ever add time.
+    pub excess: Vec<f64>,
+    pub rounds: usize,
+}
+
+impl SetActivity {
+    /// Sets whose excess is more than `min_cycles` above the median set,
+    /// highest first. A victim's own footprint can only add time, so the
+    /// median set stands in for "untouched".
+    pub fn active(&self, min_cycles: f64) -> Vec<usize> {
+        let mut sorted = self.excess.clone();
+        sorted.sort_by(f64::total_cmp);
+        let floor = sorted[sorted.len() / 2] + min_cycles;
+        let mut sets: Vec<usize> = (0..self.excess.len()).filter(|&s| self.excess[s] > floor).collect();
+        sets.sort_by(|&a, &b| self.excess[b].total_cmp(&self.excess[a]).then(a.cmp(&b)));
+        sets
+    }
+}
+
+fn trimmed_mean(mut samples: Vec<u64>) -> f64 {
+    samples.sort_unstable();
+    let kept = &samples[..(samples.len() * 9 / 10).max(1)];
+    kept.iter().sum::<u64>() as f64 / kept.len() as f64
+}
+
+/// One eviction set per L1d set, carved out of `ways` consecutive way-sized
+/// blocks of a page-aligned buffer.
+pub struct L1dPrimeProbe {
+    _buffer: PageBuffer,
+    geometry: CacheGeometry,
+    sets: Vec<EvictionSet>,
+}
+
+impl L1dPrimeProbe {
+    pub fn new() -> Option<Self> {
+        Self::with_geometry(CacheGeometry::l1d())
+    }
+
+    /// Requires a virtually indexed cache, i.e. `way_size()` no larger than
+    /// a page.
+    pub fn with_geometry(geometry: CacheGeometry) -> Option<Self> {
+        if geometry.way_size() > PAGE_SIZE || geometry.line_size < 16 {
+            return None;
+        }
+        let stride = geometry.way_size();
+        let mut buffer = PageBuffer::new(stride * geometry.ways)?;
+        let base = buffer.as_mut_slice().as_mut_ptr();
+        let sets = (0..geometry.sets)
+            .map(|s| unsafe {
+                EvictionSet::new((0..geometry.ways).map(|w| base.add(w * stride + s * geometry.line_size)).collect())
+            })
+            .collect();
+        Some(Self { _buffer: buffer, geometry, sets })
+    }
+
+    pub fn geometry(&self) -> CacheGeometry {
+        self.geometry
+    }
+
+    pub fn len(&self) -> usize {
+        self.sets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sets.is_empty()
+    }
+
+    pub fn set(&self, index: usize) -> &EvictionSet {
+        &self.sets[index]
+    }
+
+    pub fn prime_all(&self) {
+        for set in &self.sets {
+            set.prime();
+        }
+    }
+
+    /// Probe time of every set, indexed by set.
+    pub fn probe_all(&self) -> Vec<u64> {
+        let mut times = vec![0; self.sets.len()];
+        self.probe_into(&mut times);
+        times
+    }
+
+    /// Like `probe_all`, into a caller-owned buffer. Writing the results
+    /// touches a few sets itself; reusing one buffer keeps those the same
+    /// from round to round.
+    pub fn probe_into(&self, times: &mut [u64]) {
+        for (t, set) in times.iter_mut().zip(&self.sets) {
+            *t = set.probe();
+        }
+    }
+
+    /// Measures which sets `victim` touches. Each round primes, runs and
+    /// probes one set at a time, paired with an idle prime and probe of the
+    /// same set for the baseline. Priming the whole cache at once leaves
+    /// too little room for our own stack and buffers, and the resulting
+    /// misses drown out a victim's.
+    pub fn activity(&self, mut victim: impl FnMut(), rounds: usize) -> SetActivity {
+        let sets = self.sets.len();
+        let mut idle: Vec<Vec<u64>> = vec![Vec::with_capacity(rounds); sets];
+        let mut busy: Vec<Vec<u64>> = vec![Vec::with_capacity(rounds); sets];
+        for _ in 0..rounds {
+            for (s, set) in self.sets.iter().enumerate() {
+                set.prime();
+                let t = set.probe();
+                idle[s].push(t);
+                set.prime();
+                victim();
+                let t = set.probe();
+                busy[s].push(t);
+            }
+        }
+
+        let excess = idle.into_iter().zip(busy).map(|(i, b)| trimmed_mean(b) - trimmed_mean(i)).collect();
+        SetActivity { excess, rounds }
+    }
+}
+
+/// Shrinks `candidates` to a minimal set that still evicts the target, by
+/// group testing: split into `ways + 1` groups, drop one whose removal
+/// keeps the set evicting, repeat. Under LRU some group can always be
+/// dropped while more than `ways` lines remain, so `evicts` is called
+/// O(ways^2 log n) times and exactly `ways` lines are left.
+///
+/// Adaptive replacement policies can need more than `ways` congruent lines,
+/// so when no group can go the split gets finer, down to single lines.
+/// The result may then be somewhat larger than `ways`. Returns `None` if
+/// the candidates do not evict the target, before or after reduction.
+pub fn reduce(candidates: Vec<*const u8>, ways: usize, mut evicts: impl FnMut(&[*const u8]) -> bool) -> Option<Vec<*const u8>> {
+    assert!(ways > 0, "associativity must be non-zero");
+    let mut set = candidates;
+    if set.len() < ways || !evicts(&set) {
+        return None;
+    }
+    // At least `ways + 1` non-empty groups; with fewer, every group could
+    // hold a congruent line.
+    let mut groups = ways + 1;
+    while set.len() > ways {
+        groups = groups.min(set.len());
+        let bounds = |g: usize| (g * set.len() / groups, (g + 1) * set.len() / groups);
+        let removable = (0..groups).map(bounds).find(|&(start, end)| {
+            let rest: Vec<*const u8> = set[..start].iter().chain(&set[end..]).copied().collect();
+            evicts(&rest)
+        });
+        match removable {
+            Some((start, end)) => {
+                set.drain(start..end);
+            }
+            None if groups < set.len() => groups *= 2,
+            None => break,
+        }
+    }
+    // A noisy positive may have dropped a line that was needed.
+    evicts(&set).then_some(set)
+}
+
+/// Candidate lines for eviction sets of a physically indexed cache: one
+/// line per page, all at the same page offset.
+pub struct EvictionPool {
+    _buffer: PageBuffer,
+    lines: Vec<*const u8>,
+    calibration: Calibration,
+}
+
+impl EvictionPool {
+    pub fn new(pages: usize, offset: usize) -> Option<Self> {
+        assert!(pages > 1 && offset + 16 <= PAGE_SIZE, "invalid pool layout");
+        let mut buffer = PageBuffer::new(pages * PAGE_SIZE)?;
+        let base = buffer.as_mut_slice().as_mut_ptr();
+        let lines: Vec<*const u8> = (0..pages)
+            .map(|p| unsafe {
+                let line = base.add(p * PAGE_SIZE + offset);
+                // Fault every page in so none is backed by the shared zero page.
+                ptr::write_volatile(line, 1);
+                line as *const u8
+            })
+            .collect();
+        let calibration = Calibration { hit_median: 0, miss_median: 0, threshold: flush_reload::DEFAULT_THRESHOLD };
+        Some(Self { _buffer: buffer, lines, calibration })
+    }
+
+    /// Twice as many pages as it takes to cover every color with `ways`
+    /// lines, which makes a full-pool eviction very likely.
+    pub fn for_geometry(geometry: &CacheGeometry) -> Option<Self> {
+        Self::new(2 * geometry.ways * geometry.page_colors(), 0)
+    }
+
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    pub fn line(&self, i: usize) -> *const u8 {
+        self.lines[i]
+    }
+
+    pub fn calibration(&self) -> Calibration {
+        self.calibration
+    }
+
+    /// Measures the latency of `lines[target]` just after a load and after
+    /// walking the whole pool. Uses no `clflush`, so the "miss" level is
+    /// wherever the pool pushes the line, not necessarily DRAM.
+    pub fn calibrate(&mut self, target: usize) -> Calibration {
+        let t = self.lines[target];
+        let others: Vec<*const u8> = self.lines.iter().copied().filter(|&l| l != t).collect();
+        let mut hits = Vec::with_capacity(CALIBRATION_SAMPLES);
+        let mut misses = Vec::with_capacity(CALIBRATION_SAMPLES);
+        for _ in 0..CALIBRATION_SAMPLES {
+            unsafe {
+                ptr::read_volatile(t);
+                hits.push(flush_reload::reload(t));
+                misses.push(reload_after_walk(t, &others));
+            }
+        }
+        self.calibration = Calibration::from_samples(hits, misses);
+        self.calibration
+    }
+
+    /// Whether walking `lines` evicts `target`, by majority of a few trials.
+    ///
+    /// # Safety
+    ///
+    /// `target` and every line must be valid for reads.
+    pub unsafe fn evicts(&self, target: *const u8, lines: &[*const u8]) -> bool {
+        let mut votes = 0;
+        for _ in 0..EVICTION_TRIALS {
+            ptr::read_volatile(target);
+            votes += !self.calibration.is_hit(reload_after_walk(target, lines)) as usize;
+        }
+        votes * 2 > EVICTION_TRIALS
+    }
+
+    /// Calibrates on `lines[target]` and reduces the rest of the pool to a
+    /// minimal eviction set for it.
+    pub fn find(&mut self, target: usize, ways: usize) -> Option<EvictionSet> {
+        self.calibrate(target);
+        let t = self.lines[target];
+        let candidates = self.lines.iter().copied().filter(|&l| l != t).collect();
+        let found = reduce(candidates, ways, |lines| unsafe { self.evicts(t, lines) })?;
+        Some(unsafe { EvictionSet::new(found.into_iter().map(|l| l as *mut u8).collect()) })
+    }
+}
+
+/// Several passes, so lines an adaptive replacement policy kept around
+/// after the first one get pushed out too.
+#[inline(always)]
+unsafe fn walk(lines: &[*const u8]) {
+    for _ in 0..WALK_PASSES {
+        for &line in lines {
+            ptr::read_volatile(line);
+        }
+    }
+}
+
+/// Walks `lines`, then times `target`. A long walk also evicts the
+/// target's TLB entry, and the page walk alone can look like a cache
+/// miss, so another line of the same page is loaded first.
+#[inline(always)]
+unsafe fn reload_after_walk(target: *const u8, lines: &[*const u8]) -> u64 {
+    walk(lines);
+    ptr::read_volatile((target as usize ^ (PAGE_SIZE / 2)) as *const u8);
+    flush_reload::reload(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reduce_finds_congruent_lines() {
+        // Line i is congruent with the target iff i % 7 == 0; the simulated
+        // cache evicts once `ways` congruent lines are walked.
+        let ways = 4;
+        let candidates: Vec<*const u8> = (1..=200usize).map(|i| (i * PAGE_SIZE) as *const u8).collect();
+        let congruent = |l: &*const u8| (*l as usize / PAGE_SIZE).is_multiple_of(7);
+        let mut calls = 0;
+        let found = reduce(candidates.clone(), ways, |set| {
+            calls += 1;
+            set.iter().filter(|l| congruent(l)).count() >= ways
+        })
+        .unwrap();
+        assert_eq!(found.len(), ways);
+        assert!(found.iter().all(congruent), "{:?}", found);
+        assert!(calls < 200, "{}", calls);
+
+        assert!(reduce(candidates, ways, |_| false).is_none());
+    }
+
+    #[test]
+    fn test_l1d_sets_follow_address_bits() {
+        let pp = L1dPrimeProbe::new().unwrap();
+        let g = pp.geometry();
+        assert_eq!(pp.len(), g.sets);
+        for s in 0..g.sets {
+            let lines = pp.set(s).lines();
+            assert_eq!(lines.len(), g.ways);
+            assert!(lines.iter().all(|&l| g.set_index(l as usize) == s));
+        }
+        assert!(L1dPrimeProbe::with_geometry(CacheGeometry { sets: 4096, ..g }).is_none());
+    }
+
+    #[test]
+    fn test_detects_touched_l1d_set() {
+        let _timing = crate::cache::timing_lock();
+        let pp = L1dPrimeProbe::new().unwrap();
+        let g = pp.geometry();
+        // One extra miss is lost in the noise under a hypervisor, so the
+        // victim walks a whole set's worth of congruent lines.
+        let mut target = PageBuffer::new(g.ways * g.way_size()).unwrap();
+        let set = 37 % g.sets;
+        let base = target.as_mut_slice().as_mut_ptr();
+        let victim =
+            unsafe { EvictionSet::new((0..g.ways).map(|w| base.add(w * g.way_size() + set * g.line_size)).collect()) };
+
+        let activity = pp.activity(|| victim.prime(), 300);
+        assert_eq!(activity.active(4.0).first(), Some(&set), "{:?}", activity.excess);
+    }
+}
@@ -0,0 +1,373 @@
+//! Putting a buffer into a known cache state before timing accesses to it.
+//!
+//! Freshly written memory is hot no matter what the variable is called, so
+//! a "cold" measurement needs an explicit preparation step. `CachePreparer`
+//! brings a buffer into L1, evicts it to L2 or the LLC, flushes it to DRAM,
+//! or leaves its data cached with the translation gone from the TLBs.
+//! Each state can be checked by timing one access against a latency
+//! profile measured the same way; TLB-cold is checked for a page walk's
+//! worth of cycles on top of the same access with the TLB warm.
+
+use core::arch::x86_64::_mm_mfence;
+use std::fmt;
+use std::ptr;
+
+use super::flush_reload::{flush, reload};
+use super::prime_probe::{CacheGeometry, L1dPrimeProbe};
+use crate::fault::PageBuffer;
+
+const PAGE_SIZE: usize = 4096;
+const LINE_SIZE: usize = 64;
+/// Assumed when sysfs does not describe L2.
+const L2_FALLBACK: usize = 2 << 20;
+/// Well past the second-level TLB of current cores (1.5k to 3k entries).
+const TLB_SWEEP_PAGES: usize = 8192;
+const PROFILE_SAMPLES: usize = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CacheState {
+    L1,
+    /// In L2, evicted from L1.
+    L2,
+    /// In the last-level cache, evicted from L1 and L2.
+    Llc,
+    /// Flushed from every cache level.
+    Dram,
+    /// Data still cached (typically in L2), translation evicted from every
+    /// TLB level, so the access needs a page walk.
+    TlbCold,
+}
+
+impl CacheState {
+    pub const ALL: [CacheState; 5] =
+        [CacheState::L1, CacheState::L2, CacheState::Llc, CacheState::Dram, CacheState::TlbCold];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            CacheState::L1 => "L1",
+            CacheState::L2 => "L2",
+            CacheState::Llc => "LLC",
+            CacheState::Dram => "DRAM",
+            CacheState::TlbCold => "TLB-cold",
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl fmt::Display for CacheState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.name())
+    }
+}
+
+/// Median latency of one access in each state, in `timer::default_timer`
+/// cycles with its overhead subtracted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencyProfile {
+    pub medians: [u64; 5],
+    /// Median cycles the TLB-cold access loses to its page walk: the
+    /// TLB-cold median minus that of the same line after the same sweep
+    /// with the translation reloaded first.
+    pub page_walk: u64,
+}
+
+impl LatencyProfile {
+    pub fn median(&self, state: CacheState) -> u64 {
+        self.medians[state.index()]
+    }
+
+    /// The state whose median is closest to `cycles`; the earlier state in
+    /// `CacheState::ALL` wins ties.
+    pub fn classify(&self, cycles: u64) -> CacheState {
+        CacheState::ALL
+            .into_iter()
+            .min_by_key(|&s| self.median(s).abs_diff(cycles))
+            .unwrap()
+    }
+
+    /// Whether `cycles` carries at least half a page walk on top of a
+    /// TLB-warm access to swept data, but is still faster than DRAM. The
+    /// TLB-cold median sits within a walk of both L2 and LLC, so the
+    /// nearest median cannot tell it apart.
+    pub fn walked(&self, cycles: u64) -> bool {
+        let warm = self.median(CacheState::TlbCold).saturating_sub(self.page_walk);
+        cycles > warm + self.page_walk / 2 && cycles < self.median(CacheState::Dram)
+    }
+}
+
+impl fmt::Display for LatencyProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parts: Vec<String> = CacheState::ALL.iter().map(|&s| format!("{} {}", s, self.median(s))).collect();
+        write!(f, "{}, page walk {} cycles", parts.join(", "), self.page_walk)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Verification {
+    pub expected: CacheState,
+    pub observed: CacheState,
+    pub cycles: u64,
+}
+
+impl Verification {
+    pub fn reached(&self) -> bool {
+        self.expected == self.observed
+    }
+}
+
+/// Eviction buffers for each level plus the latency profile used to
+/// verify preparations.
+pub struct CachePreparer {
+    l1: L1dPrimeProbe,
+    sweep: PageBuffer,
+    /// Bytes of `sweep` walked line by line to push data out of L2.
+    l2_sweep: usize,
+    profile: LatencyProfile,
+}
+
+impl CachePreparer {
+    pub fn new() -> Option<Self> {
+        let l2 = CacheGeometry::read(2).map_or(L2_FALLBACK, |g| g.size());
+        let l2_sweep = 2 * l2;
+        let mut sweep = PageBuffer::new(l2_sweep.max(TLB_SWEEP_PAGES * PAGE_SIZE))?;
+        // The TLB sweep needs one translation per 4 KiB page; a transparent
+        // huge page would cover 512 of them with a single entry. Kernels
+        // without THP reject the advice, which is fine.
+        let pages = sweep.as_mut_slice();
+        unsafe { libc::madvise(pages.as_mut_ptr() as *mut libc::c_void, pages.len(), libc::MADV_NOHUGEPAGE) };
+        // Give every page its own frame, or the sweep would only ever touch
+        // the shared zero page.
+        for page in sweep.as_mut_slice().chunks_mut(PAGE_SIZE) {
+            page[0] = 1;
+        }
+        let mut preparer = Self {
+            l1: L1dPrimeProbe::new()?,
+            sweep,
+            l2_sweep,
+            profile: LatencyProfile { medians: [0; 5], page_walk: 0 },
+        };
+        preparer.calibrate()?;
+        Some(preparer)
+    }
+
+    pub fn profile(&self) -> LatencyProfile {
+        self.profile
+    }
+
+    /// Re-measures the profile on a scratch page.
+    pub fn calibrate(&mut self) -> Option<LatencyProfile> {
+        let scratch = PageBuffer::new(PAGE_SIZE)?;
+        let mut medians = [0; 5];
+        for state in CacheState::ALL {
+            let mut samples: Vec<u64> = (0..PROFILE_SAMPLES)
+                .map(|_| unsafe {
+                    self.prepare(scratch.as_ptr(), LINE_SIZE, state);
+                    reload(scratch.as_ptr())
+                })
+                .collect();
+            samples.sort_unstable();
+            medians[state.index()] = samples[samples.len() / 2];
+        }
+        // The sweep also pushes the data out of L1, and sometimes L2, so the
+        // walk is measured against the same sweep with another line of the
+        // page reloading the translation first.
+        let other = scratch.as_ptr().wrapping_add(PAGE_SIZE / 2);
+        let mut warm: Vec<u64> = (0..PROFILE_SAMPLES)
+            .map(|_| unsafe {
+                self.prepare(scratch.as_ptr(), LINE_SIZE, CacheState::TlbCold);
+                ptr::read_volatile(other);
+                reload(scratch.as_ptr())
+            })
+            .collect();
+        warm.sort_unstable();
+        let page_walk = medians[CacheState::TlbCold.index()].saturating_sub(warm[warm.len() / 2]);
+        self.profile = LatencyProfile { medians, page_walk };
+        Some(self.profile)
+    }
+
+    /// Puts `len` bytes at `buf` into `state`.
+    ///
+    /// `TlbCold` only works on a buffer mapped with 4 KiB pages, for the
+    /// same reason the sweep is: a huge-page translation sits in its own TLB
+    /// entries, which the sweep never displaces. `PageBuffer`s of less than
+    /// 2 MiB always are; larger ones need `MADV_NOHUGEPAGE` where THP is on.
+    ///
+    /// `Llc` also reads the line half a page away from `buf`, which is
+    /// mapped along with `buf`'s page and ends up in L1 if it is part of the
+    /// buffer.
+    ///
+    /// # Safety
+    ///
+    /// `buf` must be valid for reads of `len` bytes.
+    pub unsafe fn prepare(&self, buf: *const u8, len: usize, state: CacheState) {
+        if state == CacheState::Dram {
+            for offset in (0..len).step_by(LINE_SIZE) {
+                flush(buf.add(offset));
+            }
+            _mm_mfence();
+            return;
+        }
+
+        touch(buf, len);
+        match state {
+            CacheState::L1 => {}
+            CacheState::L2 => {
+                // Every L1d set filled with our own lines, twice so the
+                // victim's lines are the least recently used.
+                self.l1.prime_all();
+                self.l1.prime_all();
+            }
+            CacheState::Llc => {
+                touch(self.sweep.as_ptr(), self.l2_sweep);
+                // The sweep crosses a thousand pages and can take the
+                // buffer's translation with it, adding a page walk that
+                // reads slower than DRAM; a line in the other half of the
+                // page brings it back.
+                ptr::read_volatile((buf as usize ^ (PAGE_SIZE / 2)) as *const u8);
+            }
+            CacheState::TlbCold => {
+                // One line per page, with the offset rotating so the walk
+                // does not pile up in a few cache sets.
+                let mut page = 0;
+                while page < TLB_SWEEP_PAGES {
+                    let offset = page * PAGE_SIZE + page * LINE_SIZE % PAGE_SIZE;
+                    ptr::read_volatile(self.sweep.as_ptr().wrapping_add(offset));
+                    page += 1;
+                }
+            }
+            CacheState::Dram => unreachable!(),
+        }
+        _mm_mfence();
+    }
+
+    /// Times one access to the first line of `buf` and classifies it by the
+    /// nearest cache-level median, or as `TlbCold` if that was expected and
+    /// `LatencyProfile::walked` holds. The access itself brings that line
+    /// into L1, so prepare again before measuring anything else.
+    ///
+    /// # Safety
+    ///
+    /// `buf` must be valid for reads.
+    pub unsafe fn verify(&self, buf: *const u8, expected: CacheState) -> Verification {
+        let cycles = reload(buf);
+        // The TLB-cold median sits among the cache levels' and would claim
+        // their slower hits, so it only counts with the walk criterion.
+        let observed = if expected == CacheState::TlbCold && self.profile.walked(cycles) {
+            CacheState::TlbCold
+        } else {
+            CacheState::ALL
+                .into_iter()
+                .filter(|&s| s != CacheState::TlbCold)
+                .min_by_key(|&s| self.profile.median(s).abs_diff(cycles))
+                .unwrap()
+        };
+        Verification { expected, observed, cycles }
+    }
+
+    /// `prepare`, `verify`, then `prepare` again so the buffer is in
+    /// `state` when this returns.
+    ///
+    /// # Safety
+    ///
+    /// As for `prepare`.
+    pub unsafe fn prepare_verified(&self, buf: *const u8, len: usize, state: CacheState) -> Verification {
+        self.prepare(buf, len, state);
+        let verification = self.verify(buf, state);
+        self.prepare(buf, len, state);
+        verification
+    }
+}
+
+/// Reads one byte per line. Plain arithmetic rather than `step_by` and
+/// `add`, so the multi-megabyte sweeps stay quick in debug builds too.
+#[inline(always)]
+unsafe fn touch(buf: *const u8, len: usize) {
+    let mut offset = 0;
+    while offset < len {
+        ptr::read_volatile(buf.wrapping_add(offset));
+        offset += LINE_SIZE;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SETTLE_ATTEMPTS: usize = 8;
+
+    #[test]
+    fn test_classify_picks_nearest_median() {
+        let profile = LatencyProfile { medians: [40, 60, 120, 300, 160], page_walk: 80 };
+        assert_eq!(profile.classify(10), CacheState::L1);
+        assert_eq!(profile.classify(55), CacheState::L2);
+        assert_eq!(profile.classify(150), CacheState::TlbCold);
+        assert_eq!(profile.classify(1000), CacheState::Dram);
+        assert_eq!(profile.to_string(), "L1 40, L2 60, LLC 120, DRAM 300, TLB-cold 160, page walk 80 cycles");
+    }
+
+    #[test]
+    fn test_walked_needs_half_a_walk() {
+        // TLB-warm after the sweep at 80.
+        let profile = LatencyProfile { medians: [40, 60, 120, 300, 160], page_walk: 80 };
+        assert!(!profile.walked(120));
+        assert!(profile.walked(121));
+        assert!(profile.walked(160));
+        assert!(!profile.walked(300));
+    }
+
+    /// Recalibrates until `ok` holds, a bounded number of times. A busy
+    /// neighbour on the shared L3 can evict lines mid-sweep, which smears
+    /// every state past L2 in the profile taken meanwhile.
+    fn settle(preparer: &mut CachePreparer, mut ok: impl FnMut(&CachePreparer) -> bool) -> bool {
+        for _ in 0..SETTLE_ATTEMPTS {
+            if ok(preparer) {
+                return true;
+            }
+            preparer.calibrate().unwrap();
+        }
+        ok(preparer)
+    }
+
+    #[test]
+    fn test_profile_is_ordered() {
+        let _timing = crate::cache::timing_lock();
+        let mut preparer = CachePreparer::new().unwrap();
+        let ordered = |p: &CachePreparer| {
+            let m = |s| p.profile().median(s);
+            m(CacheState::L1) < m(CacheState::L2)
+                && m(CacheState::L2) < m(CacheState::Llc)
+                && m(CacheState::Llc) < m(CacheState::Dram)
+                && m(CacheState::L2) < m(CacheState::TlbCold)
+                && p.profile().page_walk > 0
+        };
+        assert!(settle(&mut preparer, ordered), "{}", preparer.profile());
+    }
+
+    #[test]
+    fn test_states_are_reached() {
+        let _timing = crate::cache::timing_lock();
+        let mut preparer = CachePreparer::new().unwrap();
+        let buf = PageBuffer::new(PAGE_SIZE).unwrap();
+        let count = |preparer: &CachePreparer, state| {
+            (0..20)
+                .filter(|_| unsafe { preparer.prepare_verified(buf.as_ptr(), 256, state) }.reached())
+                .count()
+        };
+        for state in CacheState::ALL {
+            // L2 and LLC sit closer to their neighbours than L1 and DRAM.
+            let needed = match state {
+                CacheState::L2 | CacheState::Llc => 12,
+                _ => 15,
+            };
+            let mut reached = 0;
+            let ok = settle(&mut preparer, |p| {
+                reached = count(p, state);
+                reached >= needed
+            });
+            assert!(ok, "{}: {}/20 with {}", state, reached, preparer.profile());
+        }
+    }
+}
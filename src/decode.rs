@@ -0,0 +1,484 @@
+//! Minimal x86-64 decoder: instruction length, ModRM/SIB memory operands and
+//! immediates. It does not name mnemonics; callers match on `map`/`opcode`.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpcodeMap {
+    Primary,
+    Secondary,
+    Escape38,
+    Escape3A,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Segment {
+    Fs,
+    Gs,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Prefixes {
+    pub operand_size: bool,
+    pub address_size: bool,
+    pub lock: bool,
+    pub rep: Option<u8>,
+    pub segment: Option<Segment>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModRm {
+    pub mode: u8,
+    /// `reg` field, extended by REX.R.
+    pub reg: u8,
+    /// `rm` field, extended by REX.B. Only meaningful when `mode == 3`.
+    pub rm: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemOperand {
+    pub base: Option<u8>,
+    pub index: Option<u8>,
+    pub scale: u8,
+    pub disp: i64,
+    pub rip_relative: bool,
+    pub segment: Option<Segment>,
+    pub address_size_32: bool,
+}
+
+impl MemOperand {
+    /// Computes the effective address. `reg` reads a general-purpose
+    /// register by number, `next_rip` is the address of the following
+    /// instruction and `seg_base` returns the FS/GS base.
+    pub fn effective_address(
+        &self,
+        reg: impl Fn(u8) -> u64,
+        next_rip: u64,
+        seg_base: impl Fn(Segment) -> u64,
+    ) -> u64 {
+        let mut addr = if self.rip_relative { next_rip } else { 0 };
+        if let Some(base) = self.base {
+            addr = addr.wrapping_add(reg(base));
+        }
+        if let Some(index) = self.index {
+            addr = addr.wrapping_add(reg(index).wrapping_mul(self.scale as u64));
+        }
+        addr = addr.wrapping_add(self.disp as u64);
+        if self.address_size_32 {
+            addr &= 0xFFFF_FFFF;
+        }
+        if let Some(seg) = self.segment {
+            addr = addr.wrapping_add(seg_base(seg));
+        }
+        addr
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Instruction {
+    pub len: usize,
+    pub prefixes: Prefixes,
+    pub rex: u8,
+    pub vex: bool,
+    pub map: OpcodeMap,
+    pub opcode: u8,
+    pub modrm: Option<ModRm>,
+    pub mem: Option<MemOperand>,
+    /// Sign-extended immediate or relative displacement, if any.
+    pub imm: Option<i64>,
+}
+
+impl Instruction {
+    pub fn rex_w(&self) -> bool {
+        self.rex & 0x08 != 0
+    }
+
+    /// Operand width in bytes for instructions with the usual
+    /// byte/word/dword/qword encoding rules.
+    pub fn operand_size(&self) -> u8 {
+        if self.rex_w() {
+            8
+        } else if self.prefixes.operand_size {
+            2
+        } else {
+            4
+        }
+    }
+
+    /// Jcc, JrCXZ and LOOPcc.
+    pub fn is_conditional_branch(&self) -> bool {
+        match self.map {
+            OpcodeMap::Primary => matches!(self.opcode, 0x70..=0x7F | 0xE0..=0xE3),
+            OpcodeMap::Secondary => matches!(self.opcode, 0x80..=0x8F),
+            _ => false,
+        }
+    }
+
+    /// Whether the ModRM memory operand is actually dereferenced. LEA and the
+    /// hint-NOP/prefetch space only compute an address.
+    pub fn accesses_memory(&self) -> bool {
+        if self.mem.is_none() {
+            return false;
+        }
+        match (self.map, self.opcode) {
+            (OpcodeMap::Primary, 0x8D) => false,
+            (OpcodeMap::Secondary, 0x0D | 0x18..=0x1F) if !self.vex => false,
+            _ => true,
+        }
+    }
+
+    /// Offset from RSP of the implicit stack slot touched by PUSH, POP,
+    /// CALL, RET and LEAVE-style instructions.
+    pub fn stack_slot(&self) -> Option<i64> {
+        if self.map != OpcodeMap::Primary || self.vex {
+            return None;
+        }
+        match self.opcode {
+            0x50..=0x57 | 0x68 | 0x6A | 0x9C | 0xE8 => Some(-8),
+            0x58..=0x5F | 0x9D | 0xC2 | 0xC3 | 0x8F => Some(0),
+            0xFF => match self.modrm.map(|m| m.reg & 7) {
+                Some(2) | Some(6) => Some(-8),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Imm {
+    None,
+    B,
+    W,
+    Z,
+    /// imm32, or imm64 with REX.W (MOV r64, imm64).
+    V,
+    /// imm16 followed by imm8 (ENTER).
+    Enter,
+    /// 64-bit absolute address (MOV moffs).
+    Moffs,
+}
+
+fn primary_has_modrm(op: u8) -> bool {
+    match op {
+        0x00..=0x3F => op & 0x07 < 4,
+        0x62 | 0x63 | 0x69 | 0x6B => true,
+        0x80..=0x8F => true,
+        0xC0 | 0xC1 | 0xC4..=0xC7 => true,
+        0xD0..=0xD3 | 0xD8..=0xDF => true,
+        0xF6 | 0xF7 | 0xFE | 0xFF => true,
+        _ => false,
+    }
+}
+
+fn primary_imm(op: u8, modrm: Option<ModRm>) -> Imm {
+    match op {
+        0x00..=0x3F if op & 0x07 == 4 => Imm::B,
+        0x00..=0x3F if op & 0x07 == 5 => Imm::Z,
+        0x68 | 0x69 | 0x81 | 0xC7 | 0xA9 | 0xE8 | 0xE9 => Imm::Z,
+        0x6A | 0x6B | 0x70..=0x7F | 0x80 | 0x83 | 0xA8 | 0xB0..=0xB7 => Imm::B,
+        0xC0 | 0xC1 | 0xC6 | 0xCD | 0xD4 | 0xD5 | 0xE0..=0xE7 | 0xEB => Imm::B,
+        0xA0..=0xA3 => Imm::Moffs,
+        0xB8..=0xBF => Imm::V,
+        0xC2 | 0xCA => Imm::W,
+        0xC8 => Imm::Enter,
+        0xF6 if modrm.is_some_and(|m| m.reg & 7 < 2) => Imm::B,
+        0xF7 if modrm.is_some_and(|m| m.reg & 7 < 2) => Imm::Z,
+        _ => Imm::None,
+    }
+}
+
+fn secondary_has_modrm(op: u8) -> bool {
+    !matches!(
+        op,
+        0x05..=0x09 | 0x0B | 0x0E | 0x30..=0x37 | 0x77 | 0x80..=0x8F | 0xA0..=0xA2 | 0xA8..=0xAA | 0xC8..=0xCF
+    )
+}
+
+fn secondary_imm(op: u8) -> Imm {
+    match op {
+        0x0F | 0x70..=0x73 | 0xA4 | 0xAC | 0xBA | 0xC2 | 0xC4..=0xC6 => Imm::B,
+        0x80..=0x8F => Imm::Z,
+        _ => Imm::None,
+    }
+}
+
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Cursor<'_> {
+    fn u8(&mut self) -> Option<u8> {
+        let b = *self.bytes.get(self.pos)?;
+        self.pos += 1;
+        Some(b)
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.pos).copied()
+    }
+
+    fn le(&mut self, n: usize) -> Option<i64> {
+        let raw = self.bytes.get(self.pos..self.pos + n)?;
+        self.pos += n;
+        let mut buf = [0u8; 8];
+        buf[..n].copy_from_slice(raw);
+        let v = u64::from_le_bytes(buf);
+        let shift = 64 - 8 * n as u32;
+        Some(((v << shift) as i64) >> shift)
+    }
+}
+
+/// Decodes one instruction from the start of `bytes`. Returns `None` for
+/// truncated input or encodings outside what the decoder understands
+/// (EVEX, XOP).
+pub fn decode(bytes: &[u8]) -> Option<Instruction> {
+    let mut cur = Cursor { bytes, pos: 0 };
+    let mut prefixes = Prefixes::default();
+
+    loop {
+        match cur.peek()? {
+            0x66 => prefixes.operand_size = true,
+            0x67 => prefixes.address_size = true,
+            0xF0 => prefixes.lock = true,
+            0xF2 | 0xF3 => prefixes.rep = cur.peek(),
+            0x64 => prefixes.segment = Some(Segment::Fs),
+            0x65 => prefixes.segment = Some(Segment::Gs),
+            0x26 | 0x2E | 0x36 | 0x3E => {}
+            _ => break,
+        }
+        cur.pos += 1;
+        if cur.pos > 14 {
+            return None;
+        }
+    }
+
+    let mut rex = 0u8;
+    if let Some(b @ 0x40..=0x4F) = cur.peek() {
+        rex = b;
+        cur.pos += 1;
+    }
+
+    let mut vex = false;
+    let (map, opcode) = match cur.u8()? {
+        0xC4 => {
+            let p0 = cur.u8()?;
+            let p1 = cur.u8()?;
+            vex = true;
+            rex = 0x40 | ((!p0 >> 5) & 0x07) | ((p1 >> 4) & 0x08);
+            prefixes.operand_size |= p1 & 0x03 == 1;
+            let map = match p0 & 0x1F {
+                1 => OpcodeMap::Secondary,
+                2 => OpcodeMap::Escape38,
+                3 => OpcodeMap::Escape3A,
+                _ => return None,
+            };
+            (map, cur.u8()?)
+        }
+        0xC5 => {
+            let p0 = cur.u8()?;
+            vex = true;
+            rex = 0x40 | ((!p0 >> 5) & 0x04);
+            prefixes.operand_size |= p0 & 0x03 == 1;
+            (OpcodeMap::Secondary, cur.u8()?)
+        }
+        0x62 => return None,
+        0x8F if cur.peek()? & 0x38 != 0 => return None,
+        0x0F => match cur.u8()? {
+            0x38 => (OpcodeMap::Escape38, cur.u8()?),
+            0x3A => (OpcodeMap::Escape3A, cur.u8()?),
+            op => (OpcodeMap::Secondary, op),
+        },
+        op => (OpcodeMap::Primary, op),
+    };
+
+    let has_modrm = match map {
+        OpcodeMap::Primary => primary_has_modrm(opcode),
+        OpcodeMap::Secondary if vex => opcode != 0x77,
+        OpcodeMap::Secondary => secondary_has_modrm(opcode),
+        OpcodeMap::Escape38 | OpcodeMap::Escape3A => true,
+    };
+
+    let mut modrm = None;
+    let mut mem = None;
+    if has_modrm {
+        let byte = cur.u8()?;
+        let mode = byte >> 6;
+        let reg = ((byte >> 3) & 7) | ((rex & 0x04) << 1);
+        let mut rm = byte & 7;
+        modrm = Some(ModRm { mode, reg, rm: rm | ((rex & 0x01) << 3) });
+
+        if mode != 3 {
+            let mut base = None;
+            let mut index = None;
+            let mut scale = 1;
+            let mut rip_relative = false;
+            let mut disp_len = match mode {
+                1 => 1,
+                2 => 4,
+                _ => 0,
+            };
+
+            if rm == 4 {
+                let sib = cur.u8()?;
+                scale = 1 << (sib >> 6);
+                let idx = ((sib >> 3) & 7) | ((rex & 0x02) << 2);
+                if idx != 4 {
+                    index = Some(idx);
+                }
+                rm = sib & 7;
+                if rm == 5 && mode == 0 {
+                    disp_len = 4;
+                } else {
+                    base = Some(rm | ((rex & 0x01) << 3));
+                }
+            } else if rm == 5 && mode == 0 {
+                rip_relative = true;
+                disp_len = 4;
+            } else {
+                base = Some(rm | ((rex & 0x01) << 3));
+            }
+
+            let disp = if disp_len > 0 { cur.le(disp_len)? } else { 0 };
+            mem = Some(MemOperand {
+                base,
+                index,
+                scale,
+                disp,
+                rip_relative,
+                segment: prefixes.segment,
+                address_size_32: prefixes.address_size,
+            });
+        }
+    }
+
+    let imm_kind = match map {
+        OpcodeMap::Primary => primary_imm(opcode, modrm),
+        OpcodeMap::Secondary if vex => match opcode {
+            0x70..=0x73 | 0xC2 | 0xC4..=0xC6 => Imm::B,
+            _ => Imm::None,
+        },
+        OpcodeMap::Secondary => secondary_imm(opcode),
+        OpcodeMap::Escape3A => Imm::B,
+        OpcodeMap::Escape38 => Imm::None,
+    };
+
+    let z = if prefixes.operand_size && !(map == OpcodeMap::Primary && matches!(opcode, 0xE8 | 0xE9))
+        && !(map == OpcodeMap::Secondary && matches!(opcode, 0x80..=0x8F))
+    {
+        2
+    } else {
+        4
+    };
+    let imm = match imm_kind {
+        Imm::None => None,
+        Imm::B => Some(cur.le(1)?),
+        Imm::W => Some(cur.le(2)?),
+        Imm::Z => Some(cur.le(z)?),
+        Imm::V if rex & 0x08 != 0 => Some(cur.le(8)?),
+        Imm::V => Some(cur.le(z)?),
+        Imm::Enter => {
+            let frame = cur.le(2)?;
+            cur.le(1)?;
+            Some(frame)
+        }
+        Imm::Moffs => {
+            let addr = cur.le(if prefixes.address_size { 4 } else { 8 })?;
+            mem = Some(MemOperand {
+                base: None,
+                index: None,
+                scale: 1,
+                disp: addr,
+                rip_relative: false,
+                segment: prefixes.segment,
+                address_size_32: prefixes.address_size,
+            });
+            None
+        }
+    };
+
+    if cur.pos > 15 {
+        return None;
+    }
+
+    Some(Instruction {
+        len: cur.pos,
+        prefixes,
+        rex,
+        vex,
+        map,
+        opcode,
+        modrm,
+        mem,
+        imm,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_forms() {
+        // xor rax, rcx
+        let insn = decode(&[0x48, 0x31, 0xC8]).unwrap();
+        assert_eq!(insn.len, 3);
+        assert_eq!(insn.modrm, Some(ModRm { mode: 3, reg: 1, rm: 0 }));
+        assert!(insn.mem.is_none());
+        // mov r64, imm64
+        let insn = decode(&[0x49, 0xBF, 1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        assert_eq!(insn.len, 10);
+        assert_eq!(insn.imm, Some(0x0807060504030201));
+        // jb rel8
+        let insn = decode(&[0x72, 0xF0]).unwrap();
+        assert!(insn.is_conditional_branch());
+        assert_eq!(insn.imm, Some(-16));
+    }
+
+    #[test]
+    fn test_memory_operands() {
+        // movzx eax, byte [rdi + rcx*1]
+        let insn = decode(&[0x0F, 0xB6, 0x04, 0x0F]).unwrap();
+        let mem = insn.mem.unwrap();
+        assert_eq!((mem.base, mem.index, mem.scale), (Some(7), Some(1), 1));
+        let regs = |r: u8| [0, 0x10, 0, 0, 0, 0, 0, 0x1000][r as usize];
+        assert_eq!(mem.effective_address(regs, 0, |_| 0), 0x1010);
+
+        // mov rax, [rip + 0x100]
+        let insn = decode(&[0x48, 0x8B, 0x05, 0x00, 0x01, 0x00, 0x00]).unwrap();
+        assert_eq!(insn.len, 7);
+        let mem = insn.mem.unwrap();
+        assert!(mem.rip_relative);
+        assert_eq!(mem.effective_address(|_| 0, 0x4000, |_| 0), 0x4100);
+
+        // mov rax, fs:[0x28]
+        let insn = decode(&[0x64, 0x48, 0x8B, 0x04, 0x25, 0x28, 0, 0, 0]).unwrap();
+        assert_eq!(insn.len, 9);
+        let mem = insn.mem.unwrap();
+        assert_eq!(mem.effective_address(|_| 0, 0, |_| 0x7000), 0x7028);
+
+        // lea rdx, [rsp + 8] computes but does not access
+        let insn = decode(&[0x48, 0x8D, 0x54, 0x24, 0x08]).unwrap();
+        assert_eq!(insn.len, 5);
+        assert!(!insn.accesses_memory());
+    }
+
+    #[test]
+    fn test_prefixed_and_vex() {
+        // endbr64
+        assert_eq!(decode(&[0xF3, 0x0F, 0x1E, 0xFA]).unwrap().len, 4);
+        // cmp word [rax], 0x1234
+        assert_eq!(decode(&[0x66, 0x81, 0x38, 0x34, 0x12]).unwrap().len, 5);
+        // vmovdqu ymm0, [rsi]
+        let insn = decode(&[0xC5, 0xFE, 0x6F, 0x06]).unwrap();
+        assert!(insn.vex && insn.accesses_memory());
+        assert_eq!(insn.len, 4);
+        // vpcmpeqb ymm1, ymm0, [rdi + 0x20]
+        assert_eq!(decode(&[0xC5, 0xFD, 0x74, 0x4F, 0x20]).unwrap().len, 5);
+        // vpshufb ymm0, ymm0, [r9] (3-byte VEX, 0F38 map)
+        let insn = decode(&[0xC4, 0xC2, 0x7D, 0x00, 0x01]).unwrap();
+        assert_eq!((insn.map, insn.len), (OpcodeMap::Escape38, 5));
+        assert_eq!(insn.mem.unwrap().base, Some(9));
+        // truncated
+        assert!(decode(&[0x48, 0x8B]).is_none());
+    }
+}
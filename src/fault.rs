@@ -1,8 +1,28 @@
-use libc::{mmap, mprotect, PROT_NONE, PROT_READ, PROT_WRITE, MAP_ANONYMOUS, MAP_PRIVATE};
+use libc::{mmap, mprotect, munmap, PROT_NONE, PROT_READ, PROT_WRITE, MAP_ANONYMOUS, MAP_PRIVATE, MAP_FAILED};
+use core::arch::x86_64::{_mm_clflush, _mm_mfence};
+use std::io;
+use std::mem;
 use std::ptr;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+const PAGE_SIZE: usize = 4096;
+const MAX_REGIONS: usize = 16;
+const MAX_OPEN: usize = 4;
+const MAX_FAULTS: usize = 1 << 16;
+// The general-purpose registers and RIP lead `gregs`; everything after
+// (flags, segment selectors, ERR, TRAPNO, CR2) describes the fault itself.
+const INSN_REGS: usize = libc::REG_RIP as usize + 1;
 
 static FAULT_COUNTER: AtomicUsize = AtomicUsize::new(0);
+static GUARD_PAGE: AtomicUsize = AtomicUsize::new(0);
+static RECOVERY: AtomicUsize = AtomicUsize::new(0);
+// Flushed before each suppressed call; reading it holds the faulting load
+// back from retirement.
+static SHADOW_DELAY: AtomicUsize = AtomicUsize::new(0);
+
+// Only one SIGSEGV-driven experiment may own the handler at a time.
+static HANDLER_LOCK: Mutex<()> = Mutex::new(());
 
 pub struct FaultInjector {
     base: *mut u8,
@@ -21,52 +41,528 @@ impl FaultInjector {
                 MAP_ANONYMOUS | MAP_PRIVATE,
                 -1,
                 0
-            ) as *mut u8;
-            
-            if base.is_null() {
+            );
+
+            if base == MAP_FAILED {
                 return None;
             }
-            
+            let base = base as *mut u8;
+
             // Set up guard page
             let guard_addr = base.add(fault_offset);
-            mprotect(
-                guard_addr as *mut libc::c_void,
-                4096,
-                PROT_NONE
-            );
-            
+            if mprotect(guard_addr as *mut libc::c_void, PAGE_SIZE, PROT_NONE) != 0 {
+                munmap(base as *mut libc::c_void, size);
+                return None;
+            }
+
             Some(Self { base, size, fault_offset })
         }
     }
-    
-    pub fn inject_faults(&self, func: unsafe extern fn(*const u8, *const u8, usize) -> i32) -> i32 {
+
+    pub fn inject_faults(&self, func: unsafe extern "C" fn(*const u8, *const u8, usize) -> i32) -> i32 {
+        let _lock = HANDLER_LOCK.lock().unwrap_or_else(|e| e.into_inner());
         unsafe {
-            libc::signal(libc::SIGSEGV, handle_sigsegv as libc::sighandler_t);
-            
+            GUARD_PAGE.store(self.base.add(self.fault_offset) as usize, Ordering::SeqCst);
+            let previous = install_handler(handle_sigsegv);
+
             let result = func(
                 self.base,
                 self.base.add(self.size / 2),
                 self.size / 2
             );
-            
-            libc::signal(libc::SIGSEGV, libc::SIG_DFL);
+
+            libc::sigaction(libc::SIGSEGV, &previous, ptr::null_mut());
+            GUARD_PAGE.store(0, Ordering::SeqCst);
             result
         }
     }
+
+    pub fn fault_count(&self) -> usize {
+        FAULT_COUNTER.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for FaultInjector {
+    fn drop(&mut self) {
+        unsafe {
+            munmap(self.base as *mut libc::c_void, self.size);
+        }
+    }
+}
+
+unsafe fn install_handler(
+    handler: extern "C" fn(i32, *mut libc::siginfo_t, *mut libc::c_void),
+) -> libc::sigaction {
+    let mut action: libc::sigaction = mem::zeroed();
+    action.sa_sigaction = handler as usize;
+    action.sa_flags = libc::SA_SIGINFO | libc::SA_NODEFER;
+    libc::sigemptyset(&mut action.sa_mask);
+
+    let mut previous: libc::sigaction = mem::zeroed();
+    libc::sigaction(libc::SIGSEGV, &action, &mut previous);
+    previous
+}
+
+fn page_of(addr: usize) -> usize {
+    addr & !(PAGE_SIZE - 1)
+}
+
+extern "C" fn handle_sigsegv(_sig: i32, info: *mut libc::siginfo_t, _ctx: *mut libc::c_void) {
+    let fault_addr = unsafe { (*info).si_addr() } as usize;
+    let guard = GUARD_PAGE.load(Ordering::SeqCst);
+
+    unsafe {
+        if guard != 0 && page_of(fault_addr) == guard {
+            // Count the fault and let the access through.
+            FAULT_COUNTER.fetch_add(1, Ordering::SeqCst);
+            mprotect(guard as *mut libc::c_void, PAGE_SIZE, PROT_READ | PROT_WRITE);
+        } else {
+            // Not ours: fall back to the default action on return.
+            libc::signal(libc::SIGSEGV, libc::SIG_DFL);
+        }
+    }
+}
+
+extern "C" fn handle_suppressed(_sig: i32, _info: *mut libc::siginfo_t, ctx: *mut libc::c_void) {
+    let resume = RECOVERY.swap(0, Ordering::SeqCst);
+    if resume == 0 {
+        unsafe { libc::signal(libc::SIGSEGV, libc::SIG_DFL) };
+        return;
+    }
+    let gregs = unsafe { &mut (*(ctx as *mut libc::ucontext_t)).uc_mcontext.gregs };
+    gregs[libc::REG_RIP as usize] = resume as i64;
 }
 
-extern "C" fn handle_sigsegv(_sig: i32) {
-    FAULT_COUNTER.fetch_add(1, Ordering::SeqCst);
-    
-    let ctx = unsafe { *libc::__ucontext_get() };
-    let fault_addr = ctx.uc_mcontext.fault_address as usize;
-    
+/// Runs `f` only transiently, in the shadow of a load from address 0.
+///
+/// The faulting load is queued behind a read of a flushed line, so it cannot
+/// retire and raise SIGSEGV until that read comes back from memory.
+/// Meanwhile the call to `f`, behind a branch on the faulted value,
+/// executes on the predicted path. CPUs without Meltdown forward nothing
+/// from the load, so a data dependency would never issue. The handler resumes past the call,
+/// so nothing `f` does is committed, but lines it loaded stay cached.
+/// Returns whether the fault was taken.
+///
+/// Resuming by rewriting the saved RIP avoids calling `sigsetjmp` from
+/// Rust, which cannot express a function that returns twice.
+pub fn with_fault_suppression(mut f: impl FnMut()) -> bool {
+    extern "C" fn trampoline(f: *mut &mut dyn FnMut()) {
+        unsafe { (*f)() }
+    }
+
+    let _lock = HANDLER_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut f: &mut dyn FnMut() = &mut f;
     unsafe {
-        let mut altstack = std::mem::zeroed();
-        libc::sigaltstack(ptr::null(), &mut altstack);
-        
-        if altstack.ss_flags & libc::SA_ONSTACK != 0 {
-            libc::sigaltstack(ptr::null_mut(), ptr::null_mut());
+        let previous = install_handler(handle_suppressed);
+        _mm_clflush(SHADOW_DELAY.as_ptr() as *const u8);
+        _mm_mfence();
+
+        core::arch::asm!(
+            "lea rax, [rip + 2f]",
+            "mov qword ptr [rsi], rax",
+            "mov rax, qword ptr [rdx]",
+            "cmp byte ptr [rcx], 0",
+            "jne 2f",
+            "call {trampoline}",
+            "2:",
+            trampoline = sym trampoline,
+            in("rsi") RECOVERY.as_ptr(),
+            in("rdx") SHADOW_DELAY.as_ptr(),
+            in("rcx") 0usize,
+            in("rdi") &mut f as *mut &mut dyn FnMut(),
+            clobber_abi("C"),
+        );
+        let taken = RECOVERY.swap(0, Ordering::SeqCst) == 0;
+
+        libc::sigaction(libc::SIGSEGV, &previous, ptr::null_mut());
+        taken
+    }
+}
+
+/// Page-aligned anonymous mapping, so traced data does not share pages with
+/// anything else.
+pub struct PageBuffer {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl PageBuffer {
+    pub fn new(len: usize) -> Option<Self> {
+        let len = (len.max(1) + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        let ptr = unsafe {
+            mmap(ptr::null_mut(), len, PROT_READ | PROT_WRITE, MAP_ANONYMOUS | MAP_PRIVATE, -1, 0)
+        };
+        if ptr == MAP_FAILED {
+            None
+        } else {
+            Some(Self { ptr: ptr as *mut u8, len })
+        }
+    }
+
+    pub fn as_ptr(&self) -> *const u8 {
+        self.ptr
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl Drop for PageBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            munmap(self.ptr as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageFault {
+    pub page: usize,
+    pub rip: usize,
+}
+
+/// Ordered page faults observed during one traced call.
+#[derive(Debug, Clone, Default)]
+pub struct PageTrace {
+    pub faults: Vec<PageFault>,
+    regions: Vec<(usize, usize)>,
+    /// Faults dropped because the log was full.
+    pub overflowed: usize,
+}
+
+impl PageTrace {
+    /// The fault sequence as `(region, page index within region)`, which is
+    /// what should match across secret inputs.
+    pub fn sequence(&self) -> Vec<(usize, usize)> {
+        self.faults
+            .iter()
+            .filter_map(|f| {
+                self.regions
+                    .iter()
+                    .position(|&(start, end)| f.page >= start && f.page < end)
+                    .map(|r| (r, (f.page - self.regions[r].0) / PAGE_SIZE))
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Region {
+    start: usize,
+    end: usize,
+    prot: i32,
+}
+
+static REGIONS: [[AtomicUsize; 3]; MAX_REGIONS] =
+    [const { [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)] }; MAX_REGIONS];
+static REGION_COUNT: AtomicUsize = AtomicUsize::new(0);
+static OPEN: [AtomicUsize; MAX_OPEN] = [const { AtomicUsize::new(0) }; MAX_OPEN];
+static LOG: [[AtomicUsize; 2]; MAX_FAULTS] = [const { [AtomicUsize::new(0), AtomicUsize::new(0)] }; MAX_FAULTS];
+static LOG_LEN: AtomicUsize = AtomicUsize::new(0);
+static LAST_STATE: [AtomicUsize; INSN_REGS] = [const { AtomicUsize::new(0) }; INSN_REGS];
+
+/// Controlled-channel tracer: revokes access to every watched page and logs
+/// the ordered sequence of pages a function touches, re-arming the previous
+/// page after each fault.
+///
+/// An instruction that needs several watched pages at once (a load from one
+/// and a store to another, or an access straddling a page boundary) is
+/// recognised by faulting again with unchanged registers and is given all of
+/// them for that one instruction.
+#[derive(Default)]
+pub struct PageTracer {
+    regions: Vec<Region>,
+}
+
+impl PageTracer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Watches the pages covering `len` bytes at `ptr`, which are normally
+    /// readable and writable.
+    pub fn watch(&mut self, ptr: *const u8, len: usize) -> &mut Self {
+        self.watch_with(ptr, len, PROT_READ | PROT_WRITE)
+    }
+
+    /// Watches read-only data such as a lookup table in `.rodata`; its pages
+    /// are restored to `PROT_READ` afterwards.
+    pub fn watch_readonly(&mut self, ptr: *const u8, len: usize) -> &mut Self {
+        self.watch_with(ptr, len, PROT_READ)
+    }
+
+    fn watch_with(&mut self, ptr: *const u8, len: usize, prot: i32) -> &mut Self {
+        assert!(self.regions.len() < MAX_REGIONS, "too many watched regions");
+        let start = page_of(ptr as usize);
+        let end = page_of(ptr as usize + len.max(1) - 1) + PAGE_SIZE;
+        self.regions.push(Region { start, end, prot });
+        self
+    }
+
+    /// Runs `f` with every watched page revoked and returns its result along
+    /// with the observed fault sequence. `f` must not touch watched pages
+    /// through anything but the code under test. Fails, without running
+    /// `f`, if a watched page cannot be revoked.
+    pub fn trace<R>(&self, f: impl FnOnce() -> R) -> io::Result<(R, PageTrace)> {
+        let _lock = HANDLER_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+        for (slot, region) in REGIONS.iter().zip(&self.regions) {
+            slot[0].store(region.start, Ordering::SeqCst);
+            slot[1].store(region.end, Ordering::SeqCst);
+            slot[2].store(region.prot as usize, Ordering::SeqCst);
+        }
+        REGION_COUNT.store(self.regions.len(), Ordering::SeqCst);
+        LOG_LEN.store(0, Ordering::SeqCst);
+        OPEN.iter().for_each(|p| p.store(0, Ordering::SeqCst));
+
+        let armed = Armed { regions: &self.regions, previous: unsafe { install_handler(handle_page_fault) } };
+        for region in &self.regions {
+            if unsafe { mprotect(region.start as *mut libc::c_void, region.end - region.start, PROT_NONE) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        let result = f();
+        drop(armed);
+
+        let logged = LOG_LEN.load(Ordering::SeqCst);
+        let faults = LOG[..logged.min(MAX_FAULTS)]
+            .iter()
+            .map(|e| PageFault {
+                page: e[0].load(Ordering::SeqCst),
+                rip: e[1].load(Ordering::SeqCst),
+            })
+            .collect();
+        let trace = PageTrace {
+            faults,
+            regions: self.regions.iter().map(|r| (r.start, r.end)).collect(),
+            overflowed: logged.saturating_sub(MAX_FAULTS),
+        };
+        Ok((result, trace))
+    }
+
+    /// Traces `f` once per secret and returns the index of the first secret
+    /// whose page sequence differs from the first one's.
+    pub fn check<T>(&self, secrets: &[T], mut f: impl FnMut(&T)) -> io::Result<Option<usize>> {
+        let mut reference = None;
+        for (i, secret) in secrets.iter().enumerate() {
+            let ((), trace) = self.trace(|| f(secret))?;
+            let seq = trace.sequence();
+            match &reference {
+                None => reference = Some(seq),
+                Some(r) if *r != seq => return Ok(Some(i)),
+                Some(_) => {}
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Restores the watched pages and the previous SIGSEGV handler when a trace
+/// ends, including by a panic in the traced function.
+struct Armed<'a> {
+    regions: &'a [Region],
+    previous: libc::sigaction,
+}
+
+impl Drop for Armed<'_> {
+    fn drop(&mut self) {
+        for region in self.regions {
+            unsafe { mprotect(region.start as *mut libc::c_void, region.end - region.start, region.prot) };
+        }
+        unsafe { libc::sigaction(libc::SIGSEGV, &self.previous, ptr::null_mut()) };
+        REGION_COUNT.store(0, Ordering::SeqCst);
+    }
+}
+
+fn find_region(page: usize) -> Option<i32> {
+    let count = REGION_COUNT.load(Ordering::SeqCst);
+    REGIONS[..count].iter().find_map(|slot| {
+        let start = slot[0].load(Ordering::SeqCst);
+        let end = slot[1].load(Ordering::SeqCst);
+        (page >= start && page < end).then(|| slot[2].load(Ordering::SeqCst) as i32)
+    })
+}
+
+extern "C" fn handle_page_fault(_sig: i32, info: *mut libc::siginfo_t, ctx: *mut libc::c_void) {
+    let page = page_of(unsafe { (*info).si_addr() } as usize);
+    let Some(prot) = find_region(page) else {
+        unsafe { libc::signal(libc::SIGSEGV, libc::SIG_DFL) };
+        return;
+    };
+    let gregs = unsafe { &(*(ctx as *const libc::ucontext_t)).uc_mcontext.gregs };
+
+    // Identical RIP and GPRs to the previous fault mean the same
+    // instruction is being retried and needs another page as well, so the
+    // pages it already has stay open. CR2 and the error code are left out:
+    // they name the faulting page, which is exactly what changes.
+    let n = LOG_LEN.load(Ordering::SeqCst);
+    let insn = &gregs[..INSN_REGS];
+    let same_insn = n > 0
+        && insn.iter().zip(&LAST_STATE).all(|(g, s)| *g as usize == s.load(Ordering::SeqCst));
+    for (g, s) in insn.iter().zip(&LAST_STATE) {
+        s.store(*g as usize, Ordering::SeqCst);
+    }
+
+    if !same_insn {
+        for slot in &OPEN {
+            let open = slot.swap(0, Ordering::SeqCst);
+            if open != 0 {
+                unsafe { mprotect(open as *mut libc::c_void, PAGE_SIZE, PROT_NONE) };
+            }
         }
     }
+    if n < MAX_FAULTS {
+        LOG[n][0].store(page, Ordering::SeqCst);
+        LOG[n][1].store(gregs[libc::REG_RIP as usize] as usize, Ordering::SeqCst);
+    }
+    LOG_LEN.store(n + 1, Ordering::SeqCst);
+
+    if let Some(slot) = OPEN.iter().find(|s| s.load(Ordering::SeqCst) == 0) {
+        slot.store(page, Ordering::SeqCst);
+    } else {
+        let old = OPEN[0].swap(page, Ordering::SeqCst);
+        unsafe { mprotect(old as *mut libc::c_void, PAGE_SIZE, PROT_NONE) };
+    }
+    unsafe { mprotect(page as *mut libc::c_void, PAGE_SIZE, prot) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::flush_reload::MonitorSet;
+
+    const SECRET_PAGES: usize = 4;
+
+    #[inline(never)]
+    fn table_lookup(table: &[u8], secret: u8) -> u8 {
+        unsafe { ptr::read_volatile(&table[secret as usize * PAGE_SIZE / 64]) }
+    }
+
+    #[test]
+    fn test_fault_suppression_keeps_only_cache_effects() {
+        let _timing = crate::cache::timing_lock();
+        let mut buf = PageBuffer::new(PAGE_SIZE).unwrap();
+        buf.as_mut_slice().fill(1);
+        let line = buf.as_ptr();
+        let mut monitor = unsafe { MonitorSet::new(vec![line]) };
+        monitor.calibrate();
+
+        let mut calls = 0;
+        let mut hits = 0;
+        // A neighbour thrashing the shared L3 can wash out a whole run;
+        // recalibrate and try again before judging.
+        for attempt in 0..4 {
+            if attempt > 0 {
+                std::thread::sleep(std::time::Duration::from_millis(100));
+                monitor.calibrate();
+            }
+            hits = 0;
+            for _ in 0..20 {
+                monitor.flush_all();
+                assert!(with_fault_suppression(|| {
+                    calls += 1;
+                    unsafe { ptr::read_volatile(line) };
+                }));
+                hits += monitor.probe_hits()[0] as usize;
+            }
+            if hits >= 10 {
+                break;
+            }
+        }
+        // The closure never ran architecturally, but its load did. The fault
+        // sometimes retires before the shadowed load issues, so this asks
+        // for half the rounds; a flushed line alone reads as a hit in well
+        // under one in twenty.
+        assert_eq!(calls, 0);
+        assert!(hits >= 10, "{}/20 with {:?}", hits, monitor.calibration());
+    }
+
+    #[test]
+    fn test_guard_page_fault_is_counted() {
+        let injector = FaultInjector::new(4 * PAGE_SIZE, PAGE_SIZE).unwrap();
+        let before = injector.fault_count();
+        assert_eq!(injector.inject_faults(crate::ct_memcmp), 0);
+        assert!(injector.fault_count() > before);
+    }
+
+    #[test]
+    fn test_ct_memcmp_page_sequence_is_input_independent() {
+        let mut lhs = PageBuffer::new(2 * PAGE_SIZE).unwrap();
+        let mut rhs = PageBuffer::new(2 * PAGE_SIZE).unwrap();
+        let len = lhs.as_slice().len();
+        let (lp, rp) = (lhs.as_ptr(), rhs.as_ptr());
+
+        let mut tracer = PageTracer::new();
+        tracer.watch(lp, len).watch(rp, len);
+
+        lhs.as_mut_slice().fill(0x11);
+        rhs.as_mut_slice().fill(0x11);
+        let (equal, a) = tracer.trace(|| crate::ct_memcmp(lp, rp, len)).unwrap();
+        rhs.as_mut_slice()[0] = 0x22;
+        let (differs, b) = tracer.trace(|| crate::ct_memcmp(lp, rp, len)).unwrap();
+
+        assert_eq!(equal, 0);
+        assert_ne!(differs, 0);
+        assert_eq!(a.sequence(), b.sequence());
+        // lhs page 0, rhs page 0, ... alternating for every byte.
+        assert_eq!(a.sequence()[..4], [(0, 0), (1, 0), (0, 0), (1, 0)]);
+        assert!(a.sequence().contains(&(1, 1)));
+    }
+
+    #[test]
+    fn test_table_lookup_leaks_page() {
+        let mut table = PageBuffer::new(SECRET_PAGES * PAGE_SIZE).unwrap();
+        table.as_mut_slice().fill(1);
+        let slice = table.as_slice();
+
+        let mut tracer = PageTracer::new();
+        tracer.watch(slice.as_ptr(), slice.len());
+
+        let (_, low) = tracer.trace(|| table_lookup(slice, 3)).unwrap();
+        let (_, high) = tracer.trace(|| table_lookup(slice, 200)).unwrap();
+        assert_eq!(low.sequence(), [(0, 0)]);
+        assert_eq!(high.sequence(), [(0, 3)]);
+
+        assert_eq!(tracer.check(&[1u8, 2, 250], |&s| { table_lookup(slice, s); }).unwrap(), Some(2));
+    }
+
+    #[test]
+    fn test_straddling_load_gets_both_pages() {
+        let mut buf = PageBuffer::new(2 * PAGE_SIZE).unwrap();
+        buf.as_mut_slice().fill(0x5a);
+        let p = buf.as_ptr();
+
+        let mut tracer = PageTracer::new();
+        tracer.watch(p, 2 * PAGE_SIZE);
+
+        // One unaligned load covering the last 4 bytes of page 0 and the first
+        // 4 of page 1.
+        let (v, trace) = tracer.trace(|| unsafe { ptr::read_unaligned(p.add(PAGE_SIZE - 4) as *const u64) }).unwrap();
+        assert_eq!(v, 0x5a5a_5a5a_5a5a_5a5a);
+        assert_eq!(trace.sequence(), [(0, 0), (0, 1)]);
+    }
+
+    #[test]
+    fn test_panicking_trace_restores_pages() {
+        let mut buf = PageBuffer::new(PAGE_SIZE).unwrap();
+        buf.as_mut_slice().fill(0x33);
+        let p = buf.as_ptr();
+
+        let mut tracer = PageTracer::new();
+        tracer.watch(p, PAGE_SIZE);
+
+        let panicked = std::panic::catch_unwind(|| tracer.trace(|| panic!("traced code panicked")));
+        assert!(panicked.is_err());
+        // Without the restore this read would fault with no handler left to
+        // reopen the page.
+        assert_eq!(unsafe { ptr::read_volatile(p) }, 0x33);
+        let ((), trace) = tracer.trace(|| ()).unwrap();
+        assert!(trace.sequence().is_empty());
+    }
 }
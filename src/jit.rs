@@ -1,9 +1,8 @@
-use std::arch::x86_64::{_mm_clflush, _mm_lfence, _mm_mfence};
+use std::arch::x86_64::{_mm_clflush, _mm_mfence};
 use std::mem;
-use libc::{mmap, mprotect, munmap, PROT_READ, PROT_WRITE, PROT_EXEC, MAP_ANONYMOUS, MAP_PRIVATE};
-use rand::Rng;
+use libc::{mmap, mprotect, munmap, PROT_READ, PROT_WRITE, PROT_EXEC, MAP_ANONYMOUS, MAP_PRIVATE, MAP_FAILED};
 
-const PAGE_SIZE: usize = 4096;
+use crate::Comparator;
 
 pub struct JitBuffer {
     ptr: *mut u8,
@@ -20,12 +19,12 @@ impl JitBuffer {
                 MAP_ANONYMOUS | MAP_PRIVATE,
                 -1,
                 0
-            ) as *mut u8;
+            );
             
-            if ptr.is_null() {
+            if ptr == MAP_FAILED {
                 None
             } else {
-                Some(Self { ptr, size })
+                Some(Self { ptr: ptr as *mut u8, size })
             }
         }
     }
@@ -51,6 +50,20 @@ impl JitBuffer {
             _mm_mfence();
         }
     }
+
+    pub fn code(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.size) }
+    }
+
+    /// The buffer as a comparator entry point.
+    ///
+    /// # Safety
+    ///
+    /// The buffer must hold a complete function with the `ct_memcmp` ABI and
+    /// must have been made executable.
+    pub unsafe fn as_comparator(&self) -> Comparator {
+        mem::transmute::<*mut u8, Comparator>(self.ptr)
+    }
 }
 
 impl Drop for JitBuffer {
@@ -61,10 +74,17 @@ impl Drop for JitBuffer {
     }
 }
 
+// Caller-saved registers that are not argument registers under SysV, so the
+// generated code never clobbers `lhs` (rdi), `rhs` (rsi) or `len` (rdx).
+const SCRATCH_REGS: [u8; 6] = [0, 1, 8, 9, 10, 11];
+
+const RDI: u8 = 7;
+const RSI: u8 = 6;
+
 #[derive(Default)]
 struct CodeGenerator {
     buffer: Vec<u8>,
-    reg_map: [u8; 8],
+    reg_map: [u8; 4],
 }
 
 impl CodeGenerator {
@@ -75,55 +95,105 @@ impl CodeGenerator {
     }
     
     fn randomize_registers(&mut self) {
-        use rand::Rng;
+        use rand::seq::SliceRandom;
         let mut rng = rand::thread_rng();
 
-        let mut regs = [0u8, 1u8, 2u8, 3u8];
-        rng.shuffle(&mut regs);
-        self.reg_map = regs;
+        let mut regs = SCRATCH_REGS;
+        regs.shuffle(&mut rng);
+        self.reg_map.copy_from_slice(&regs[..4]);
+    }
+
+    fn emit_rex(&mut self, w: bool, reg: u8, index: u8, rm: u8) {
+        let rex = 0x40 | (w as u8) << 3 | (reg >> 3) << 2 | (index >> 3) << 1 | (rm >> 3);
+        if rex != 0x40 {
+            self.buffer.push(rex);
+        }
+    }
+
+    fn emit_reg_reg(&mut self, opcode: u8, reg: u8, rm: u8) {
+        self.emit_rex(true, reg, 0, rm);
+        self.buffer.extend_from_slice(&[opcode, 0xC0 | (reg & 7) << 3 | (rm & 7)]);
     }
     
     fn emit_mov(&mut self, dst: u8, src: u8) {
-        self.buffer.extend_from_slice(&[0x48, 0x8B, 0xC0 | (dst << 3) | src]);
+        self.emit_reg_reg(0x8B, dst, src);
     }
     
     fn emit_xor(&mut self, dst: u8, src: u8) {
-        self.buffer.extend_from_slice(&[0x48, 0x31, 0xC0 | (dst << 3) | src]);
+        self.emit_reg_reg(0x31, src, dst);
     }
     
     fn emit_or(&mut self, dst: u8, src: u8) {
-        self.buffer.extend_from_slice(&[0x48, 0x09, 0xC0 | (dst << 3) | src]);
+        self.emit_reg_reg(0x09, src, dst);
     }
-    
-    fn generate_ct_memcmp(&mut self, size: usize) {
-        self.emit_mov(self.reg_map[0], 0); 
-        self.emit_mov(self.reg_map[1], 1); 
-        
-        let loop_start = self.buffer.len();
-        
-        self.emit_mov(self.reg_map[2], 0); // lhs
-        self.emit_mov(self.reg_map[3], 1); // rhs
-        
-        self.emit_xor(self.reg_map[2], self.reg_map[3]);
-        self.emit_or(self.reg_map[1], self.reg_map[2]);
-    
-        self.emit_mov(self.reg_map[0], self.reg_map[0] + 1);
-        
+
+    /// movzx dst, byte [base + index]
+    fn emit_load_byte(&mut self, dst: u8, base: u8, index: u8) {
+        self.emit_rex(false, dst, index, base);
+        // rbp/r13 as base cannot use mod=00, so always emit a zero disp8.
         self.buffer.extend_from_slice(&[
-            0x48, 0x81, 0xF8,
-            (size as u32).to_le_bytes()[0],
-            (size as u32).to_le_bytes()[1],
-            (size as u32).to_le_bytes()[2],
-            (size as u32).to_le_bytes()[3],
-            0x72, 
-            (loop_start as i8 - self.buffer.len() as i8 - 1) as u8
+            0x0F, 0xB6,
+            0x44 | (dst & 7) << 3,
+            (index & 7) << 3 | (base & 7),
+            0x00,
         ]);
-        
+    }
+
+    fn emit_add_imm8(&mut self, dst: u8, imm: i8) {
+        self.emit_rex(true, 0, 0, dst);
+        self.buffer.extend_from_slice(&[0x83, 0xC0 | (dst & 7), imm as u8]);
+    }
+
+    /// `cmp r64, imm32`; the immediate is sign-extended to 64 bits.
+    fn emit_cmp_imm32(&mut self, dst: u8, imm: i32) {
+        self.emit_rex(true, 0, 0, dst);
+        self.buffer.extend_from_slice(&[0x81, 0xF8 | (dst & 7)]);
+        self.buffer.extend_from_slice(&imm.to_le_bytes());
+    }
+
+    fn emit_jb(&mut self, target: usize) {
+        let short = target as isize - (self.buffer.len() + 2) as isize;
+        if short >= i8::MIN as isize {
+            self.buffer.extend_from_slice(&[0x72, short as i8 as u8]);
+        } else {
+            let near = target as isize - (self.buffer.len() + 6) as isize;
+            self.buffer.extend_from_slice(&[0x0F, 0x82]);
+            self.buffer.extend_from_slice(&(near as i32).to_le_bytes());
+        }
+    }
+    
+    fn generate_ct_memcmp(&mut self, size: usize) {
+        let [idx, acc, lhs, rhs] = self.reg_map;
+
+        self.emit_xor(idx, idx);
+        self.emit_xor(acc, acc);
+
+        if size > 0 {
+            let loop_start = self.buffer.len();
+
+            self.emit_load_byte(lhs, RDI, idx);
+            self.emit_load_byte(rhs, RSI, idx);
+
+            self.emit_xor(lhs, rhs);
+            self.emit_or(acc, lhs);
+
+            self.emit_add_imm8(idx, 1);
+            self.emit_cmp_imm32(idx, size as i32);
+            self.emit_jb(loop_start);
+        }
+
+        self.emit_mov(0, acc);
         self.buffer.push(0xC3); 
     }
 }
 
+/// Compiles a comparator for exactly `size` bytes.
+///
+/// # Panics
+///
+/// If `size` does not fit the sign-extended 32-bit loop bound.
 pub fn compile_ct_memcmp(size: usize) -> JitBuffer {
+    assert!(size <= i32::MAX as usize, "comparator size {} exceeds the 32-bit loop bound", size);
     let mut gen = CodeGenerator::new();
     gen.generate_ct_memcmp(size);
     
@@ -134,28 +204,54 @@ pub fn compile_ct_memcmp(size: usize) -> JitBuffer {
     jit
 }
 
-#[cfg(target_arch = "aarch64")]
-mod mte {
+/// `compile_ct_memcmp` for tests: every comparator compiled in the test
+/// suite goes through the taint emulator and must come out data-oblivious.
+#[cfg(test)]
+pub(crate) fn compile_checked(size: usize) -> JitBuffer {
+    let jit = compile_ct_memcmp(size);
+    let lhs = vec![0x5A; size];
+    let rhs = vec![0xA5; size];
+    let report = crate::taint::analyze_comparator(jit.code(), &lhs, &rhs).unwrap();
+    assert!(report.violations.is_empty(), "{}: {:x?}", size, report.violations);
+    jit
+}
+
+#[cfg(test)]
+mod tests {
     use super::*;
-    
-    pub fn generate_mte_memcmp(size: usize) -> JitBuffer {
-        let mut gen = CodeGenerator::new();
-        
-        gen.buffer.extend_from_slice(&[
-            0xE8, 0x07, 0x00, 0x58,
-            0xE8, 0x03, 0x00, 0x91, 
-            0x09, 0x01, 0x40, 0xF9, 
-        ]);
-        
-        gen.generate_ct_memcmp(size);
-        
-        let mut jit = JitBuffer::new(gen.buffer.len()).unwrap();
-        jit.write_instructions(0, &gen.buffer);
-        jit.make_executable();
-        
-        jit
+
+    #[test]
+    fn test_compiled_comparator_matches_ct_memcmp() {
+        for size in [1, 16, 200] {
+            let jit = compile_checked(size);
+            let cmp = unsafe { jit.as_comparator() };
+            let a = vec![0x3Cu8; size];
+            let mut b = a.clone();
+            assert_eq!(unsafe { cmp(a.as_ptr(), b.as_ptr(), size) }, 0);
+            b[size - 1] ^= 0x81;
+            let expected = crate::ct_memcmp(a.as_ptr(), b.as_ptr(), size);
+            assert_eq!(unsafe { cmp(a.as_ptr(), b.as_ptr(), size) }, expected);
+        }
     }
-}
 
-#[cfg(target_arch = "aarch64")]
-pub use mte::generate_mte_memcmp;
+    #[test]
+    fn test_compiled_comparator_is_data_oblivious() {
+        for size in [0, 1, 16, 200] {
+            let jit = compile_checked(size);
+            let lhs = vec![7u8; size];
+            let mut rhs = lhs.clone();
+            if let Some(last) = rhs.last_mut() {
+                *last = 6;
+            }
+            let report = crate::taint::analyze_comparator(jit.code(), &lhs, &rhs).unwrap();
+            assert_eq!(report.result, (size > 0) as u64);
+            assert_eq!(report.result_taint != 0, size > 0);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "32-bit loop bound")]
+    fn test_rejects_size_past_imm32() {
+        compile_ct_memcmp(i32::MAX as usize + 1);
+    }
+}
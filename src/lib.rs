@@ -1,8 +1,11 @@
+pub type Comparator = unsafe extern "C" fn(*const u8, *const u8, usize) -> i32;
+
 #[repr(align(64))]
-pub struct AlignedBuffer([u8; 64]);
+pub struct AlignedBuffer(pub [u8; 64]);
 
 #[inline(never)]
 #[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub extern "C" fn ct_memcmp(lhs: *const u8, rhs: *const u8, len: usize) -> i32 {
     let mut acc: u8 = 0;
     for i in 0..len {
@@ -15,10 +18,84 @@ pub extern "C" fn ct_memcmp(lhs: *const u8, rhs: *const u8, len: usize) -> i32 {
     acc as i32
 }
 
-#[cfg(target_arch = "x86_64")]
+/// `ct_memcmp` with every derived pointer clamped by
+/// `arch::index_nospec`, so a mispredicted loop bound cannot read past
+/// `len` even transiently.
+#[inline(never)]
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn ct_memcmp_nospec(lhs: *const u8, rhs: *const u8, len: usize) -> i32 {
+    let mut acc: u8 = 0;
+    for i in 0..len {
+        let i = arch::index_nospec(i, len);
+        unsafe {
+            let l = core::ptr::read_volatile(lhs.add(i));
+            let r = core::ptr::read_volatile(rhs.add(i));
+            acc |= l ^ r;
+        }
+    }
+    acc as i32
+}
+
+pub mod arch;
+
+pub mod ffi;
+
+pub mod pac;
+
+pub mod mte;
+
+pub mod masked;
+
+pub use crate::masked::{ct_eq_masked, MaskedSecret};
+
+pub mod shuffle;
+
+pub use crate::shuffle::ct_memcmp_shuffled;
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
 #[path = "tsx_memcmp.rs"]
 pub mod tsx_memcmp;
 
+pub mod decode;
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+pub mod fault;
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+pub mod ptrace;
+
+#[cfg(target_os = "linux")]
+pub mod symbolize;
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+pub mod trace;
+
+#[cfg(target_arch = "x86_64")]
+pub mod jit;
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+pub mod cache;
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+pub mod spectre;
+
+#[cfg(target_os = "linux")]
+pub mod perf;
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+pub mod tsc;
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+pub mod timer;
+
+#[cfg(all(test, target_arch = "x86_64", target_os = "linux"))]
+mod tests;
+
+pub mod taint;
+
+pub mod power;
+
 pub mod sandbox {
     pub fn sandbox() {
         println!("This is a sandbox function for testing purposes.");
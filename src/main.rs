@@ -1,5 +1,3 @@
-use memcopy::sandbox::sandbox;
-
 fn main() {
     println!("Running the memcopy project.");
     memcopy::sandbox();
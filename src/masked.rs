@@ -0,0 +1,143 @@
+//! First-order Boolean-masked secret storage and comparison.
+//!
+//! A secret `s` is kept as two shares `s ^ m` and `m`. Comparison never
+//! recombines them: each byte's difference is only formed after being
+//! multiplied in GF(2^8) by a fresh random nonzero factor, which preserves
+//! "zero vs nonzero" and nothing else about the secret byte.
+
+use core::hint::black_box;
+use rand::{Rng, RngCore};
+
+/// A secret stored in two Boolean shares.
+pub struct MaskedSecret {
+    masked: Vec<u8>,
+    mask: Vec<u8>,
+}
+
+impl MaskedSecret {
+    pub fn new(secret: &[u8]) -> Self {
+        Self::with_rng(secret, &mut rand::thread_rng())
+    }
+
+    pub fn with_rng(secret: &[u8], rng: &mut impl RngCore) -> Self {
+        let mut mask = vec![0u8; secret.len()];
+        rng.fill_bytes(&mut mask);
+        let masked = secret.iter().zip(&mask).map(|(s, m)| s ^ m).collect();
+        Self { masked, mask }
+    }
+
+    pub fn len(&self) -> usize {
+        self.mask.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mask.is_empty()
+    }
+
+    /// Re-randomizes both shares without changing the secret.
+    pub fn remask(&mut self) {
+        self.remask_with(&mut rand::thread_rng());
+    }
+
+    pub fn remask_with(&mut self, rng: &mut impl RngCore) {
+        for (s, m) in self.masked.iter_mut().zip(self.mask.iter_mut()) {
+            let r: u8 = rng.gen();
+            *s ^= r;
+            *m ^= r;
+        }
+    }
+
+    pub fn shares(&self) -> (&[u8], &[u8]) {
+        (&self.masked, &self.mask)
+    }
+
+    /// Recombines the shares. This is the one place the secret exists in the
+    /// clear, so keep it out of comparison paths.
+    pub fn unmask(&self) -> Vec<u8> {
+        self.masked.iter().zip(&self.mask).map(|(s, m)| s ^ m).collect()
+    }
+}
+
+impl Drop for MaskedSecret {
+    fn drop(&mut self) {
+        for b in self.masked.iter_mut().chain(self.mask.iter_mut()) {
+            unsafe { core::ptr::write_volatile(b, 0) };
+        }
+    }
+}
+
+/// Branchless GF(2^8) multiplication modulo the AES polynomial.
+#[inline(never)]
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut p = 0u8;
+    for _ in 0..8 {
+        p ^= a & 0u8.wrapping_sub(b & 1);
+        let carry = 0u8.wrapping_sub(a >> 7);
+        a = (a << 1) ^ (carry & 0x1B);
+        b >>= 1;
+    }
+    p
+}
+
+/// Compares `input` against a masked secret. The masks are refreshed first,
+/// so no two calls operate on the same shares.
+pub fn ct_eq_masked(secret: &mut MaskedSecret, input: &[u8]) -> bool {
+    ct_eq_masked_with(secret, input, &mut rand::thread_rng())
+}
+
+/// `ct_eq_masked` with an explicit RNG for masks and blinding factors.
+pub fn ct_eq_masked_with(secret: &mut MaskedSecret, input: &[u8], rng: &mut impl RngCore) -> bool {
+    secret.remask_with(rng);
+    if secret.len() != input.len() {
+        return false;
+    }
+
+    let mut acc = 0u8;
+    let shares = secret.masked.iter().zip(&secret.mask);
+    for ((&masked, &mask), &x) in shares.zip(input) {
+        let rho: u8 = rng.gen_range(1..=255);
+        // (s ^ m ^ x) * rho and m * rho are each uniformly random; their XOR
+        // is (s ^ x) * rho, which is zero iff the bytes match and otherwise
+        // uniform over the nonzero values.
+        let lhs = black_box(masked ^ x);
+        let a = black_box(gf_mul(lhs, rho));
+        let b = black_box(gf_mul(mask, rho));
+        acc |= a ^ b;
+    }
+    black_box(acc) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_gf_mul() {
+        assert_eq!(gf_mul(0x57, 0x83), 0xC1);
+        assert_eq!(gf_mul(0x57, 0x13), 0xFE);
+        assert_eq!(gf_mul(0, 0x13), 0);
+    }
+
+    #[test]
+    fn test_masked_compare() {
+        let mut secret = MaskedSecret::new(b"hunter22");
+        assert!(ct_eq_masked(&mut secret, b"hunter22"));
+        assert!(!ct_eq_masked(&mut secret, b"hunter23"));
+        assert!(!ct_eq_masked(&mut secret, b"Hunter22"));
+        assert!(!ct_eq_masked(&mut secret, b"hunter2"));
+        assert_eq!(secret.unmask(), b"hunter22");
+    }
+
+    #[test]
+    fn test_masks_refresh_every_call() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let mut secret = MaskedSecret::with_rng(&[0xAA; 32], &mut rng);
+        let before = secret.shares().1.to_vec();
+        assert!(ct_eq_masked_with(&mut secret, &[0xAA; 32], &mut rng));
+        assert_ne!(secret.shares().1, &before[..]);
+        assert_ne!(secret.shares().0, &[0xAA; 32][..]);
+        assert_eq!(secret.unmask(), [0xAA; 32]);
+    }
+}
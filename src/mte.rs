@@ -0,0 +1,234 @@
+//! Software emulation of ARM memory tagging for x86 test runs.
+//!
+//! `TaggedHeap` hands out 16-byte granules from an arena, gives each
+//! allocation a random 4-bit tag kept in shadow memory and returns pointers
+//! with that tag in the top byte. Such pointers are not dereferenceable on
+//! x86; every access goes through `check`, which compares the pointer tag
+//! with the shadow tag of each granule touched and strips the tag.
+
+use core::fmt;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+
+use crate::Comparator;
+
+pub const GRANULE: usize = 16;
+const TAG_SHIFT: u32 = 56;
+const ADDR_MASK: u64 = (1 << TAG_SHIFT) - 1;
+/// Shadow value of granules that are not part of a live allocation.
+const UNTAGGED: u8 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TagFault {
+    /// First granule whose memory tag differs from the pointer tag.
+    Mismatch { addr: u64, expected: u8, actual: u8 },
+    /// The access leaves the heap arena entirely.
+    Wild { addr: u64 },
+}
+
+impl fmt::Display for TagFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TagFault::Mismatch { addr, expected, actual } => write!(
+                f,
+                "tag mismatch at {:#x}: pointer tag {:#x}, memory tag {:#x}",
+                addr, expected, actual
+            ),
+            TagFault::Wild { addr } => write!(f, "access at {:#x} is outside the tagged heap", addr),
+        }
+    }
+}
+
+impl std::error::Error for TagFault {}
+
+pub fn pointer_tag(ptr: u64) -> u8 {
+    (ptr >> TAG_SHIFT) as u8 & 0x0F
+}
+
+pub fn strip_tag(ptr: u64) -> u64 {
+    ptr & ADDR_MASK
+}
+
+pub struct TaggedHeap {
+    arena: Box<[u128]>,
+    shadow: Vec<u8>,
+    live: HashMap<usize, usize>,
+    next: usize,
+    rng: StdRng,
+}
+
+impl TaggedHeap {
+    pub fn new(capacity: usize) -> Self {
+        Self::with_rng(capacity, StdRng::from_entropy())
+    }
+
+    pub fn with_seed(capacity: usize, seed: u64) -> Self {
+        Self::with_rng(capacity, StdRng::seed_from_u64(seed))
+    }
+
+    fn with_rng(capacity: usize, rng: StdRng) -> Self {
+        let granules = capacity.div_ceil(GRANULE);
+        Self {
+            arena: vec![0u128; granules].into_boxed_slice(),
+            shadow: vec![UNTAGGED; granules],
+            live: HashMap::new(),
+            next: 0,
+            rng,
+        }
+    }
+
+    fn base(&self) -> u64 {
+        self.arena.as_ptr() as u64
+    }
+
+    pub fn memory_tag(&self, addr: u64) -> Option<u8> {
+        let offset = strip_tag(addr).checked_sub(self.base())? as usize;
+        self.shadow.get(offset / GRANULE).copied()
+    }
+
+    /// Allocates `len` bytes rounded up to whole granules and returns the
+    /// tagged address. The tag differs from both neighbouring granules, so
+    /// a linear overflow into the next allocation is always caught.
+    pub fn alloc(&mut self, len: usize) -> Option<u64> {
+        let granules = len.max(1).div_ceil(GRANULE);
+        let first = self.next;
+        if first + granules > self.shadow.len() {
+            return None;
+        }
+        let left = first.checked_sub(1).map_or(UNTAGGED, |g| self.shadow[g]);
+        let right = self.shadow.get(first + granules).copied().unwrap_or(UNTAGGED);
+        let tag = loop {
+            let t = self.rng.gen_range(1..16u8);
+            if t != left && t != right {
+                break t;
+            }
+        };
+        self.shadow[first..first + granules].fill(tag);
+        self.live.insert(first, granules);
+        self.next = first + granules;
+        Some((self.base() + (first * GRANULE) as u64) | ((tag as u64) << TAG_SHIFT))
+    }
+
+    /// Retags the allocation as unallocated, so later use of the stale
+    /// pointer reports a mismatch. Returns false for unknown pointers.
+    pub fn free(&mut self, ptr: u64) -> bool {
+        if self.check(ptr, 1).is_err() {
+            return false;
+        }
+        let first = (strip_tag(ptr) - self.base()) as usize / GRANULE;
+        match self.live.remove(&first) {
+            Some(granules) => {
+                self.shadow[first..first + granules].fill(UNTAGGED);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Verifies every granule of `[ptr, ptr + len)` and returns the
+    /// untagged address on success. The pointer is derived from a shared
+    /// borrow of the arena, so it is only good for reads; `write` goes
+    /// through the arena mutably.
+    pub fn check(&self, ptr: u64, len: usize) -> Result<*const u8, TagFault> {
+        let offset = self.checked_offset(ptr, len)?;
+        Ok(unsafe { self.arena.as_ptr().cast::<u8>().add(offset) })
+    }
+
+    /// Byte offset of `ptr` into the arena once its tag has been verified
+    /// over `len` bytes.
+    fn checked_offset(&self, ptr: u64, len: usize) -> Result<usize, TagFault> {
+        let addr = strip_tag(ptr);
+        let expected = pointer_tag(ptr);
+        let start = addr.wrapping_sub(self.base()) as usize;
+        let end = start.checked_add(len).ok_or(TagFault::Wild { addr })?;
+        if addr < self.base() || end > self.shadow.len() * GRANULE {
+            return Err(TagFault::Wild { addr });
+        }
+        for g in start / GRANULE..end.div_ceil(GRANULE) {
+            let actual = self.shadow[g];
+            if actual != expected {
+                let at = (g * GRANULE).max(start);
+                return Err(TagFault::Mismatch {
+                    addr: self.base() + at as u64,
+                    expected,
+                    actual,
+                });
+            }
+        }
+        Ok(start)
+    }
+
+    pub fn read(&self, ptr: u64, buf: &mut [u8]) -> Result<(), TagFault> {
+        let src = self.check(ptr, buf.len())?;
+        unsafe { core::ptr::copy_nonoverlapping(src, buf.as_mut_ptr(), buf.len()) };
+        Ok(())
+    }
+
+    pub fn write(&mut self, ptr: u64, data: &[u8]) -> Result<(), TagFault> {
+        let offset = self.checked_offset(ptr, data.len())?;
+        let dst = unsafe { self.arena.as_mut_ptr().cast::<u8>().add(offset) };
+        unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), dst, data.len()) };
+        Ok(())
+    }
+
+    /// Runs `cmp` on two tagged buffers after checking both ranges.
+    ///
+    /// # Safety
+    ///
+    /// `cmp` must not read more than `len` bytes from either side.
+    pub unsafe fn compare(&self, cmp: Comparator, lhs: u64, rhs: u64, len: usize) -> Result<i32, TagFault> {
+        let l = self.check(lhs, len)?;
+        let r = self.check(rhs, len)?;
+        Ok(cmp(l, r, len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checked_compare() {
+        let mut heap = TaggedHeap::with_seed(256, 1);
+        let a = heap.alloc(32).unwrap();
+        let b = heap.alloc(32).unwrap();
+        assert_ne!(pointer_tag(a), pointer_tag(b));
+        heap.write(a, &[7; 32]).unwrap();
+        heap.write(b, &[7; 32]).unwrap();
+        assert_eq!(unsafe { heap.compare(crate::ct_memcmp, a, b, 32) }, Ok(0));
+    }
+
+    #[test]
+    fn test_overflow_reports_first_mismatch() {
+        let mut heap = TaggedHeap::with_seed(256, 2);
+        let a = heap.alloc(20).unwrap();
+        let b = heap.alloc(16).unwrap();
+        // 20 bytes round up to two granules; byte 32 belongs to `b`.
+        assert!(heap.check(a, 32).is_ok());
+        let fault = unsafe { heap.compare(crate::ct_memcmp, a, b, 33) }.unwrap_err();
+        assert_eq!(
+            fault,
+            TagFault::Mismatch {
+                addr: strip_tag(a) + 32,
+                expected: pointer_tag(a),
+                actual: pointer_tag(b),
+            }
+        );
+        assert!(matches!(heap.check(a, 4096), Err(TagFault::Wild { .. })));
+    }
+
+    #[test]
+    fn test_use_after_free() {
+        let mut heap = TaggedHeap::with_seed(64, 3);
+        let a = heap.alloc(16).unwrap();
+        assert!(heap.free(a));
+        assert!(!heap.free(a));
+        let mut buf = [0u8; 4];
+        let fault = heap.read(a + 4, &mut buf).unwrap_err();
+        assert_eq!(
+            fault,
+            TagFault::Mismatch { addr: strip_tag(a) + 4, expected: pointer_tag(a), actual: UNTAGGED }
+        );
+    }
+}
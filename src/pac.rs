@@ -0,0 +1,274 @@
+//! Software pointer authentication.
+//!
+//! User-space x86-64 pointers only use the low 48 bits; `arch::sanitize_data`
+//! already strips the rest. Here the upper 16 bits carry a keyed MAC
+//! (SipHash-2-4 over address and context) so a corrupted comparator or
+//! table pointer is caught before it is used. `TaggedPtr` splits the same
+//! bits into an 8-bit type tag and an 8-bit MAC.
+
+use core::fmt;
+use core::marker::PhantomData;
+use rand::RngCore;
+
+use crate::arch::sanitize_data;
+use crate::Comparator;
+
+const ADDR_BITS: u32 = 48;
+
+/// Any bit pattern here makes the pointer non-canonical, so dereferencing a
+/// poisoned pointer faults instead of reaching attacker-chosen memory.
+const POISON: u64 = 0x2000 << ADDR_BITS;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacError {
+    BadMac,
+    BadTag,
+}
+
+impl fmt::Display for PacError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PacError::BadMac => write!(f, "pointer authentication failed"),
+            PacError::BadTag => write!(f, "pointer type tag mismatch"),
+        }
+    }
+}
+
+impl std::error::Error for PacError {}
+
+#[inline(always)]
+fn sip_round(v: &mut [u64; 4]) {
+    v[0] = v[0].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(13) ^ v[0];
+    v[0] = v[0].rotate_left(32);
+    v[2] = v[2].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(16) ^ v[2];
+    v[0] = v[0].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(21) ^ v[0];
+    v[2] = v[2].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(17) ^ v[2];
+    v[2] = v[2].rotate_left(32);
+}
+
+/// All-ones if `a == b`, zero otherwise.
+#[inline(always)]
+fn ct_eq_mask(a: u64, b: u64) -> u64 {
+    let x = a ^ b;
+    ((x | x.wrapping_neg()) >> 63).wrapping_sub(1)
+}
+
+#[derive(Clone)]
+pub struct PacKey {
+    k0: u64,
+    k1: u64,
+}
+
+impl PacKey {
+    pub fn new(k0: u64, k1: u64) -> Self {
+        Self { k0, k1 }
+    }
+
+    pub fn random() -> Self {
+        let mut rng = rand::thread_rng();
+        Self::new(rng.next_u64(), rng.next_u64())
+    }
+
+    /// SipHash-2-4 of the 16-byte message `addr || context`.
+    fn mac(&self, addr: u64, context: u64) -> u64 {
+        self.mac_words(&[addr, context])
+    }
+
+    /// SipHash-2-4 over `words`, each absorbed as its own message block, so
+    /// no two fields can be traded against each other.
+    fn mac_words(&self, words: &[u64]) -> u64 {
+        let mut v = [
+            self.k0 ^ 0x736f_6d65_7073_6575,
+            self.k1 ^ 0x646f_7261_6e64_6f6d,
+            self.k0 ^ 0x6c79_6765_6e65_7261,
+            self.k1 ^ 0x7465_6462_7974_6573,
+        ];
+        let length = ((words.len() * 8) as u64) << 56;
+        for &m in words.iter().chain([&length]) {
+            v[3] ^= m;
+            sip_round(&mut v);
+            sip_round(&mut v);
+            v[0] ^= m;
+        }
+        v[2] ^= 0xFF;
+        for _ in 0..4 {
+            sip_round(&mut v);
+        }
+        v[0] ^ v[1] ^ v[2] ^ v[3]
+    }
+
+    /// Puts a 16-bit MAC of the low 48 bits of `ptr` and `context` into the
+    /// upper 16 bits. Whatever `ptr` had there is discarded.
+    pub fn sign(&self, ptr: u64, context: u64) -> u64 {
+        let addr = sanitize_data(ptr);
+        addr | (self.mac(addr, context) << ADDR_BITS)
+    }
+
+    /// Strips the MAC, or returns the pointer with `POISON` set if it does
+    /// not verify. Neither path branches on the MAC.
+    pub fn authenticate_or_poison(&self, signed: u64, context: u64) -> u64 {
+        let addr = sanitize_data(signed);
+        let ok = ct_eq_mask(signed >> ADDR_BITS, self.mac(addr, context) & 0xFFFF);
+        addr | (POISON & !ok)
+    }
+
+    pub fn authenticate(&self, signed: u64, context: u64) -> Result<u64, PacError> {
+        let ptr = self.authenticate_or_poison(signed, context);
+        if ptr & POISON != 0 {
+            return Err(PacError::BadMac);
+        }
+        Ok(ptr)
+    }
+
+    pub fn sign_ptr<T>(&self, ptr: *const T, context: u64) -> SignedPtr<T> {
+        SignedPtr {
+            raw: self.sign(ptr as u64, context),
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn sign_comparator(&self, f: Comparator, context: u64) -> SignedPtr<()> {
+        self.sign_ptr(f as *const (), context)
+    }
+
+    /// # Safety
+    ///
+    /// `signed` must have been produced by `sign_comparator` with this key
+    /// and context; a valid MAC is only as good as what was signed.
+    pub unsafe fn authenticate_comparator(&self, signed: SignedPtr<()>, context: u64) -> Result<Comparator, PacError> {
+        let ptr = signed.authenticate(self, context)?;
+        Ok(core::mem::transmute::<*const (), Comparator>(ptr))
+    }
+}
+
+/// A pointer whose upper 16 bits hold a MAC. It cannot be dereferenced
+/// without going through `authenticate`.
+pub struct SignedPtr<T> {
+    raw: u64,
+    _marker: PhantomData<*const T>,
+}
+
+impl<T> SignedPtr<T> {
+    pub fn raw(&self) -> u64 {
+        self.raw
+    }
+
+    pub fn from_raw(raw: u64) -> Self {
+        Self { raw, _marker: PhantomData }
+    }
+
+    pub fn authenticate(&self, key: &PacKey, context: u64) -> Result<*const T, PacError> {
+        key.authenticate(self.raw, context).map(|p| p as *const T)
+    }
+}
+
+impl<T> Clone for SignedPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for SignedPtr<T> {}
+
+/// A pointer carrying an 8-bit type tag in bits 56..64 and an 8-bit MAC
+/// over address, context and tag in bits 48..56.
+pub struct TaggedPtr<T> {
+    raw: u64,
+    _marker: PhantomData<*const T>,
+}
+
+impl<T> TaggedPtr<T> {
+    pub fn new(key: &PacKey, ptr: *const T, tag: u8, context: u64) -> Self {
+        let addr = sanitize_data(ptr as u64);
+        let mac = key.mac_words(&[addr, context, tag as u64]) & 0xFF;
+        Self {
+            raw: addr | (mac << ADDR_BITS) | ((tag as u64) << 56),
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn tag(&self) -> u8 {
+        (self.raw >> 56) as u8
+    }
+
+    pub fn raw(&self) -> u64 {
+        self.raw
+    }
+
+    pub fn from_raw(raw: u64) -> Self {
+        Self { raw, _marker: PhantomData }
+    }
+
+    /// Checks the tag against `expected` and the MAC against the key, both
+    /// without early exit, and returns the stripped pointer.
+    pub fn get(&self, key: &PacKey, expected: u8, context: u64) -> Result<*const T, PacError> {
+        let addr = sanitize_data(self.raw);
+        let mac = key.mac_words(&[addr, context, self.tag() as u64]) & 0xFF;
+        let tag_ok = ct_eq_mask(self.tag() as u64, expected as u64);
+        let mac_ok = ct_eq_mask((self.raw >> ADDR_BITS) & 0xFF, mac);
+        match (tag_ok != 0, mac_ok != 0) {
+            (true, true) => Ok(addr as *const T),
+            (false, _) => Err(PacError::BadTag),
+            (true, false) => Err(PacError::BadMac),
+        }
+    }
+}
+
+impl<T> Clone for TaggedPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for TaggedPtr<T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_and_authenticate() {
+        let key = PacKey::new(1, 2);
+        let value = 42u64;
+        let signed = key.sign_ptr(&value, 7);
+        assert_ne!(signed.raw() >> 48, 0);
+        assert_eq!(signed.authenticate(&key, 7), Ok(&value as *const u64));
+        assert_eq!(signed.authenticate(&key, 8), Err(PacError::BadMac));
+        assert_eq!(signed.authenticate(&PacKey::new(1, 3), 7), Err(PacError::BadMac));
+
+        let forged = SignedPtr::<u64>::from_raw(signed.raw() ^ 0x40);
+        assert_eq!(forged.authenticate(&key, 7), Err(PacError::BadMac));
+        let poisoned = key.authenticate_or_poison(forged.raw(), 7);
+        assert_eq!(poisoned, sanitize_data(forged.raw()) | POISON);
+    }
+
+    #[test]
+    fn test_signed_comparator() {
+        let key = PacKey::new(0x0123_4567, 0x89AB_CDEF);
+        let signed = key.sign_comparator(crate::ct_memcmp, 0xC0);
+        let cmp = unsafe { key.authenticate_comparator(signed, 0xC0) }.unwrap();
+        assert_eq!(unsafe { cmp(b"ab".as_ptr(), b"ab".as_ptr(), 2) }, 0);
+        assert!(unsafe { key.authenticate_comparator(signed, 0xC1) }.is_err());
+    }
+
+    #[test]
+    fn test_tagged_ptr() {
+        let key = PacKey::new(5, 6);
+        let table = [0u8; 256];
+        let tagged = TaggedPtr::new(&key, table.as_ptr(), 3, 0);
+        assert_eq!(tagged.tag(), 3);
+        assert_eq!(tagged.get(&key, 3, 0), Ok(table.as_ptr()));
+        assert_eq!(tagged.get(&key, 4, 0), Err(PacError::BadTag));
+        assert_eq!(tagged.get(&key, 3, 1), Err(PacError::BadMac));
+
+        // Context and tag are separate MAC inputs, so swapping them does not
+        // carry the MAC over.
+        let tagged = TaggedPtr::new(&key, table.as_ptr(), 2, 1);
+        let swapped = TaggedPtr::<u8>::from_raw((tagged.raw() & !(0xFF << 56)) | (1 << 56));
+        assert_eq!(swapped.get(&key, 1, 2), Err(PacError::BadMac));
+    }
+}
@@ -0,0 +1,375 @@
+//! Minimal `perf_event_open` bindings: the attribute struct with the
+//! kernel's layout, single self-monitoring counters, and groups that are
+//! enabled, disabled and read as one. `events` names the events worth
+//! asking for and copes with the ones a machine does not have.
+
+use std::io;
+use std::mem;
+
+pub mod events;
+
+pub const PERF_TYPE_HARDWARE: u32 = 0;
+pub const PERF_TYPE_SOFTWARE: u32 = 1;
+pub const PERF_TYPE_HW_CACHE: u32 = 3;
+
+pub const PERF_COUNT_HW_CPU_CYCLES: u64 = 0;
+pub const PERF_COUNT_HW_INSTRUCTIONS: u64 = 1;
+pub const PERF_COUNT_HW_CACHE_REFERENCES: u64 = 2;
+pub const PERF_COUNT_HW_CACHE_MISSES: u64 = 3;
+pub const PERF_COUNT_HW_BRANCH_INSTRUCTIONS: u64 = 4;
+pub const PERF_COUNT_HW_BRANCH_MISSES: u64 = 5;
+
+pub const PERF_COUNT_SW_CPU_CLOCK: u64 = 0;
+pub const PERF_COUNT_SW_TASK_CLOCK: u64 = 1;
+pub const PERF_COUNT_SW_PAGE_FAULTS: u64 = 2;
+pub const PERF_COUNT_SW_CONTEXT_SWITCHES: u64 = 3;
+pub const PERF_COUNT_SW_CPU_MIGRATIONS: u64 = 4;
+pub const PERF_COUNT_SW_PAGE_FAULTS_MIN: u64 = 5;
+pub const PERF_COUNT_SW_PAGE_FAULTS_MAJ: u64 = 6;
+
+pub const PERF_FORMAT_TOTAL_TIME_ENABLED: u64 = 1 << 0;
+pub const PERF_FORMAT_TOTAL_TIME_RUNNING: u64 = 1 << 1;
+pub const PERF_FORMAT_ID: u64 = 1 << 2;
+pub const PERF_FORMAT_GROUP: u64 = 1 << 3;
+
+const PERF_FLAG_FD_CLOEXEC: libc::c_ulong = 1 << 3;
+
+// `_IO('$', n)`.
+const PERF_EVENT_IOC_ENABLE: libc::c_ulong = 0x2400;
+const PERF_EVENT_IOC_DISABLE: libc::c_ulong = 0x2401;
+const PERF_EVENT_IOC_RESET: libc::c_ulong = 0x2403;
+/// Makes an ioctl on the leader apply to every member of its group.
+const PERF_IOC_FLAG_GROUP: libc::c_ulong = 1;
+
+/// Bit positions within the kernel's bitfield word, in declaration order.
+const DISABLED: u32 = 0;
+const INHERIT: u32 = 1;
+const PINNED: u32 = 2;
+const EXCLUSIVE: u32 = 3;
+const EXCLUDE_USER: u32 = 4;
+const EXCLUDE_KERNEL: u32 = 5;
+const EXCLUDE_HV: u32 = 6;
+const EXCLUDE_IDLE: u32 = 7;
+
+/// `struct perf_event_attr` up to `PERF_ATTR_SIZE_VER5`, which every
+/// kernel since 4.1 accepts. The C bitfields share one `u64` (`flags`);
+/// set them through the setters rather than by hand.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PerfEventAttr {
+    pub type_: u32,
+    pub size: u32,
+    pub config: u64,
+    pub sample_period: u64,
+    pub sample_type: u64,
+    pub read_format: u64,
+    pub flags: u64,
+    pub wakeup_events: u32,
+    pub bp_type: u32,
+    pub config1: u64,
+    pub config2: u64,
+    pub branch_sample_type: u64,
+    pub sample_regs_user: u64,
+    pub sample_stack_user: u32,
+    pub clockid: i32,
+    pub sample_regs_intr: u64,
+    pub aux_watermark: u32,
+    pub sample_max_stack: u16,
+    pub reserved_2: u16,
+}
+
+impl PerfEventAttr {
+    pub fn new(type_: u32, config: u64) -> Self {
+        Self {
+            type_,
+            size: mem::size_of::<Self>() as u32,
+            config,
+            ..Default::default()
+        }
+    }
+
+    fn set_bit(&mut self, bit: u32, on: bool) -> &mut Self {
+        if on {
+            self.flags |= 1 << bit;
+        } else {
+            self.flags &= !(1 << bit);
+        }
+        self
+    }
+
+    /// Start stopped; `enable` starts counting.
+    pub fn set_disabled(&mut self, on: bool) -> &mut Self {
+        self.set_bit(DISABLED, on)
+    }
+
+    /// Count in threads created after the counter is opened too.
+    pub fn set_inherit(&mut self, on: bool) -> &mut Self {
+        self.set_bit(INHERIT, on)
+    }
+
+    pub fn set_pinned(&mut self, on: bool) -> &mut Self {
+        self.set_bit(PINNED, on)
+    }
+
+    pub fn set_exclusive(&mut self, on: bool) -> &mut Self {
+        self.set_bit(EXCLUSIVE, on)
+    }
+
+    pub fn set_exclude_user(&mut self, on: bool) -> &mut Self {
+        self.set_bit(EXCLUDE_USER, on)
+    }
+
+    pub fn set_exclude_kernel(&mut self, on: bool) -> &mut Self {
+        self.set_bit(EXCLUDE_KERNEL, on)
+    }
+
+    pub fn set_exclude_hv(&mut self, on: bool) -> &mut Self {
+        self.set_bit(EXCLUDE_HV, on)
+    }
+
+    pub fn set_exclude_idle(&mut self, on: bool) -> &mut Self {
+        self.set_bit(EXCLUDE_IDLE, on)
+    }
+}
+
+/// One counter for the calling thread on any CPU, counting user space only.
+pub struct Counter {
+    fd: i32,
+}
+
+impl Counter {
+    pub fn open(type_: u32, config: u64) -> io::Result<Self> {
+        let mut attr = PerfEventAttr::new(type_, config);
+        attr.set_exclude_kernel(true).set_exclude_hv(true);
+        Self::open_attr(&attr, -1)
+    }
+
+    /// Opens `attr` for the calling thread, in the group led by `group_fd`
+    /// or on its own with -1.
+    pub fn open_attr(attr: &PerfEventAttr, group_fd: i32) -> io::Result<Self> {
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_perf_event_open,
+                attr as *const PerfEventAttr,
+                0,
+                -1,
+                group_fd,
+                PERF_FLAG_FD_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { fd: fd as i32 })
+    }
+
+    pub fn fd(&self) -> i32 {
+        self.fd
+    }
+
+    /// The raw count. Only meaningful with the default `read_format` of 0.
+    pub fn read(&self) -> io::Result<u64> {
+        let mut value = 0u64;
+        let n = unsafe { libc::read(self.fd, &mut value as *mut u64 as *mut libc::c_void, 8) };
+        if n != 8 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(value)
+    }
+
+    fn ioctl(&self, request: libc::c_ulong, arg: libc::c_ulong) -> io::Result<()> {
+        if unsafe { libc::ioctl(self.fd, request, arg) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Counter {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/// Counts of one group read, and how long the group was enabled and
+/// actually on the PMU.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupReading {
+    pub time_enabled: u64,
+    pub time_running: u64,
+    /// Raw counts, in the order the events were added.
+    pub values: Vec<u64>,
+}
+
+impl GroupReading {
+    /// Parses the `PERF_FORMAT_GROUP | TOTAL_TIME_ENABLED |
+    /// TOTAL_TIME_RUNNING` layout: nr, enabled, running, then nr values.
+    pub fn parse(words: &[u64]) -> Option<Self> {
+        let (&nr, rest) = words.split_first()?;
+        let (&[time_enabled, time_running], values) = rest.split_first_chunk::<2>()?;
+        let values = values.get(..nr as usize)?.to_vec();
+        Some(Self { time_enabled, time_running, values })
+    }
+
+    /// The `i`th count extrapolated to the whole enabled time, for when the
+    /// kernel multiplexed the group with others. `None` if the group never
+    /// got onto the PMU.
+    pub fn scaled(&self, i: usize) -> Option<u64> {
+        if self.time_running == 0 {
+            return None;
+        }
+        let value = self.values[i] as u128 * self.time_enabled as u128 / self.time_running as u128;
+        Some(value as u64)
+    }
+
+    /// True if the group was on the PMU for less than the whole region.
+    pub fn multiplexed(&self) -> bool {
+        self.time_running < self.time_enabled
+    }
+}
+
+/// Counters scheduled onto the PMU together and read in one `read(2)`,
+/// so their counts cover exactly the same instructions. The first event
+/// added leads the group.
+#[derive(Default)]
+pub struct Group {
+    counters: Vec<Counter>,
+}
+
+impl Group {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a user-space-only event; returns its index in readings.
+    pub fn add(&mut self, type_: u32, config: u64) -> io::Result<usize> {
+        let mut attr = PerfEventAttr::new(type_, config);
+        attr.set_exclude_kernel(true).set_exclude_hv(true);
+        self.add_attr(attr)
+    }
+
+    /// Adds `attr` with the group's read format. The leader starts
+    /// disabled; members follow it.
+    pub fn add_attr(&mut self, mut attr: PerfEventAttr) -> io::Result<usize> {
+        attr.read_format = PERF_FORMAT_GROUP | PERF_FORMAT_TOTAL_TIME_ENABLED | PERF_FORMAT_TOTAL_TIME_RUNNING;
+        let leader = self.counters.first().map_or(-1, Counter::fd);
+        attr.set_disabled(leader == -1);
+        self.counters.push(Counter::open_attr(&attr, leader)?);
+        Ok(self.counters.len() - 1)
+    }
+
+    pub fn len(&self) -> usize {
+        self.counters.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counters.is_empty()
+    }
+
+    fn leader_ioctl(&self, request: libc::c_ulong) -> io::Result<()> {
+        match self.counters.first() {
+            Some(leader) => leader.ioctl(request, PERF_IOC_FLAG_GROUP),
+            None => Ok(()),
+        }
+    }
+
+    pub fn reset(&self) -> io::Result<()> {
+        self.leader_ioctl(PERF_EVENT_IOC_RESET)
+    }
+
+    pub fn enable(&self) -> io::Result<()> {
+        self.leader_ioctl(PERF_EVENT_IOC_ENABLE)
+    }
+
+    pub fn disable(&self) -> io::Result<()> {
+        self.leader_ioctl(PERF_EVENT_IOC_DISABLE)
+    }
+
+    pub fn read(&self) -> io::Result<GroupReading> {
+        let Some(leader) = self.counters.first() else {
+            return Ok(GroupReading { time_enabled: 0, time_running: 0, values: Vec::new() });
+        };
+        let mut words = vec![0u64; 3 + self.counters.len()];
+        let bytes = words.len() * 8;
+        let n = unsafe { libc::read(leader.fd, words.as_mut_ptr() as *mut libc::c_void, bytes) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        GroupReading::parse(&words[..n as usize / 8])
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "short perf group read"))
+    }
+
+    /// Resets the group, counts over `f` only, and reads it.
+    pub fn measure<T>(&self, f: impl FnOnce() -> T) -> io::Result<(T, GroupReading)> {
+        self.reset()?;
+        self.enable()?;
+        let result = f();
+        self.disable()?;
+        Ok((result, self.read()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attr_layout_matches_kernel() {
+        assert_eq!(mem::size_of::<PerfEventAttr>(), 112);
+        assert_eq!(mem::offset_of!(PerfEventAttr, flags), 40);
+        assert_eq!(mem::offset_of!(PerfEventAttr, wakeup_events), 48);
+        assert_eq!(mem::offset_of!(PerfEventAttr, clockid), 92);
+        assert_eq!(PerfEventAttr::new(PERF_TYPE_HARDWARE, 0).size, 112);
+
+        let mut attr = PerfEventAttr::new(PERF_TYPE_HARDWARE, 0);
+        attr.set_disabled(true).set_exclude_kernel(true).set_exclude_hv(true);
+        assert_eq!(attr.flags, 0b110_0001);
+        attr.set_disabled(false);
+        assert_eq!(attr.flags, 0b110_0000);
+    }
+
+    #[test]
+    fn test_group_reading_scales_by_running_time() {
+        let reading = GroupReading::parse(&[2, 1000, 250, 40, 7, 99]).unwrap();
+        assert_eq!(reading.values, [40, 7]);
+        assert!(reading.multiplexed());
+        assert_eq!(reading.scaled(0), Some(160));
+        assert_eq!(reading.scaled(1), Some(28));
+        assert_eq!(GroupReading::parse(&[2, 1000, 0, 1, 2]).unwrap().scaled(0), None);
+        assert_eq!(GroupReading::parse(&[3, 1000, 1000, 1]), None);
+    }
+
+    #[test]
+    fn test_group_counts_only_the_measured_region() {
+        let mut group = Group::new();
+        group.add(PERF_TYPE_SOFTWARE, PERF_COUNT_SW_TASK_CLOCK).unwrap();
+        group.add(PERF_TYPE_SOFTWARE, PERF_COUNT_SW_PAGE_FAULTS).unwrap();
+        let pages = 64;
+        let ((), reading) = group
+            .measure(|| {
+                let mut buf = crate::fault::PageBuffer::new(pages * 4096).unwrap();
+                for page in buf.as_mut_slice().chunks_mut(4096) {
+                    page[0] = 1;
+                }
+            })
+            .unwrap();
+        assert!(reading.scaled(0).unwrap() > 0, "{:?}", reading);
+        assert!(reading.scaled(1).unwrap() >= pages as u64, "{:?}", reading);
+        // Disabled outside `measure`: nothing accumulates in between.
+        std::hint::black_box(vec![1u8; pages * 4096]);
+        assert_eq!(group.read().unwrap(), reading);
+    }
+
+    #[test]
+    fn test_cycle_counter_counts_or_reports_error() {
+        match Counter::open(PERF_TYPE_HARDWARE, PERF_COUNT_HW_CPU_CYCLES) {
+            Ok(counter) => {
+                let before = counter.read().unwrap();
+                std::hint::black_box((0..10_000u64).sum::<u64>());
+                assert!(counter.read().unwrap() > before);
+            }
+            // No PMU (common under hypervisors), or perf_event_paranoid.
+            Err(e) => assert!(e.raw_os_error().is_some(), "{}", e),
+        }
+    }
+}
@@ -0,0 +1,391 @@
+//! A typed catalogue of perf events, opened so that a missing event is
+//! reported rather than silently read as zero.
+//!
+//! Hypervisors often expose no PMU at all, or only part of one. `EventSet`
+//! opens each requested event together with its partner, e.g. LLC loads
+//! with LLC load misses, so a ratio of the two covers the same
+//! instructions. If a pair will not open it tries both members on their
+//! own, keeps the ones the kernel accepts and records why the others
+//! failed. If none of the hardware events open, it adds the software
+//! events the kernel always has, so a report still says something about
+//! the region.
+
+use std::fmt;
+use std::io;
+
+use super::*;
+
+/// Caches `PERF_TYPE_HW_CACHE` can count, in the kernel's numbering.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HwCache {
+    L1d = 0,
+    L1i = 1,
+    Llc = 2,
+    Dtlb = 3,
+    Itlb = 4,
+    Bpu = 5,
+    Node = 6,
+}
+
+impl HwCache {
+    fn name(self) -> &'static str {
+        match self {
+            HwCache::L1d => "L1-dcache",
+            HwCache::L1i => "L1-icache",
+            HwCache::Llc => "LLC",
+            HwCache::Dtlb => "dTLB",
+            HwCache::Itlb => "iTLB",
+            HwCache::Bpu => "branch",
+            HwCache::Node => "node",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CacheOp {
+    Read = 0,
+    Write = 1,
+    Prefetch = 2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CacheResult {
+    Access = 0,
+    Miss = 1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Event {
+    Cycles,
+    Instructions,
+    Branches,
+    BranchMisses,
+    CacheReferences,
+    CacheMisses,
+    Cache(HwCache, CacheOp, CacheResult),
+    /// Nanoseconds the task spent on a CPU.
+    TaskClock,
+    PageFaults,
+    MinorFaults,
+    MajorFaults,
+    ContextSwitches,
+    CpuMigrations,
+}
+
+impl Event {
+    pub const L1D_READS: Event = Event::Cache(HwCache::L1d, CacheOp::Read, CacheResult::Access);
+    pub const L1D_READ_MISSES: Event = Event::Cache(HwCache::L1d, CacheOp::Read, CacheResult::Miss);
+    pub const LLC_READS: Event = Event::Cache(HwCache::Llc, CacheOp::Read, CacheResult::Access);
+    pub const LLC_READ_MISSES: Event = Event::Cache(HwCache::Llc, CacheOp::Read, CacheResult::Miss);
+    pub const DTLB_READS: Event = Event::Cache(HwCache::Dtlb, CacheOp::Read, CacheResult::Access);
+    pub const DTLB_READ_MISSES: Event = Event::Cache(HwCache::Dtlb, CacheOp::Read, CacheResult::Miss);
+    pub const ITLB_READS: Event = Event::Cache(HwCache::Itlb, CacheOp::Read, CacheResult::Access);
+    pub const ITLB_READ_MISSES: Event = Event::Cache(HwCache::Itlb, CacheOp::Read, CacheResult::Miss);
+
+    /// Everything the probe asks for by default.
+    pub const CATALOGUE: [Event; 18] = [
+        Event::Cycles,
+        Event::Instructions,
+        Event::Branches,
+        Event::BranchMisses,
+        Event::CacheReferences,
+        Event::CacheMisses,
+        Event::L1D_READS,
+        Event::L1D_READ_MISSES,
+        Event::LLC_READS,
+        Event::LLC_READ_MISSES,
+        Event::DTLB_READS,
+        Event::DTLB_READ_MISSES,
+        Event::ITLB_READS,
+        Event::ITLB_READ_MISSES,
+        Event::TaskClock,
+        Event::PageFaults,
+        Event::ContextSwitches,
+        Event::CpuMigrations,
+    ];
+
+    /// Added when no hardware event opens. The kernel implements these
+    /// itself, so they need no PMU.
+    pub const SOFTWARE_FALLBACK: [Event; 4] =
+        [Event::TaskClock, Event::PageFaults, Event::ContextSwitches, Event::CpuMigrations];
+
+    /// `perf_event_attr.type` and `.config`.
+    pub fn encoding(self) -> (u32, u64) {
+        match self {
+            Event::Cycles => (PERF_TYPE_HARDWARE, PERF_COUNT_HW_CPU_CYCLES),
+            Event::Instructions => (PERF_TYPE_HARDWARE, PERF_COUNT_HW_INSTRUCTIONS),
+            Event::Branches => (PERF_TYPE_HARDWARE, PERF_COUNT_HW_BRANCH_INSTRUCTIONS),
+            Event::BranchMisses => (PERF_TYPE_HARDWARE, PERF_COUNT_HW_BRANCH_MISSES),
+            Event::CacheReferences => (PERF_TYPE_HARDWARE, PERF_COUNT_HW_CACHE_REFERENCES),
+            Event::CacheMisses => (PERF_TYPE_HARDWARE, PERF_COUNT_HW_CACHE_MISSES),
+            Event::Cache(cache, op, result) => {
+                (PERF_TYPE_HW_CACHE, cache as u64 | (op as u64) << 8 | (result as u64) << 16)
+            }
+            Event::TaskClock => (PERF_TYPE_SOFTWARE, PERF_COUNT_SW_TASK_CLOCK),
+            Event::PageFaults => (PERF_TYPE_SOFTWARE, PERF_COUNT_SW_PAGE_FAULTS),
+            Event::MinorFaults => (PERF_TYPE_SOFTWARE, PERF_COUNT_SW_PAGE_FAULTS_MIN),
+            Event::MajorFaults => (PERF_TYPE_SOFTWARE, PERF_COUNT_SW_PAGE_FAULTS_MAJ),
+            Event::ContextSwitches => (PERF_TYPE_SOFTWARE, PERF_COUNT_SW_CONTEXT_SWITCHES),
+            Event::CpuMigrations => (PERF_TYPE_SOFTWARE, PERF_COUNT_SW_CPU_MIGRATIONS),
+        }
+    }
+
+    pub fn is_software(self) -> bool {
+        self.encoding().0 == PERF_TYPE_SOFTWARE
+    }
+
+    /// What the count is in, if not a plain number of events.
+    pub fn unit(self) -> Option<&'static str> {
+        (self == Event::TaskClock).then_some("ns")
+    }
+
+    /// The event whose count this one is usually divided by or into:
+    /// instructions for cycles, misses for accesses and the reverse.
+    pub fn partner(self) -> Option<Event> {
+        Some(match self {
+            Event::Cycles => Event::Instructions,
+            Event::Instructions => Event::Cycles,
+            Event::Branches => Event::BranchMisses,
+            Event::BranchMisses => Event::Branches,
+            Event::CacheReferences => Event::CacheMisses,
+            Event::CacheMisses => Event::CacheReferences,
+            Event::Cache(cache, op, CacheResult::Access) => Event::Cache(cache, op, CacheResult::Miss),
+            Event::Cache(cache, op, CacheResult::Miss) => Event::Cache(cache, op, CacheResult::Access),
+            _ => return None,
+        })
+    }
+
+    /// Opens the event on its own and closes it again.
+    pub fn probe(self) -> io::Result<()> {
+        open_group(&[self]).map(drop)
+    }
+}
+
+/// A group counting `events` in user space, led by the first.
+fn open_group(events: &[Event]) -> io::Result<Group> {
+    let mut group = Group::new();
+    for event in events {
+        let (type_, config) = event.encoding();
+        group.add(type_, config)?;
+    }
+    Ok(group)
+}
+
+/// Names as `perf list` prints them.
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match *self {
+            Event::Cycles => "cycles",
+            Event::Instructions => "instructions",
+            Event::Branches => "branches",
+            Event::BranchMisses => "branch-misses",
+            Event::CacheReferences => "cache-references",
+            Event::CacheMisses => "cache-misses",
+            Event::TaskClock => "task-clock",
+            Event::PageFaults => "page-faults",
+            Event::MinorFaults => "minor-faults",
+            Event::MajorFaults => "major-faults",
+            Event::ContextSwitches => "context-switches",
+            Event::CpuMigrations => "cpu-migrations",
+            Event::Cache(cache, op, result) => {
+                let op = match op {
+                    CacheOp::Read => "load",
+                    CacheOp::Write => "store",
+                    CacheOp::Prefetch => "prefetch",
+                };
+                let name = match result {
+                    CacheResult::Access => format!("{}-{}s", cache.name(), op),
+                    CacheResult::Miss => format!("{}-{}-misses", cache.name(), op),
+                };
+                return f.pad(&name);
+            }
+        };
+        f.pad(name)
+    }
+}
+
+/// One event's count over a measured region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EventCount {
+    pub event: Event,
+    /// Scaled for multiplexing; `None` if the event opened but its group
+    /// was never scheduled onto the PMU during the region.
+    pub value: Option<u64>,
+    pub multiplexed: bool,
+}
+
+impl EventCount {
+    /// False if the PMU was busy with other groups for the whole region.
+    pub fn scheduled(&self) -> bool {
+        self.value.is_some()
+    }
+}
+
+/// The events that opened, in groups of partners that the kernel
+/// schedules together and multiplexes against each other, and the ones
+/// that did not.
+pub struct EventSet {
+    opened: Vec<(Vec<Event>, Group)>,
+    unavailable: Vec<(Event, io::Error)>,
+    fell_back: bool,
+}
+
+impl EventSet {
+    /// Tries each of `events`; see the module docs for the fallback.
+    pub fn open(events: &[Event]) -> Self {
+        let mut set = Self { opened: Vec::new(), unavailable: Vec::new(), fell_back: false };
+        for &event in events {
+            match event.partner().filter(|p| events.contains(p)) {
+                Some(partner) => set.try_add_pair(event, partner),
+                None => set.try_add(event),
+            }
+        }
+        let wanted_hardware = events.iter().any(|e| !e.is_software());
+        if wanted_hardware && set.events().iter().all(|e| e.is_software()) {
+            set.fell_back = true;
+            for event in Event::SOFTWARE_FALLBACK {
+                set.try_add(event);
+            }
+        }
+        set
+    }
+
+    fn tried(&self, event: Event) -> bool {
+        self.contains(event) || self.unavailable.iter().any(|(e, _)| *e == event)
+    }
+
+    fn try_add(&mut self, event: Event) {
+        if self.tried(event) {
+            return;
+        }
+        match open_group(&[event]) {
+            Ok(group) => self.opened.push((vec![event], group)),
+            Err(e) => self.unavailable.push((event, e)),
+        }
+    }
+
+    /// Both in one group, or each on its own if that fails.
+    fn try_add_pair(&mut self, event: Event, partner: Event) {
+        if self.tried(event) || self.tried(partner) {
+            self.try_add(event);
+            return;
+        }
+        match open_group(&[event, partner]) {
+            Ok(group) => self.opened.push((vec![event, partner], group)),
+            Err(_) => {
+                self.try_add(event);
+                self.try_add(partner);
+            }
+        }
+    }
+
+    pub fn contains(&self, event: Event) -> bool {
+        self.opened.iter().any(|(events, _)| events.contains(&event))
+    }
+
+    /// Events that will be counted, in the order they opened.
+    pub fn events(&self) -> Vec<Event> {
+        self.opened.iter().flat_map(|(events, _)| events.iter().copied()).collect()
+    }
+
+    /// The events counted together, one list per group.
+    pub fn groups(&self) -> Vec<Vec<Event>> {
+        self.opened.iter().map(|(events, _)| events.clone()).collect()
+    }
+
+    /// Events the kernel refused, with its reason.
+    pub fn unavailable(&self) -> &[(Event, io::Error)] {
+        &self.unavailable
+    }
+
+    /// True if the software events were added because no hardware event
+    /// opened.
+    pub fn fell_back(&self) -> bool {
+        self.fell_back
+    }
+
+    /// Resets and enables every event, runs `f`, disables and reads them.
+    pub fn measure<T>(&self, f: impl FnOnce() -> T) -> io::Result<(T, Vec<EventCount>)> {
+        for (_, group) in &self.opened {
+            group.reset()?;
+        }
+        for (_, group) in &self.opened {
+            group.enable()?;
+        }
+        let result = f();
+        for (_, group) in &self.opened {
+            group.disable()?;
+        }
+        let mut counts = Vec::new();
+        for (events, group) in &self.opened {
+            let reading = group.read()?;
+            for (i, &event) in events.iter().enumerate() {
+                counts.push(EventCount { event, value: reading.scaled(i), multiplexed: reading.multiplexed() });
+            }
+        }
+        Ok((result, counts))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encodings_and_names() {
+        assert_eq!(Event::Instructions.encoding(), (PERF_TYPE_HARDWARE, 1));
+        // LL | OP_READ << 8 | RESULT_MISS << 16, as in the kernel docs.
+        assert_eq!(Event::LLC_READ_MISSES.encoding(), (PERF_TYPE_HW_CACHE, 0x10002));
+        assert_eq!(Event::DTLB_READS.encoding(), (PERF_TYPE_HW_CACHE, 0x3));
+        assert_eq!(Event::Cache(HwCache::L1d, CacheOp::Prefetch, CacheResult::Miss).encoding().1, 0x10200);
+        assert_eq!(Event::PageFaults.encoding(), (PERF_TYPE_SOFTWARE, 2));
+        assert_eq!(Event::L1D_READ_MISSES.to_string(), "L1-dcache-load-misses");
+        assert_eq!(Event::ITLB_READS.to_string(), "iTLB-loads");
+        assert_eq!(format!("{:<12}|", Event::TaskClock), "task-clock  |");
+    }
+
+    #[test]
+    fn test_every_event_opens_or_reports_why() {
+        let set = EventSet::open(&Event::CATALOGUE);
+        assert_eq!(set.events().len() + set.unavailable().len(), Event::CATALOGUE.len());
+        for (event, e) in set.unavailable() {
+            assert!(!event.is_software(), "{}: {}", event, e);
+            assert!(e.raw_os_error().is_some(), "{}: {}", event, e);
+        }
+        assert!(Event::SOFTWARE_FALLBACK.iter().all(|&e| set.contains(e)));
+    }
+
+    #[test]
+    fn test_partners_share_a_group() {
+        assert_eq!(Event::LLC_READS.partner(), Some(Event::LLC_READ_MISSES));
+        assert_eq!(Event::Instructions.partner(), Some(Event::Cycles));
+        assert_eq!(Event::TaskClock.partner(), None);
+
+        let set = EventSet::open(&Event::CATALOGUE);
+        for group in set.groups() {
+            match group[..] {
+                [a, b] => assert_eq!(a.partner(), Some(b)),
+                [_] => {}
+                _ => panic!("{:?}", group),
+            }
+        }
+        // A partner outside the request is not pulled in.
+        let set = EventSet::open(&[Event::CacheMisses, Event::TaskClock]);
+        assert!(set.groups().iter().all(|g| g.len() == 1), "{:?}", set.groups());
+    }
+
+    #[test]
+    fn test_falls_back_to_software_without_pmu() {
+        let hardware = [Event::Cycles, Event::DTLB_READ_MISSES];
+        let set = EventSet::open(&hardware);
+        let no_pmu = hardware.iter().all(|e| e.probe().is_err());
+        assert_eq!(set.fell_back(), no_pmu);
+
+        let ((), counts) = set.measure(|| std::hint::black_box(vec![1u8; 1 << 20]).clear()).unwrap();
+        assert_eq!(counts.len(), set.events().len());
+        let task_clock = counts.iter().find(|c| c.event == Event::TaskClock);
+        if no_pmu {
+            assert!(task_clock.unwrap().value.unwrap() > 0, "{:?}", counts);
+        }
+    }
+}
@@ -0,0 +1,288 @@
+//! Simulated power traces for emulated comparator kernels and a correlation
+//! power analysis (CPA) engine to attack them.
+
+use rand::rngs::StdRng;
+use std::ops::Range;
+use rand::{Rng, SeedableRng};
+
+use crate::taint::{EmuError, Emulator, LeakageSample};
+
+/// Known keys and inputs per key used to locate points of interest.
+const PROFILE_KEYS: usize = 4;
+const PROFILE_INPUTS: usize = 64;
+/// Noiseless leakage that matches the hypothesis this closely is taken to
+/// be the hypothesised value itself.
+const POI_CORRELATION: f64 = 0.99;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LeakageModel {
+    /// Hamming weight of every value written by an instruction.
+    HammingWeight,
+    /// Hamming distance between the old and new value of each destination.
+    HammingDistance,
+}
+
+/// Runs machine code through the taint emulator and turns its per-instruction
+/// leakage into a power trace, optionally with Gaussian noise.
+pub struct PowerSimulator {
+    code: Vec<u8>,
+    model: LeakageModel,
+    noise: f64,
+    rng: StdRng,
+}
+
+impl PowerSimulator {
+    pub fn new(code: &[u8], model: LeakageModel) -> Self {
+        Self {
+            code: code.to_vec(),
+            model,
+            noise: 0.0,
+            rng: StdRng::from_entropy(),
+        }
+    }
+
+    /// Standard deviation of the additive noise, in bit flips.
+    pub fn with_noise(mut self, sigma: f64) -> Self {
+        self.noise = sigma;
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = StdRng::seed_from_u64(seed);
+        self
+    }
+
+    /// One sample per executed instruction of a comparator call over `lhs`
+    /// and `rhs`.
+    pub fn trace(&mut self, lhs: &[u8], rhs: &[u8]) -> Result<Vec<f64>, EmuError> {
+        let leakage = self.noiseless(lhs, rhs)?;
+        Ok(leakage.into_iter().map(|leak| leak + self.gaussian()).collect())
+    }
+
+    fn noiseless(&self, lhs: &[u8], rhs: &[u8]) -> Result<Vec<f64>, EmuError> {
+        let mut emu = Emulator::for_comparator(&self.code, lhs, rhs);
+        emu.record_leakage();
+        emu.run()?;
+        let leak = |sample: &LeakageSample| match self.model {
+            LeakageModel::HammingWeight => sample.hw,
+            LeakageModel::HammingDistance => sample.hd,
+        };
+        Ok(emu.leakage().iter().map(|s| leak(s) as f64).collect())
+    }
+
+    /// Samples of a one-byte comparison that leak exactly
+    /// `hypothesis(key, input)`, found by profiling the code with known
+    /// keys. Restricting `cpa_points` to these keeps out instructions that
+    /// leak the input alone, which look like guess 0, and ones that leak a
+    /// related value such as `key ^ input ^ 1`, which look like another key.
+    pub fn points_of_interest(&self, hypothesis: impl Fn(u8, u8) -> f64) -> Result<Vec<usize>, EmuError> {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut points: Option<Vec<usize>> = None;
+        for _ in 0..PROFILE_KEYS {
+            let key: u8 = rng.gen();
+            let inputs: Vec<u8> = (0..PROFILE_INPUTS).map(|_| rng.gen()).collect();
+            let traces = inputs.iter().map(|&x| self.noiseless(&[key], &[x])).collect::<Result<Vec<_>, _>>()?;
+            let h: Vec<f64> = inputs.iter().map(|&x| hypothesis(key, x)).collect();
+            let shortest = traces.iter().map(Vec::len).min().unwrap_or(0);
+            let matching = (0..shortest).filter(|&i| {
+                let samples: Vec<f64> = traces.iter().map(|t| t[i]).collect();
+                pearson(&h, &samples) > POI_CORRELATION
+            });
+            points = Some(match points {
+                None => matching.collect(),
+                Some(kept) => matching.filter(|i| kept.contains(i)).collect(),
+            });
+        }
+        Ok(points.unwrap_or_default())
+    }
+
+    fn gaussian(&mut self) -> f64 {
+        if self.noise == 0.0 {
+            return 0.0;
+        }
+        // Box-Muller; `rand` alone has no normal distribution.
+        let u1: f64 = self.rng.gen_range(f64::EPSILON..1.0);
+        let u2: f64 = self.rng.gen();
+        self.noise * (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+    }
+}
+
+fn pearson(a: &[f64], b: &[f64]) -> f64 {
+    let n = a.len() as f64;
+    let (ma, mb) = (a.iter().sum::<f64>() / n, b.iter().sum::<f64>() / n);
+    let cov: f64 = a.iter().zip(b).map(|(x, y)| (x - ma) * (y - mb)).sum();
+    let da: f64 = a.iter().map(|x| (x - ma) * (x - ma)).sum();
+    let db: f64 = b.iter().map(|y| (y - mb) * (y - mb)).sum();
+    let denom = (da * db).sqrt();
+    if denom > 0.0 { cov / denom } else { 0.0 }
+}
+
+/// HW(guess ^ input): the first secret-dependent value a XOR-accumulating
+/// comparator writes.
+pub fn hw_xor(guess: u8, input: u8) -> f64 {
+    (guess ^ input).count_ones() as f64
+}
+
+#[derive(Debug, Clone)]
+pub struct CpaResult {
+    /// Pearson correlation per key guess (outer) and sample (inner).
+    pub correlations: Vec<Vec<f64>>,
+    /// Guesses ordered by their peak correlation, best first.
+    pub ranking: Vec<(u8, f64)>,
+}
+
+impl CpaResult {
+    pub fn best(&self) -> u8 {
+        self.ranking[0].0
+    }
+
+    pub fn rank_of(&self, key: u8) -> usize {
+        self.ranking.iter().position(|&(g, _)| g == key).unwrap_or(usize::MAX)
+    }
+
+    pub fn peak(&self, key: u8) -> f64 {
+        self.ranking.iter().find(|&&(g, _)| g == key).map_or(0.0, |&(_, c)| c)
+    }
+}
+
+/// Correlates `hypothesis(guess, input)` for each of the 256 byte guesses
+/// against every sample of `traces`, where `inputs[i]` is the known input
+/// byte of trace `i`. Traces are truncated to the shortest one.
+///
+/// Guesses are ranked by their highest positive correlation so that, under a
+/// Hamming-weight model, a key is not confused with its complement.
+pub fn cpa(traces: &[Vec<f64>], inputs: &[u8], hypothesis: impl Fn(u8, u8) -> f64) -> CpaResult {
+    cpa_window(traces, inputs, 0..usize::MAX, hypothesis)
+}
+
+/// `cpa` restricted to the samples in `window`.
+///
+/// Instructions that only handle the known input leak `HW(input)`, which is
+/// exactly the hypothesis for guess 0, so attacks on comparators should
+/// start the window after the input loads, or use `cpa_points`.
+pub fn cpa_window(
+    traces: &[Vec<f64>],
+    inputs: &[u8],
+    window: Range<usize>,
+    hypothesis: impl Fn(u8, u8) -> f64,
+) -> CpaResult {
+    let shortest = traces.iter().map(Vec::len).min().unwrap_or(0);
+    let points: Vec<usize> = (window.start.min(shortest)..window.end.min(shortest)).collect();
+    cpa_points(traces, inputs, &points, hypothesis)
+}
+
+/// `cpa` restricted to the samples at `points`, e.g. those from
+/// `PowerSimulator::points_of_interest`. `correlations` has one entry per
+/// point, in the same order. Points past the shortest trace are skipped.
+pub fn cpa_points(
+    traces: &[Vec<f64>],
+    inputs: &[u8],
+    points: &[usize],
+    hypothesis: impl Fn(u8, u8) -> f64,
+) -> CpaResult {
+    assert_eq!(traces.len(), inputs.len(), "one known input per trace");
+    let n = traces.len() as f64;
+    let shortest = traces.iter().map(Vec::len).min().unwrap_or(0);
+    let points: Vec<usize> = points.iter().copied().filter(|&i| i < shortest).collect();
+    let traces: Vec<Vec<f64>> = traces.iter().map(|t| points.iter().map(|&i| t[i]).collect()).collect();
+    let len = points.len();
+
+    let mut mean = vec![0.0; len];
+    for trace in &traces {
+        for (m, &x) in mean.iter_mut().zip(trace.iter()) {
+            *m += x / n;
+        }
+    }
+    let mut dev = vec![0.0; len];
+    for trace in &traces {
+        for ((d, &x), &m) in dev.iter_mut().zip(trace.iter()).zip(&mean) {
+            *d += (x - m) * (x - m);
+        }
+    }
+
+    let mut correlations = Vec::with_capacity(256);
+    let mut ranking = Vec::with_capacity(256);
+    for guess in 0..=255u8 {
+        let h: Vec<f64> = inputs.iter().map(|&x| hypothesis(guess, x)).collect();
+        let h_mean = h.iter().sum::<f64>() / n;
+        let h_dev = h.iter().map(|v| (v - h_mean) * (v - h_mean)).sum::<f64>();
+
+        let mut cov = vec![0.0; len];
+        for (trace, &hv) in traces.iter().zip(&h) {
+            for ((c, &x), &m) in cov.iter_mut().zip(trace.iter()).zip(&mean) {
+                *c += (hv - h_mean) * (x - m);
+            }
+        }
+        let rho: Vec<f64> = cov
+            .iter()
+            .zip(&dev)
+            .map(|(&c, &d)| {
+                let denom = (h_dev * d).sqrt();
+                if denom > 0.0 { c / denom } else { 0.0 }
+            })
+            .collect();
+
+        let peak = rho.iter().copied().fold(f64::MIN, f64::max);
+        ranking.push((guess, peak));
+        correlations.push(rho);
+    }
+    ranking.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    CpaResult { correlations, ranking }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+
+    const TRACES: usize = 400;
+
+    fn collect(sim: &mut PowerSimulator, secret: u8) -> (Vec<Vec<f64>>, Vec<u8>) {
+        let mut rng = StdRng::seed_from_u64(7);
+        let inputs: Vec<u8> = (0..TRACES).map(|_| rng.gen()).collect();
+        let traces = inputs
+            .iter()
+            .map(|&x| sim.trace(&[secret], &[x]).unwrap())
+            .collect();
+        (traces, inputs)
+    }
+
+    #[test]
+    fn test_trace_shape_is_input_independent() {
+        let jit = crate::jit::compile_checked(4);
+        let mut sim = PowerSimulator::new(jit.code(), LeakageModel::HammingWeight);
+        let a = sim.trace(&[1, 2, 3, 4], &[1, 2, 3, 4]).unwrap();
+        let b = sim.trace(&[1, 2, 3, 4], &[9, 9, 9, 9]).unwrap();
+        assert_eq!(a.len(), b.len());
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn test_cpa_recovers_secret_byte() {
+        let secret = 0xC3;
+        // Register allocation is random, and decides which other values
+        // the comparator's instructions leak alongside secret ^ input.
+        for _ in 0..6 {
+            let jit = crate::jit::compile_checked(1);
+            for model in [LeakageModel::HammingWeight, LeakageModel::HammingDistance] {
+                let mut sim = PowerSimulator::new(jit.code(), model).with_noise(1.0).with_seed(1);
+                let (traces, inputs) = collect(&mut sim, secret);
+                let points = sim.points_of_interest(hw_xor).unwrap();
+                assert!(!points.is_empty(), "{:?}", model);
+                let result = cpa_points(&traces, &inputs, &points, hw_xor);
+                assert_eq!(result.best(), secret, "{:?} at {:?}", model, points);
+                assert!(result.peak(secret) > 0.5);
+            }
+        }
+    }
+
+    #[test]
+    fn test_points_of_interest_skip_input_only_samples() {
+        let jit = crate::jit::compile_checked(1);
+        let sim = PowerSimulator::new(jit.code(), LeakageModel::HammingWeight);
+        let points = sim.points_of_interest(hw_xor).unwrap();
+        // Guess 0 is HW(input), which only the input load leaks.
+        let inputs = sim.points_of_interest(|_, x| x.count_ones() as f64).unwrap();
+        assert!(!inputs.is_empty() && inputs.iter().all(|i| !points.contains(i)), "{:?} {:?}", points, inputs);
+    }
+}
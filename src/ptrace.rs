@@ -0,0 +1,570 @@
+use libc::{c_void, pid_t, user_regs_struct};
+use std::ffi::CString;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::ptr;
+
+use crate::symbolize::Symbolizer;
+use crate::Comparator;
+
+const INT3: u64 = 0xCC;
+const MIN_STEP_BUDGET: usize = 256;
+
+/// A comparator call running in a forked child, stopped under ptrace.
+///
+/// The child is parked on the first instruction of the target function with
+/// the original arguments loaded, so `steps()` counts instructions executed
+/// inside the call (including callees) and nothing from the fork path.
+pub struct Tracee {
+    pid: pid_t,
+    lhs: *const u8,
+    rhs: *const u8,
+    entry_sp: u64,
+    return_addr: u64,
+    steps: usize,
+    live: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepEvent {
+    Stepped,
+    Returned(i32),
+    Signaled(i32),
+    Exited(i32),
+}
+
+impl Tracee {
+    pub fn spawn(target: Comparator, lhs: &[u8], rhs: &[u8]) -> io::Result<Self> {
+        let len = lhs.len().min(rhs.len());
+        let (lp, rp) = (lhs.as_ptr(), rhs.as_ptr());
+
+        let pid = unsafe { libc::fork() };
+        if pid == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        if pid == 0 {
+            // Nothing that can take a lock may run here: the parent may be
+            // multithreaded and only this thread survives the fork.
+            unsafe {
+                libc::ptrace(libc::PTRACE_TRACEME, 0, 0, 0);
+                libc::raise(libc::SIGSTOP);
+                target(lp, rp, len);
+                libc::_exit(0);
+            }
+        }
+
+        let mut tracee = Self {
+            pid,
+            lhs: lp,
+            rhs: rp,
+            entry_sp: 0,
+            return_addr: 0,
+            steps: 0,
+            live: true,
+        };
+        tracee.expect_stop(libc::SIGSTOP)?;
+        tracee.request(libc::PTRACE_SETOPTIONS, 0, libc::PTRACE_O_EXITKILL as usize)?;
+        tracee.run_to_entry(target as usize as u64)?;
+        Ok(tracee)
+    }
+
+    pub fn pid(&self) -> pid_t {
+        self.pid
+    }
+
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+    pub fn lhs(&self) -> *const u8 {
+        self.lhs
+    }
+
+    pub fn rhs(&self) -> *const u8 {
+        self.rhs
+    }
+
+    pub fn regs(&self) -> io::Result<user_regs_struct> {
+        let mut regs: user_regs_struct = unsafe { mem::zeroed() };
+        self.request(libc::PTRACE_GETREGS, 0, &mut regs as *mut _ as usize)?;
+        Ok(regs)
+    }
+
+    pub fn set_regs(&self, regs: &user_regs_struct) -> io::Result<()> {
+        self.request(libc::PTRACE_SETREGS, 0, regs as *const _ as usize)?;
+        Ok(())
+    }
+
+    pub fn peek(&self, addr: usize) -> io::Result<u64> {
+        unsafe { *libc::__errno_location() = 0 };
+        let word = unsafe { libc::ptrace(libc::PTRACE_PEEKDATA, self.pid, addr, 0) };
+        if word == -1 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(0) {
+                return Err(err);
+            }
+        }
+        Ok(word as u64)
+    }
+
+    pub fn poke(&self, addr: usize, word: u64) -> io::Result<()> {
+        self.request(libc::PTRACE_POKEDATA, addr, word as usize)?;
+        Ok(())
+    }
+
+    /// Reads `buf.len()` bytes of child memory starting at `addr`.
+    pub fn read_bytes(&self, addr: usize, buf: &mut [u8]) -> io::Result<()> {
+        let mut word_addr = usize::MAX;
+        let mut word = 0;
+        for (i, byte) in buf.iter_mut().enumerate() {
+            let a = addr + i;
+            if a & !7 != word_addr {
+                word_addr = a & !7;
+                word = self.peek(word_addr)?;
+            }
+            *byte = (word >> ((a & 7) * 8)) as u8;
+        }
+        Ok(())
+    }
+
+    /// Executes one instruction and reports whether the call has returned.
+    pub fn step(&mut self) -> io::Result<StepEvent> {
+        self.request(libc::PTRACE_SINGLESTEP, 0, 0)?;
+        let status = self.wait()?;
+
+        if libc::WIFEXITED(status) {
+            self.live = false;
+            return Ok(StepEvent::Exited(libc::WEXITSTATUS(status)));
+        }
+        if libc::WIFSIGNALED(status) {
+            self.live = false;
+            return Ok(StepEvent::Signaled(libc::WTERMSIG(status)));
+        }
+
+        let sig = libc::WSTOPSIG(status);
+        if sig != libc::SIGTRAP {
+            return Ok(StepEvent::Signaled(sig));
+        }
+
+        self.steps += 1;
+        let regs = self.regs()?;
+        if regs.rip == self.return_addr && regs.rsp > self.entry_sp {
+            Ok(StepEvent::Returned(regs.rax as i32))
+        } else {
+            Ok(StepEvent::Stepped)
+        }
+    }
+
+    /// Single-steps until the call returns, the child dies or `budget`
+    /// instructions have been executed.
+    pub fn finish(&mut self, budget: usize) -> io::Result<Option<StepEvent>> {
+        while self.steps < budget {
+            match self.step()? {
+                StepEvent::Stepped => continue,
+                event => return Ok(Some(event)),
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn inject(&self, site: FaultSite) -> io::Result<()> {
+        match site {
+            FaultSite::Register { reg, bit } => {
+                let mut regs = self.regs()?;
+                *reg.slot(&mut regs) ^= 1 << (bit & 63);
+                self.set_regs(&regs)
+            }
+            FaultSite::Flag(flag) => {
+                let mut regs = self.regs()?;
+                regs.eflags ^= 1 << flag.bit();
+                self.set_regs(&regs)
+            }
+            FaultSite::Lhs { offset, bit } => self.flip_byte(self.lhs as usize + offset, bit),
+            FaultSite::Rhs { offset, bit } => self.flip_byte(self.rhs as usize + offset, bit),
+        }
+    }
+
+    fn flip_byte(&self, addr: usize, bit: u8) -> io::Result<()> {
+        let word = self.peek(addr & !7)?;
+        let mask = 1u64 << ((addr & 7) * 8 + (bit & 7) as usize);
+        self.poke(addr & !7, word ^ mask)
+    }
+
+    fn run_to_entry(&mut self, entry: u64) -> io::Result<()> {
+        let addr = entry as usize;
+        let original = self.peek(addr)?;
+        self.poke(addr, (original & !0xFF) | INT3)?;
+        self.request(libc::PTRACE_CONT, 0, 0)?;
+        self.expect_stop(libc::SIGTRAP)?;
+        self.poke(addr, original)?;
+
+        let mut regs = self.regs()?;
+        if regs.rip != entry + 1 {
+            return Err(io::Error::other("tracee trapped outside the target entry"));
+        }
+        regs.rip = entry;
+        self.set_regs(&regs)?;
+
+        self.entry_sp = regs.rsp;
+        self.return_addr = self.peek(regs.rsp as usize)?;
+        Ok(())
+    }
+
+    fn expect_stop(&mut self, sig: i32) -> io::Result<()> {
+        let status = self.wait()?;
+        if libc::WIFSTOPPED(status) && libc::WSTOPSIG(status) == sig {
+            return Ok(());
+        }
+        if libc::WIFEXITED(status) || libc::WIFSIGNALED(status) {
+            self.live = false;
+        }
+        Err(io::Error::other(format!("unexpected tracee status {:#x}", status)))
+    }
+
+    fn wait(&self) -> io::Result<i32> {
+        let mut status = 0;
+        loop {
+            if unsafe { libc::waitpid(self.pid, &mut status, 0) } != -1 {
+                return Ok(status);
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+
+    fn request(&self, req: libc::c_uint, addr: usize, data: usize) -> io::Result<i64> {
+        let ret = unsafe {
+            libc::ptrace(req, self.pid, addr as *mut c_void, data as *mut c_void)
+        };
+        if ret == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(ret)
+        }
+    }
+}
+
+impl Drop for Tracee {
+    fn drop(&mut self) {
+        if self.live {
+            unsafe {
+                libc::kill(self.pid, libc::SIGKILL);
+                libc::waitpid(self.pid, ptr::null_mut(), 0);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    Rax, Rbx, Rcx, Rdx, Rsi, Rdi, Rbp, Rsp,
+    R8, R9, R10, R11, R12, R13, R14, R15,
+}
+
+impl Register {
+    /// Register by hardware encoding number (0 = RAX ... 15 = R15).
+    pub fn from_number(n: u8) -> Register {
+        const ENCODING: [Register; 16] = [
+            Register::Rax, Register::Rcx, Register::Rdx, Register::Rbx,
+            Register::Rsp, Register::Rbp, Register::Rsi, Register::Rdi,
+            Register::R8, Register::R9, Register::R10, Register::R11,
+            Register::R12, Register::R13, Register::R14, Register::R15,
+        ];
+        ENCODING[(n & 15) as usize]
+    }
+
+    pub fn read(self, regs: &user_regs_struct) -> u64 {
+        let mut copy = *regs;
+        *self.slot(&mut copy)
+    }
+
+    pub const ALL: [Register; 16] = [
+        Register::Rax, Register::Rbx, Register::Rcx, Register::Rdx,
+        Register::Rsi, Register::Rdi, Register::Rbp, Register::Rsp,
+        Register::R8, Register::R9, Register::R10, Register::R11,
+        Register::R12, Register::R13, Register::R14, Register::R15,
+    ];
+
+    fn slot(self, regs: &mut user_regs_struct) -> &mut u64 {
+        match self {
+            Register::Rax => &mut regs.rax,
+            Register::Rbx => &mut regs.rbx,
+            Register::Rcx => &mut regs.rcx,
+            Register::Rdx => &mut regs.rdx,
+            Register::Rsi => &mut regs.rsi,
+            Register::Rdi => &mut regs.rdi,
+            Register::Rbp => &mut regs.rbp,
+            Register::Rsp => &mut regs.rsp,
+            Register::R8 => &mut regs.r8,
+            Register::R9 => &mut regs.r9,
+            Register::R10 => &mut regs.r10,
+            Register::R11 => &mut regs.r11,
+            Register::R12 => &mut regs.r12,
+            Register::R13 => &mut regs.r13,
+            Register::R14 => &mut regs.r14,
+            Register::R15 => &mut regs.r15,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flag {
+    Carry,
+    Parity,
+    Zero,
+    Sign,
+    Overflow,
+}
+
+impl Flag {
+    pub const ALL: [Flag; 5] = [Flag::Carry, Flag::Parity, Flag::Zero, Flag::Sign, Flag::Overflow];
+
+    fn bit(self) -> u32 {
+        match self {
+            Flag::Carry => 0,
+            Flag::Parity => 2,
+            Flag::Zero => 6,
+            Flag::Sign => 7,
+            Flag::Overflow => 11,
+        }
+    }
+}
+
+/// Where a single bit flip is applied. Input offsets are relative to the
+/// comparator's `lhs` / `rhs` arguments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultSite {
+    Register { reg: Register, bit: u32 },
+    Flag(Flag),
+    Lhs { offset: usize, bit: u8 },
+    Rhs { offset: usize, bit: u8 },
+}
+
+impl fmt::Display for FaultSite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FaultSite::Register { reg, bit } => write!(f, "{:?}[{}]", reg, bit),
+            FaultSite::Flag(flag) => write!(f, "{:?}F", flag),
+            FaultSite::Lhs { offset, bit } => write!(f, "lhs[{}].{}", offset, bit),
+            FaultSite::Rhs { offset, bit } => write!(f, "rhs[{}].{}", offset, bit),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultOutcome {
+    /// The comparator returned the golden result.
+    Masked,
+    /// A different non-equal (or equal, for equal inputs) result came back.
+    Corrupted(i32),
+    /// Unequal inputs were reported as equal.
+    Bypassed,
+    /// The child died with the given signal.
+    Crashed(i32),
+    /// The call did not return within the step budget.
+    Hung,
+}
+
+impl FaultOutcome {
+    fn symbol(self) -> char {
+        match self {
+            FaultOutcome::Masked => '.',
+            FaultOutcome::Corrupted(_) => 'c',
+            FaultOutcome::Bypassed => 'B',
+            FaultOutcome::Crashed(_) => 'X',
+            FaultOutcome::Hung => 'H',
+        }
+    }
+}
+
+/// A golden run of `target` over a fixed input pair that single-fault
+/// experiments are measured against.
+pub struct FaultCampaign<'a> {
+    target: Comparator,
+    lhs: &'a [u8],
+    rhs: &'a [u8],
+    golden: i32,
+    steps: usize,
+}
+
+impl<'a> FaultCampaign<'a> {
+    pub fn new(target: Comparator, lhs: &'a [u8], rhs: &'a [u8]) -> io::Result<Self> {
+        let mut tracee = Tracee::spawn(target, lhs, rhs)?;
+        match tracee.finish(usize::MAX)? {
+            Some(StepEvent::Returned(golden)) => Ok(Self {
+                target,
+                lhs,
+                rhs,
+                golden,
+                steps: tracee.steps(),
+            }),
+            other => Err(io::Error::other(format!("golden run did not return: {:?}", other))),
+        }
+    }
+
+    /// Looks `symbol` up in the process with `resolve_symbol` and builds a
+    /// campaign for it. The symbol must have the `ct_memcmp` signature.
+    pub fn for_symbol(symbol: &str, lhs: &'a [u8], rhs: &'a [u8]) -> io::Result<Self> {
+        let target = resolve_symbol(symbol).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("symbol `{}` not found", symbol))
+        })?;
+        Self::new(target, lhs, rhs)
+    }
+
+    pub fn golden(&self) -> i32 {
+        self.golden
+    }
+
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+    /// Runs the target again, flips `site` after `step` instructions and
+    /// classifies the result against the golden run.
+    pub fn inject(&self, step: usize, site: FaultSite) -> io::Result<FaultOutcome> {
+        let mut tracee = Tracee::spawn(self.target, self.lhs, self.rhs)?;
+        if let Some(event) = tracee.finish(step)? {
+            // The call finished before the fault point; nothing was injected.
+            return Ok(self.classify(Some(event)));
+        }
+        tracee.inject(site)?;
+        let budget = self.steps.saturating_mul(4).max(MIN_STEP_BUDGET);
+        let event = tracee.finish(budget)?;
+        Ok(self.classify(event))
+    }
+
+    /// Sweeps every instruction step of the golden run against every site.
+    pub fn sweep(&self, sites: &[FaultSite]) -> io::Result<FaultMap> {
+        let mut outcomes = Vec::with_capacity(sites.len());
+        for &site in sites {
+            let row = (0..self.steps)
+                .map(|step| self.inject(step, site))
+                .collect::<io::Result<Vec<_>>>()?;
+            outcomes.push(row);
+        }
+        Ok(FaultMap {
+            golden: self.golden,
+            steps: self.steps,
+            sites: sites.to_vec(),
+            outcomes,
+        })
+    }
+
+    fn classify(&self, event: Option<StepEvent>) -> FaultOutcome {
+        match event {
+            Some(StepEvent::Returned(r)) if r == self.golden => FaultOutcome::Masked,
+            Some(StepEvent::Returned(0)) => FaultOutcome::Bypassed,
+            Some(StepEvent::Returned(r)) => FaultOutcome::Corrupted(r),
+            Some(StepEvent::Signaled(sig)) => FaultOutcome::Crashed(sig),
+            Some(StepEvent::Exited(_)) | Some(StepEvent::Stepped) => {
+                FaultOutcome::Crashed(0)
+            }
+            None => FaultOutcome::Hung,
+        }
+    }
+}
+
+/// Fault-sensitivity map: one row per site, one column per instruction step.
+pub struct FaultMap {
+    pub golden: i32,
+    pub steps: usize,
+    pub sites: Vec<FaultSite>,
+    pub outcomes: Vec<Vec<FaultOutcome>>,
+}
+
+impl FaultMap {
+    pub fn count(&self, pred: impl Fn(FaultOutcome) -> bool) -> usize {
+        self.outcomes.iter().flatten().filter(|&&o| pred(o)).count()
+    }
+
+    pub fn bypasses(&self) -> Vec<(FaultSite, usize)> {
+        let mut found = Vec::new();
+        for (site, row) in self.sites.iter().zip(&self.outcomes) {
+            for (step, outcome) in row.iter().enumerate() {
+                if *outcome == FaultOutcome::Bypassed {
+                    found.push((*site, step));
+                }
+            }
+        }
+        found
+    }
+}
+
+impl fmt::Display for FaultMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "golden = {}, {} steps", self.golden, self.steps)?;
+        for (site, row) in self.sites.iter().zip(&self.outcomes) {
+            let line: String = row.iter().map(|o| o.symbol()).collect();
+            writeln!(f, "{:>12} {}", site.to_string(), line)?;
+        }
+        Ok(())
+    }
+}
+
+/// Finds `symbol` in the executable's static symbol table, which also has
+/// functions missing from `.dynsym` such as `ct_memcmp`, and falls back to
+/// `dlsym` for shared objects.
+pub fn resolve_symbol(symbol: &str) -> Option<Comparator> {
+    let addr = match Symbolizer::new().resolve(symbol) {
+        Some(addr) => addr as *mut c_void,
+        None => {
+            let name = CString::new(symbol).ok()?;
+            unsafe { libc::dlsym(libc::RTLD_DEFAULT, name.as_ptr()) }
+        }
+    };
+    if addr.is_null() {
+        None
+    } else {
+        Some(unsafe { mem::transmute::<*mut c_void, Comparator>(addr) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_golden_run() {
+        let a = [1u8, 2, 3, 4];
+        let b = [1u8, 2, 3, 5];
+        let campaign = FaultCampaign::new(crate::ct_memcmp, &a, &b).unwrap();
+        assert_eq!(campaign.golden(), crate::ct_memcmp(a.as_ptr(), b.as_ptr(), 4));
+        assert!(campaign.steps() > 0);
+    }
+
+    #[test]
+    fn test_input_fault_bypasses_comparison() {
+        let a = [1u8, 2, 3, 4];
+        let b = [1u8, 2, 3, 5];
+        let campaign = FaultCampaign::new(crate::ct_memcmp, &a, &b).unwrap();
+        // Flipping bit 0 of rhs[3] before the first instruction makes the
+        // inputs equal.
+        let outcome = campaign.inject(0, FaultSite::Rhs { offset: 3, bit: 0 }).unwrap();
+        assert_eq!(outcome, FaultOutcome::Bypassed);
+        let outcome = campaign.inject(0, FaultSite::Lhs { offset: 0, bit: 7 }).unwrap();
+        assert_eq!(outcome, FaultOutcome::Corrupted(0x81));
+    }
+
+    #[test]
+    fn test_sweep_symbol() {
+        let a = [7u8; 8];
+        let b = [7u8; 8];
+        let campaign = FaultCampaign::for_symbol("memcmp", &a, &b).unwrap();
+        let map = campaign.sweep(&[FaultSite::Flag(Flag::Zero)]).unwrap();
+        assert_eq!(map.outcomes[0].len(), campaign.steps());
+        assert!(map.count(|o| o != FaultOutcome::Hung) > 0);
+    }
+
+    #[test]
+    fn test_for_unexported_symbol() {
+        let a = [1u8, 2, 3, 4];
+        let b = [1u8, 2, 3, 5];
+        let campaign = FaultCampaign::for_symbol("ct_memcmp", &a, &b).unwrap();
+        assert_eq!(campaign.golden(), crate::ct_memcmp(a.as_ptr(), b.as_ptr(), 4));
+        let outcome = campaign.inject(0, FaultSite::Rhs { offset: 3, bit: 0 }).unwrap();
+        assert_eq!(outcome, FaultOutcome::Bypassed);
+    }
+}
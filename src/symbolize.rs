@@ -0,0 +1,208 @@
+use std::ffi::CStr;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+
+const SHT_SYMTAB: u32 = 2;
+const STT_FUNC: u8 = 2;
+const PT_PHDR: u32 = 6;
+
+struct Symbol {
+    addr: u64,
+    size: u64,
+    name: String,
+}
+
+/// Maps code addresses in the current process to `symbol+offset`, using the
+/// static symbol table of `/proc/self/exe` and `dladdr` for shared objects.
+pub struct Symbolizer {
+    symbols: Vec<Symbol>,
+}
+
+impl Symbolizer {
+    pub fn new() -> Self {
+        let symbols = load_exe_symbols().unwrap_or_default();
+        Self { symbols }
+    }
+
+    pub fn lookup(&self, addr: u64) -> Option<(String, u64)> {
+        let idx = self.symbols.partition_point(|s| s.addr <= addr);
+        if idx > 0 {
+            let sym = &self.symbols[idx - 1];
+            if addr < sym.addr + sym.size.max(1) {
+                return Some((sym.name.clone(), addr - sym.addr));
+            }
+        }
+        lookup_dynamic(addr)
+    }
+
+    /// The address of the function named `name` (demangled, without its
+    /// hash) in the static symbol table, including symbols that are not
+    /// exported.
+    pub fn resolve(&self, name: &str) -> Option<u64> {
+        self.symbols.iter().find(|s| s.name == name).map(|s| s.addr)
+    }
+
+    pub fn describe(&self, addr: u64) -> String {
+        match self.lookup(addr) {
+            Some((name, 0)) => name,
+            Some((name, off)) => format!("{}+{:#x}", name, off),
+            None => format!("{:#x}", addr),
+        }
+    }
+}
+
+impl Default for Symbolizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn lookup_dynamic(addr: u64) -> Option<(String, u64)> {
+    let mut info: libc::Dl_info = unsafe { std::mem::zeroed() };
+    if unsafe { libc::dladdr(addr as *const libc::c_void, &mut info) } == 0 || info.dli_sname.is_null() {
+        return None;
+    }
+    let name = unsafe { CStr::from_ptr(info.dli_sname) }.to_string_lossy();
+    Some((demangle(&name), addr - info.dli_saddr as u64))
+}
+
+fn read_u16(buf: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes(buf[off..off + 2].try_into().unwrap())
+}
+
+fn read_u32(buf: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(buf[off..off + 4].try_into().unwrap())
+}
+
+fn read_u64(buf: &[u8], off: usize) -> u64 {
+    u64::from_le_bytes(buf[off..off + 8].try_into().unwrap())
+}
+
+fn read_at(file: &File, off: u64, len: usize) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    file.read_exact_at(&mut buf, off)?;
+    Ok(buf)
+}
+
+fn load_exe_symbols() -> io::Result<Vec<Symbol>> {
+    let file = File::open("/proc/self/exe")?;
+    let ehdr = read_at(&file, 0, 64)?;
+    if &ehdr[..4] != b"\x7fELF" || ehdr[4] != 2 {
+        return Err(io::Error::other("not an ELF64 image"));
+    }
+
+    let phoff = read_u64(&ehdr, 0x20);
+    let phentsize = read_u16(&ehdr, 0x36) as usize;
+    let phnum = read_u16(&ehdr, 0x38) as usize;
+    let phdrs = read_at(&file, phoff, phentsize * phnum)?;
+    let phdr_vaddr = (0..phnum)
+        .map(|i| &phdrs[i * phentsize..])
+        .find(|p| read_u32(p, 0) == PT_PHDR)
+        .map_or(phoff, |p| read_u64(p, 0x10));
+    let bias = unsafe { libc::getauxval(libc::AT_PHDR) }.wrapping_sub(phdr_vaddr);
+
+    let shoff = read_u64(&ehdr, 0x28);
+    let shentsize = read_u16(&ehdr, 0x3A) as usize;
+    let shnum = read_u16(&ehdr, 0x3C) as usize;
+    let shdrs = read_at(&file, shoff, shentsize * shnum)?;
+    let section = |i: usize| &shdrs[i * shentsize..(i + 1) * shentsize];
+
+    let mut symbols = Vec::new();
+    for i in 0..shnum {
+        let sh = section(i);
+        if read_u32(sh, 4) != SHT_SYMTAB {
+            continue;
+        }
+        let symtab = read_at(&file, read_u64(sh, 0x18), read_u64(sh, 0x20) as usize)?;
+        let strsh = section(read_u32(sh, 0x28) as usize);
+        let strtab = read_at(&file, read_u64(strsh, 0x18), read_u64(strsh, 0x20) as usize)?;
+
+        for sym in symtab.chunks_exact(24) {
+            if sym[4] & 0x0F != STT_FUNC || read_u64(sym, 8) == 0 {
+                continue;
+            }
+            let name_off = read_u32(sym, 0) as usize;
+            let end = strtab[name_off..].iter().position(|&b| b == 0).unwrap_or(0);
+            let name = String::from_utf8_lossy(&strtab[name_off..name_off + end]);
+            symbols.push(Symbol {
+                addr: read_u64(sym, 8).wrapping_add(bias),
+                size: read_u64(sym, 16),
+                name: demangle(&name),
+            });
+        }
+    }
+    symbols.sort_by_key(|s| s.addr);
+    Ok(symbols)
+}
+
+/// Demangles legacy (`_ZN...E`) Rust symbols and drops the trailing hash.
+/// Anything else is returned unchanged.
+pub fn demangle(name: &str) -> String {
+    let Some(mut rest) = name.strip_prefix("_ZN") else {
+        return name.to_string();
+    };
+    let mut parts = Vec::new();
+    while let Some(digits) = rest.find(|c: char| !c.is_ascii_digit()) {
+        if digits == 0 {
+            break;
+        }
+        let Ok(len) = rest[..digits].parse::<usize>() else {
+            break;
+        };
+        let Some(ident) = rest.get(digits..digits + len) else {
+            break;
+        };
+        parts.push(ident);
+        rest = &rest[digits + len..];
+    }
+    if rest != "E" || parts.is_empty() {
+        return name.to_string();
+    }
+    if let Some(last) = parts.last() {
+        if last.len() == 17 && last.starts_with('h') && last[1..].chars().all(|c| c.is_ascii_hexdigit()) {
+            parts.pop();
+        }
+    }
+    parts
+        .join("::")
+        .replace("$LT$", "<")
+        .replace("$GT$", ">")
+        .replace("$RF$", "&")
+        .replace("$BP$", "*")
+        .replace("$C$", ",")
+        .replace("$u20$", " ")
+        .replace("$u7b$", "{")
+        .replace("$u7d$", "}")
+        .replace("..", "::")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_demangle() {
+        assert_eq!(
+            demangle("_ZN4core3ptr13read_volatile17h0123456789abcdefE"),
+            "core::ptr::read_volatile"
+        );
+        assert_eq!(demangle("ct_memcmp"), "ct_memcmp");
+    }
+
+    #[test]
+    fn test_describe_exported_function() {
+        let sym = Symbolizer::new();
+        let addr = crate::ct_memcmp as *const () as u64;
+        assert_eq!(sym.describe(addr), "ct_memcmp");
+        assert_eq!(sym.describe(addr + 3), "ct_memcmp+0x3");
+    }
+
+    #[test]
+    fn test_resolve_round_trips() {
+        let sym = Symbolizer::new();
+        let addr = crate::ct_memcmp as *const () as u64;
+        assert_eq!(sym.resolve("ct_memcmp"), Some(addr));
+        assert_eq!(sym.resolve("no_such_symbol"), None);
+    }
+}
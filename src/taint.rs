@@ -0,0 +1,659 @@
+//! Taint-tracking emulator for the x86-64 subset produced by the JIT.
+//!
+//! Every register, memory byte and the flags carry shadow taint. Values
+//! derived from bytes marked secret stay tainted, and the emulator records a
+//! violation whenever a conditional branch reads tainted flags or a memory
+//! operand's address depends on tainted registers.
+
+use std::fmt;
+
+use crate::decode::{self, Instruction, OpcodeMap};
+
+pub const CODE_BASE: u64 = 0x1000_0000;
+pub const LHS_BASE: u64 = 0x2000_0000;
+pub const RHS_BASE: u64 = 0x3000_0000;
+const STACK_TOP: u64 = 0x7FFF_0000;
+const STACK_SIZE: usize = 4096;
+const RETURN_SENTINEL: u64 = 0xDEAD_BEEF_0000;
+const DEFAULT_STEP_LIMIT: usize = 1 << 24;
+
+const RAX: u8 = 0;
+const RDX: u8 = 2;
+const RSP: u8 = 4;
+const RSI: u8 = 6;
+const RDI: u8 = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    /// Conditional branch (or LOOP/JrCXZ) whose condition is secret.
+    TaintedBranch { rip: u64 },
+    /// Load or store whose address is derived from a secret.
+    TaintedAddress { rip: u64, addr: u64 },
+}
+
+/// Per-instruction leakage: Hamming weight of every value written and
+/// Hamming distance between old and new contents of each destination.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LeakageSample {
+    pub rip: u64,
+    pub hw: u32,
+    pub hd: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EmuError {
+    Unsupported { rip: u64, bytes: Vec<u8> },
+    Unmapped { rip: u64, addr: u64 },
+    StepLimit,
+}
+
+impl fmt::Display for EmuError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmuError::Unsupported { rip, bytes } => {
+                write!(f, "unsupported instruction at {:#x}: {:02x?}", rip, bytes)
+            }
+            EmuError::Unmapped { rip, addr } => {
+                write!(f, "access to unmapped {:#x} at {:#x}", addr, rip)
+            }
+            EmuError::StepLimit => write!(f, "step limit reached"),
+        }
+    }
+}
+
+impl std::error::Error for EmuError {}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Flags {
+    cf: bool,
+    pf: bool,
+    zf: bool,
+    sf: bool,
+    of: bool,
+}
+
+struct Region {
+    base: u64,
+    data: Vec<u8>,
+    taint: Vec<u8>,
+}
+
+#[derive(Clone, Copy)]
+enum Operand {
+    Reg(u8),
+    Mem(u64),
+}
+
+fn mask(size: u8) -> u64 {
+    match size {
+        1 => 0xFF,
+        2 => 0xFFFF,
+        4 => 0xFFFF_FFFF,
+        _ => u64::MAX,
+    }
+}
+
+/// Carries taint from the lowest tainted bit upwards, as an adder would.
+fn spread(taint: u64) -> u64 {
+    if taint == 0 {
+        0
+    } else {
+        u64::MAX << taint.trailing_zeros()
+    }
+}
+
+pub struct Emulator {
+    regs: [u64; 16],
+    taint: [u64; 16],
+    flags: Flags,
+    flags_taint: bool,
+    rip: u64,
+    regions: Vec<Region>,
+    violations: Vec<Violation>,
+    steps: usize,
+    step_limit: usize,
+    leakage: Option<Vec<LeakageSample>>,
+}
+
+impl Emulator {
+    /// Maps `code` at `CODE_BASE` with a small stack whose return address
+    /// ends emulation.
+    pub fn new(code: &[u8]) -> Self {
+        let mut emu = Self {
+            regs: [0; 16],
+            taint: [0; 16],
+            flags: Flags::default(),
+            flags_taint: false,
+            rip: CODE_BASE,
+            regions: Vec::new(),
+            violations: Vec::new(),
+            steps: 0,
+            step_limit: DEFAULT_STEP_LIMIT,
+            leakage: None,
+        };
+        emu.map(CODE_BASE, code);
+        emu.map(STACK_TOP - STACK_SIZE as u64, &[0; STACK_SIZE]);
+        emu.regs[RSP as usize] = STACK_TOP - 8;
+        emu.store(STACK_TOP - 8, 8, RETURN_SENTINEL, 0)
+            .expect("stack is mapped");
+        emu
+    }
+
+    pub fn with_step_limit(mut self, limit: usize) -> Self {
+        self.step_limit = limit;
+        self
+    }
+
+    pub fn map(&mut self, base: u64, data: &[u8]) {
+        self.regions.push(Region {
+            base,
+            data: data.to_vec(),
+            taint: vec![0; data.len()],
+        });
+    }
+
+    /// Marks `len` mapped bytes at `addr` as secret.
+    pub fn mark_secret(&mut self, addr: u64, len: usize) {
+        for i in 0..len as u64 {
+            if let Some((r, off)) = self.locate(addr + i) {
+                self.regions[r].taint[off] = 0xFF;
+            }
+        }
+    }
+
+    pub fn set_reg(&mut self, reg: u8, value: u64) {
+        self.regs[reg as usize] = value;
+        self.taint[reg as usize] = 0;
+    }
+
+    pub fn reg(&self, reg: u8) -> (u64, u64) {
+        (self.regs[reg as usize], self.taint[reg as usize])
+    }
+
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+    pub fn violations(&self) -> &[Violation] {
+        &self.violations
+    }
+
+    /// Starts recording one `LeakageSample` per executed instruction.
+    pub fn record_leakage(&mut self) {
+        self.leakage = Some(Vec::new());
+    }
+
+    pub fn leakage(&self) -> &[LeakageSample] {
+        self.leakage.as_deref().unwrap_or(&[])
+    }
+
+    fn leak(&mut self, old: u64, new: u64) {
+        if let Some(sample) = self.leakage.as_mut().and_then(|l| l.last_mut()) {
+            sample.hw += new.count_ones();
+            sample.hd += (old ^ new).count_ones();
+        }
+    }
+
+    /// Runs until the function returns to the sentinel and yields RAX.
+    pub fn run(&mut self) -> Result<u64, EmuError> {
+        while self.rip != RETURN_SENTINEL {
+            if self.steps >= self.step_limit {
+                return Err(EmuError::StepLimit);
+            }
+            self.step()?;
+        }
+        Ok(self.regs[RAX as usize])
+    }
+
+    fn locate(&self, addr: u64) -> Option<(usize, usize)> {
+        self.regions.iter().enumerate().find_map(|(i, r)| {
+            let off = addr.checked_sub(r.base)? as usize;
+            (off < r.data.len()).then_some((i, off))
+        })
+    }
+
+    fn load(&self, addr: u64, size: u8) -> Result<(u64, u64), EmuError> {
+        let mut value = 0u64;
+        let mut taint = 0u64;
+        for i in 0..size as u64 {
+            let (r, off) = self
+                .locate(addr + i)
+                .ok_or(EmuError::Unmapped { rip: self.rip, addr: addr + i })?;
+            value |= (self.regions[r].data[off] as u64) << (8 * i);
+            taint |= (self.regions[r].taint[off] as u64) << (8 * i);
+        }
+        Ok((value, taint))
+    }
+
+    fn store(&mut self, addr: u64, size: u8, value: u64, taint: u64) -> Result<(), EmuError> {
+        for i in 0..size as u64 {
+            let (r, off) = self
+                .locate(addr + i)
+                .ok_or(EmuError::Unmapped { rip: self.rip, addr: addr + i })?;
+            let old = self.regions[r].data[off];
+            self.regions[r].data[off] = (value >> (8 * i)) as u8;
+            self.regions[r].taint[off] = (taint >> (8 * i)) as u8;
+            self.leak(old as u64, (value >> (8 * i)) as u8 as u64);
+        }
+        Ok(())
+    }
+
+    fn fetch(&self) -> Result<Instruction, EmuError> {
+        let mut bytes = Vec::with_capacity(15);
+        for i in 0..15 {
+            match self.locate(self.rip + i) {
+                Some((r, off)) => bytes.push(self.regions[r].data[off]),
+                None => break,
+            }
+        }
+        decode::decode(&bytes).ok_or_else(|| self.unsupported(&bytes))
+    }
+
+    fn unsupported(&self, bytes: &[u8]) -> EmuError {
+        EmuError::Unsupported {
+            rip: self.rip,
+            bytes: bytes[..bytes.len().min(15)].to_vec(),
+        }
+    }
+
+    fn rm_operand(&mut self, insn: &Instruction) -> Operand {
+        let Some(mem) = insn.mem else {
+            return Operand::Reg(insn.modrm.map_or(0, |m| m.rm));
+        };
+        let next = self.rip + insn.len as u64;
+        let regs = self.regs;
+        let addr = mem.effective_address(|r| regs[r as usize], next, |_| 0);
+        let addr_taint = mem.base.map_or(0, |r| self.taint[r as usize])
+            | mem.index.map_or(0, |r| self.taint[r as usize]);
+        if addr_taint != 0 && insn.accesses_memory() {
+            self.violations.push(Violation::TaintedAddress { rip: self.rip, addr });
+        }
+        Operand::Mem(addr)
+    }
+
+    fn read(&self, op: Operand, size: u8) -> Result<(u64, u64), EmuError> {
+        match op {
+            Operand::Reg(r) => Ok((self.regs[r as usize] & mask(size), self.taint[r as usize] & mask(size))),
+            Operand::Mem(addr) => self.load(addr, size),
+        }
+    }
+
+    fn write(&mut self, op: Operand, size: u8, value: u64, taint: u64) -> Result<(), EmuError> {
+        match op {
+            Operand::Reg(r) => {
+                let r = r as usize;
+                let old = self.regs[r];
+                match size {
+                    // 32-bit writes zero the upper half.
+                    4 | 8 => {
+                        self.regs[r] = value & mask(size);
+                        self.taint[r] = taint & mask(size);
+                    }
+                    _ => {
+                        let m = mask(size);
+                        self.regs[r] = (self.regs[r] & !m) | (value & m);
+                        self.taint[r] = (self.taint[r] & !m) | (taint & m);
+                    }
+                }
+                self.leak(old, self.regs[r]);
+                Ok(())
+            }
+            Operand::Mem(addr) => self.store(addr, size, value & mask(size), taint & mask(size)),
+        }
+    }
+
+    fn push(&mut self, value: u64, taint: u64) -> Result<(), EmuError> {
+        let sp = self.regs[RSP as usize] - 8;
+        self.regs[RSP as usize] = sp;
+        self.store(sp, 8, value, taint)
+    }
+
+    fn pop(&mut self) -> Result<(u64, u64), EmuError> {
+        let sp = self.regs[RSP as usize];
+        let v = self.load(sp, 8)?;
+        self.regs[RSP as usize] = sp + 8;
+        Ok(v)
+    }
+
+    fn set_logic_flags(&mut self, res: u64, size: u8, taint: u64) {
+        let msb = 1u64 << (size as u32 * 8 - 1);
+        self.flags = Flags {
+            cf: false,
+            of: false,
+            zf: res & mask(size) == 0,
+            sf: res & msb != 0,
+            pf: (res as u8).count_ones().is_multiple_of(2),
+        };
+        self.flags_taint = taint != 0;
+    }
+
+    fn set_arith_flags(&mut self, a: u64, b: u64, res: u64, size: u8, sub: bool, taint: u64) {
+        let m = mask(size);
+        let msb = 1u64 << (size as u32 * 8 - 1);
+        let (a, b, res) = (a & m, b & m, res & m);
+        let (cf, of) = if sub {
+            (a < b, (a ^ b) & (a ^ res) & msb != 0)
+        } else {
+            (res < a, (a ^ res) & (b ^ res) & msb != 0)
+        };
+        self.flags = Flags {
+            cf,
+            of,
+            zf: res == 0,
+            sf: res & msb != 0,
+            pf: (res as u8).count_ones().is_multiple_of(2),
+        };
+        self.flags_taint = taint != 0;
+    }
+
+    fn condition(&self, cc: u8) -> bool {
+        let f = self.flags;
+        let base = match cc >> 1 {
+            0 => f.of,
+            1 => f.cf,
+            2 => f.zf,
+            3 => f.cf || f.zf,
+            4 => f.sf,
+            5 => f.pf,
+            6 => f.sf != f.of,
+            _ => f.zf || f.sf != f.of,
+        };
+        base != (cc & 1 != 0)
+    }
+
+    /// Applies a two-operand ALU op (`/digit` numbering of the 0x80 group).
+    fn alu(&mut self, op: u8, dst: Operand, src: (u64, u64), size: u8, same_reg: bool) -> Result<(), EmuError> {
+        let (a, ta) = self.read(dst, size)?;
+        let (b, tb) = src;
+        let m = mask(size);
+        match op {
+            // add, sub, cmp
+            0 | 5 | 7 => {
+                let sub = op != 0;
+                let res = if sub { a.wrapping_sub(b) } else { a.wrapping_add(b) } & m;
+                // sub r, r is a zeroing idiom.
+                let taint = if sub && same_reg { 0 } else { spread(ta | tb) & m };
+                self.set_arith_flags(a, b, res, size, sub, taint);
+                if op != 7 {
+                    self.write(dst, size, res, taint)?;
+                }
+            }
+            // or, and, xor
+            1 | 4 | 6 => {
+                let res = match op {
+                    1 => a | b,
+                    4 => a & b,
+                    _ => a ^ b,
+                } & m;
+                let taint = if op == 6 && same_reg { 0 } else { (ta | tb) & m };
+                self.set_logic_flags(res, size, taint);
+                self.write(dst, size, res, taint)?;
+            }
+            _ => return Err(self.unsupported(&[])),
+        }
+        Ok(())
+    }
+
+    pub fn step(&mut self) -> Result<(), EmuError> {
+        let insn = self.fetch()?;
+        let next = self.rip + insn.len as u64;
+        let size = insn.operand_size();
+        let reg = insn.modrm.map_or(0, |m| m.reg);
+        let mut target = next;
+        self.steps += 1;
+        if let Some(leakage) = self.leakage.as_mut() {
+            leakage.push(LeakageSample { rip: self.rip, ..Default::default() });
+        }
+
+        if insn.is_conditional_branch() && self.flags_taint {
+            self.violations.push(Violation::TaintedBranch { rip: self.rip });
+        }
+
+        match (insn.map, insn.opcode) {
+            // ALU r/m, r and r, r/m forms: add, or, and, sub, xor, cmp.
+            (OpcodeMap::Primary, op @ (0x01 | 0x03 | 0x09 | 0x0B | 0x21 | 0x23 | 0x29 | 0x2B | 0x31 | 0x33 | 0x39 | 0x3B)) => {
+                let alu_op = op >> 3;
+                let rm = self.rm_operand(&insn);
+                let same = matches!(rm, Operand::Reg(r) if r == reg);
+                if op & 2 == 0 {
+                    let src = self.read(Operand::Reg(reg), size)?;
+                    self.alu(alu_op, rm, src, size, same)?;
+                } else {
+                    let src = self.read(rm, size)?;
+                    self.alu(alu_op, Operand::Reg(reg), src, size, same)?;
+                }
+            }
+            (OpcodeMap::Primary, 0x81 | 0x83) => {
+                let rm = self.rm_operand(&insn);
+                let imm = insn.imm.unwrap_or(0) as u64 & mask(size);
+                self.alu(reg & 7, rm, (imm, 0), size, false)?;
+            }
+            (OpcodeMap::Primary, 0x85) => {
+                let rm = self.rm_operand(&insn);
+                let (a, ta) = self.read(rm, size)?;
+                let (b, tb) = self.read(Operand::Reg(reg), size)?;
+                self.set_logic_flags(a & b, size, ta | tb);
+            }
+            (OpcodeMap::Primary, 0x89) => {
+                let rm = self.rm_operand(&insn);
+                let (v, t) = self.read(Operand::Reg(reg), size)?;
+                self.write(rm, size, v, t)?;
+            }
+            (OpcodeMap::Primary, 0x8B) => {
+                let rm = self.rm_operand(&insn);
+                let (v, t) = self.read(rm, size)?;
+                self.write(Operand::Reg(reg), size, v, t)?;
+            }
+            (OpcodeMap::Primary, 0x8D) => {
+                let mem = insn.mem.ok_or_else(|| self.unsupported(&[]))?;
+                let regs = self.regs;
+                let addr = mem.effective_address(|r| regs[r as usize], next, |_| 0);
+                let taint = mem.base.map_or(0, |r| self.taint[r as usize])
+                    | mem.index.map_or(0, |r| self.taint[r as usize]);
+                self.write(Operand::Reg(reg), size, addr, spread(taint))?;
+            }
+            (OpcodeMap::Primary, 0x90) => {}
+            (OpcodeMap::Primary, op @ 0x50..=0x57) => {
+                let r = (op & 7) | (insn.rex & 1) << 3;
+                let (v, t) = (self.regs[r as usize], self.taint[r as usize]);
+                self.push(v, t)?;
+            }
+            (OpcodeMap::Primary, op @ 0x58..=0x5F) => {
+                let r = (op & 7) | (insn.rex & 1) << 3;
+                let (v, t) = self.pop()?;
+                self.write(Operand::Reg(r), 8, v, t)?;
+            }
+            (OpcodeMap::Primary, 0x70..=0x7F) | (OpcodeMap::Secondary, 0x80..=0x8F) => {
+                if self.condition(insn.opcode & 0x0F) {
+                    target = next.wrapping_add(insn.imm.unwrap_or(0) as u64);
+                }
+            }
+            (OpcodeMap::Primary, op @ 0xB8..=0xBF) => {
+                let r = (op & 7) | (insn.rex & 1) << 3;
+                self.write(Operand::Reg(r), size, insn.imm.unwrap_or(0) as u64, 0)?;
+            }
+            (OpcodeMap::Primary, 0xC1 | 0xD1) => {
+                let rm = self.rm_operand(&insn);
+                let count = if insn.opcode == 0xD1 { 1 } else { insn.imm.unwrap_or(0) as u32 };
+                let count = count & if size == 8 { 63 } else { 31 };
+                let (v, t) = self.read(rm, size)?;
+                let bits = size as u32 * 8;
+                let (res, taint) = match reg & 7 {
+                    4 => (v << count, t << count),
+                    5 => (v >> count, t >> count),
+                    7 => {
+                        let shift = 64 - bits;
+                        let sv = (((v << shift) as i64) >> (shift + count)) as u64;
+                        let st = (((t << shift) as i64) >> (shift + count)) as u64;
+                        (sv, st)
+                    }
+                    _ => return Err(self.unsupported(&[insn.opcode])),
+                };
+                if count != 0 {
+                    self.set_logic_flags(res, size, taint);
+                }
+                self.write(rm, size, res, taint)?;
+            }
+            (OpcodeMap::Primary, 0xC3) => {
+                let (v, _) = self.pop()?;
+                target = v;
+            }
+            (OpcodeMap::Primary, 0xC7) => {
+                let rm = self.rm_operand(&insn);
+                self.write(rm, size, insn.imm.unwrap_or(0) as u64, 0)?;
+            }
+            (OpcodeMap::Primary, 0xE8) => {
+                self.push(next, 0)?;
+                target = next.wrapping_add(insn.imm.unwrap_or(0) as u64);
+            }
+            (OpcodeMap::Primary, 0xE9 | 0xEB) => {
+                target = next.wrapping_add(insn.imm.unwrap_or(0) as u64);
+            }
+            (OpcodeMap::Primary, 0xF7) if matches!(reg & 7, 2 | 3) => {
+                let rm = self.rm_operand(&insn);
+                let (v, t) = self.read(rm, size)?;
+                if reg & 7 == 2 {
+                    self.write(rm, size, !v, t)?;
+                } else {
+                    let res = 0u64.wrapping_sub(v);
+                    self.set_arith_flags(0, v, res, size, true, spread(t));
+                    self.write(rm, size, res, spread(t))?;
+                }
+            }
+            (OpcodeMap::Primary, 0xFF) if reg & 7 < 2 => {
+                let rm = self.rm_operand(&insn);
+                let (v, t) = self.read(rm, size)?;
+                let cf = self.flags.cf;
+                let (res, sub) = if reg & 7 == 0 { (v.wrapping_add(1), false) } else { (v.wrapping_sub(1), true) };
+                self.set_arith_flags(v, 1, res, size, sub, spread(t));
+                self.flags.cf = cf;
+                self.write(rm, size, res, spread(t))?;
+            }
+            (OpcodeMap::Secondary, 0x1F) => {}
+            (OpcodeMap::Secondary, cc @ 0x40..=0x4F) => {
+                let rm = self.rm_operand(&insn);
+                let (src, ts) = self.read(rm, size)?;
+                let (dst, td) = self.read(Operand::Reg(reg), size)?;
+                // The select itself is data-oblivious, but its result
+                // depends on the condition.
+                let ft = if self.flags_taint { mask(size) } else { 0 };
+                let v = if self.condition(cc & 0x0F) { src } else { dst };
+                self.write(Operand::Reg(reg), size, v, ts | td | ft)?;
+            }
+            (OpcodeMap::Secondary, 0xAF) => {
+                let rm = self.rm_operand(&insn);
+                let (a, ta) = self.read(Operand::Reg(reg), size)?;
+                let (b, tb) = self.read(rm, size)?;
+                self.write(Operand::Reg(reg), size, a.wrapping_mul(b), spread(ta | tb))?;
+            }
+            (OpcodeMap::Secondary, op @ (0xB6 | 0xB7)) => {
+                let rm = self.rm_operand(&insn);
+                let src_size = if op == 0xB6 { 1 } else { 2 };
+                if let Operand::Reg(r) = rm {
+                    if src_size == 1 && insn.rex == 0 && r >= 4 {
+                        // AH..BH are not modelled.
+                        return Err(self.unsupported(&[0x0F, op]));
+                    }
+                }
+                let (v, t) = self.read(rm, src_size)?;
+                self.write(Operand::Reg(reg), size, v, t)?;
+            }
+            _ => {
+                let mut bytes = Vec::new();
+                for i in 0..insn.len as u64 {
+                    bytes.push(self.load(self.rip + i, 1)?.0 as u8);
+                }
+                return Err(self.unsupported(&bytes));
+            }
+        }
+
+        self.rip = target;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TaintReport {
+    pub result: u64,
+    /// Taint of RAX on return: nonzero means the result depends on the secret,
+    /// which is expected for a comparator.
+    pub result_taint: u64,
+    pub violations: Vec<Violation>,
+    pub steps: usize,
+}
+
+impl Emulator {
+    /// Sets up a call with the `ct_memcmp` ABI over `lhs` (secret) and `rhs`
+    /// (public).
+    pub fn for_comparator(code: &[u8], lhs: &[u8], rhs: &[u8]) -> Self {
+        let mut emu = Emulator::new(code);
+        emu.map(LHS_BASE, lhs);
+        emu.map(RHS_BASE, rhs);
+        emu.mark_secret(LHS_BASE, lhs.len());
+        emu.set_reg(RDI, LHS_BASE);
+        emu.set_reg(RSI, RHS_BASE);
+        emu.set_reg(RDX, lhs.len().min(rhs.len()) as u64);
+        emu
+    }
+}
+
+pub fn analyze_comparator(code: &[u8], lhs: &[u8], rhs: &[u8]) -> Result<TaintReport, EmuError> {
+    let mut emu = Emulator::for_comparator(code, lhs, rhs);
+    let result = emu.run()?;
+    Ok(TaintReport {
+        result,
+        result_taint: emu.reg(RAX).1,
+        violations: emu.violations.clone(),
+        steps: emu.steps,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Early-exit compare of 4 bytes:
+    //   xor ecx, ecx
+    //   movzx eax, byte [rdi + rcx]
+    //   movzx r8d, byte [rsi + rcx]
+    //   cmp eax, r8d
+    //   jne done
+    //   add rcx, 1
+    //   cmp rcx, 4
+    //   jb loop
+    // done:
+    //   ret
+    const EARLY_EXIT: &[u8] = &[
+        0x31, 0xC9,
+        0x0F, 0xB6, 0x04, 0x0F,
+        0x44, 0x0F, 0xB6, 0x04, 0x0E,
+        0x44, 0x39, 0xC0,
+        0x75, 0x0D,
+        0x48, 0x83, 0xC1, 0x01,
+        0x48, 0x81, 0xF9, 0x04, 0x00, 0x00, 0x00,
+        0x72, 0xE5,
+        0xC3,
+    ];
+
+    // movzx eax, byte [rdi]; movzx eax, byte [rsi + rax]; ret
+    const TABLE_LOOKUP: &[u8] = &[0x0F, 0xB6, 0x07, 0x0F, 0xB6, 0x04, 0x06, 0xC3];
+
+    #[test]
+    fn test_detects_secret_branch() {
+        let report = analyze_comparator(EARLY_EXIT, &[1, 2, 3, 4], &[1, 2, 3, 4]).unwrap();
+        assert_eq!(report.violations.len(), 4);
+        assert!(matches!(report.violations[0], Violation::TaintedBranch { rip } if rip == CODE_BASE + 14));
+    }
+
+    #[test]
+    fn test_detects_secret_address() {
+        let mut table = [0u8; 256];
+        table[9] = 42;
+        let report = analyze_comparator(TABLE_LOOKUP, &[9], &table).unwrap();
+        assert_eq!(report.result, 42);
+        assert_eq!(
+            report.violations,
+            [Violation::TaintedAddress { rip: CODE_BASE + 3, addr: RHS_BASE + 9 }]
+        );
+    }
+}
